@@ -1,6 +1,56 @@
 use bytes::BytesMut;
 use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
-use t3::encode::encode_file;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use t3::encode::{StripeEncoder, encode_file};
+use t3::profile::ErasureProfile;
+
+// ピークメモリ使用量を測るためのアロケータ
+struct PeakAlloc;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for PeakAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            let current = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(current, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOC: PeakAlloc = PeakAlloc;
+
+// 入力データを除いて、`f` の実行中に増えたメモリのピークを返す
+fn peak_memory(f: impl FnOnce()) -> usize {
+    let base = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(base, Ordering::Relaxed);
+    f();
+    PEAK.load(Ordering::Relaxed) - base
+}
+
+// multipartのチャンクを模して、64KiBずつエンコーダに渡す
+fn encode_stream(data: &[u8]) {
+    let mut encoder = StripeEncoder::new(ErasureProfile::default()).unwrap();
+    for chunk in data.chunks(64 * 1024) {
+        encoder.push(chunk);
+        while let Some(stripe) = encoder.next_stripe().unwrap() {
+            std::hint::black_box(stripe);
+        }
+    }
+    while let Some(stripe) = encoder.finish().unwrap() {
+        std::hint::black_box(stripe);
+    }
+}
 
 fn bench_encode_data(c: &mut Criterion) {
     let mut group = c.benchmark_group("encode");
@@ -10,7 +60,35 @@ fn bench_encode_data(c: &mut Criterion) {
             BenchmarkId::new("bench_encode_data", n),
             &data,
             |b, data| {
-                b.iter(|| encode_file(BytesMut::from(&data[..])));
+                b.iter(|| encode_file(BytesMut::from(&data[..]), ErasureProfile::default()));
+            },
+        );
+    }
+
+    group.finish();
+}
+
+fn bench_encode_stream(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode_stream");
+    for n in [1, 10, 50, 100, 300, 512, 1024] {
+        let data: Vec<u8> = vec![1u8; n * 1024 * 1024];
+        let whole = peak_memory(|| {
+            std::hint::black_box(
+                encode_file(BytesMut::from(&data[..]), ErasureProfile::default()).unwrap(),
+            );
+        });
+        let stream = peak_memory(|| encode_stream(&data));
+        println!(
+            "{} MiB: peak memory encode_file = {} KiB, streaming = {} KiB",
+            n,
+            whole / 1024,
+            stream / 1024
+        );
+        group.bench_with_input(
+            BenchmarkId::new("bench_encode_stream", n),
+            &data,
+            |b, data| {
+                b.iter(|| encode_stream(data));
             },
         );
     }
@@ -18,5 +96,5 @@ fn bench_encode_data(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, bench_encode_data);
+criterion_group!(benches, bench_encode_data, bench_encode_stream);
 criterion_main!(benches);
@@ -0,0 +1,27 @@
+-- 初期のマイグレーションはすべて CREATE TABLE IF NOT EXISTS なので、空のデータベースでは最初の古い形のテーブルが残る
+-- 以降のマイグレーションが前提にしている最新の形(object_id 列、bucket_name の UNIQUE)に作り直す
+-- object_key 列の object_metadata は MetadataStore::new がマイグレーションの前に置き換えている
+CREATE TABLE bucket_metadata_new (
+    id TEXT PRIMARY KEY NOT NULL,
+    bucket_name TEXT NOT NULL UNIQUE,
+    created_at TEXT NOT NULL
+);
+-- 同じ名前のバケットが複数ある場合は最初に作られたものを残す
+INSERT OR IGNORE INTO bucket_metadata_new (id, bucket_name, created_at)
+SELECT id, bucket_name, created_at FROM bucket_metadata ORDER BY created_at;
+DROP TABLE bucket_metadata;
+ALTER TABLE bucket_metadata_new RENAME TO bucket_metadata;
+
+CREATE TABLE object_metadata_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    bucket_name TEXT NOT NULL,
+    object_id TEXT NOT NULL,
+    file_name TEXT,
+    content_type TEXT,
+    content_length INTEGER,
+    created_at TEXT NOT NULL
+);
+INSERT INTO object_metadata_new (id, bucket_name, object_id, file_name, content_type, content_length, created_at)
+SELECT id, bucket_name, object_id, file_name, content_type, content_length, created_at FROM object_metadata;
+DROP TABLE object_metadata;
+ALTER TABLE object_metadata_new RENAME TO object_metadata;
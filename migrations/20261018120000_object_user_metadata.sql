@@ -0,0 +1 @@
+ALTER TABLE object_metadata ADD COLUMN user_metadata TEXT;
@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS idx_object_metadata_bucket_object ON object_metadata (bucket_name, object_id);
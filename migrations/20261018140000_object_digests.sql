@@ -0,0 +1,2 @@
+ALTER TABLE object_metadata ADD COLUMN md5 TEXT;
+ALTER TABLE object_metadata ADD COLUMN sha256 TEXT;
@@ -0,0 +1,7 @@
+-- 同じキーの行が複数ある場合は最後に登録されたものだけを残す
+DELETE FROM object_metadata
+WHERE id NOT IN (SELECT MAX(id) FROM object_metadata GROUP BY bucket_name, object_id);
+DROP INDEX IF EXISTS idx_object_metadata_bucket_object;
+CREATE UNIQUE INDEX idx_object_metadata_bucket_object ON object_metadata (bucket_name, object_id);
+-- 上書きのたびに変わるシャードの世代。NULLの行は世代導入前のもので、キーをそのままシャード名に使う
+ALTER TABLE object_metadata ADD COLUMN generation TEXT;
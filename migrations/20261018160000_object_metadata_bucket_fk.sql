@@ -0,0 +1,27 @@
+-- バケットが消えてしまったオブジェクトが残っている場合は、削除できるようにバケットを作り直す
+INSERT OR IGNORE INTO bucket_metadata (id, bucket_name, created_at)
+SELECT lower(hex(randomblob(16))), bucket_name, strftime('%Y-%m-%dT%H:%M:%S+00:00', 'now')
+FROM object_metadata
+WHERE bucket_name NOT IN (SELECT bucket_name FROM bucket_metadata)
+GROUP BY bucket_name;
+
+-- SQLiteでは既存のテーブルに外部キーを追加できないので作り直す
+CREATE TABLE object_metadata_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    bucket_name TEXT NOT NULL REFERENCES bucket_metadata (bucket_name),
+    object_id TEXT NOT NULL,
+    file_name TEXT,
+    content_type TEXT,
+    content_length INTEGER,
+    created_at TEXT NOT NULL,
+    user_metadata TEXT,
+    md5 TEXT,
+    sha256 TEXT,
+    generation TEXT
+);
+INSERT INTO object_metadata_new (id, bucket_name, object_id, file_name, content_type, content_length, created_at, user_metadata, md5, sha256, generation)
+SELECT id, bucket_name, object_id, file_name, content_type, content_length, created_at, user_metadata, md5, sha256, generation
+FROM object_metadata;
+DROP TABLE object_metadata;
+ALTER TABLE object_metadata_new RENAME TO object_metadata;
+CREATE UNIQUE INDEX idx_object_metadata_bucket_object ON object_metadata (bucket_name, object_id);
@@ -0,0 +1,3 @@
+-- スクラブで確認したシャードの状態と、最後にスクラブした時刻
+ALTER TABLE object_metadata ADD COLUMN health TEXT;
+ALTER TABLE object_metadata ADD COLUMN last_scrubbed_at TEXT;
@@ -0,0 +1,8 @@
+-- バケットごとのイレイジャーコーディングのパラメータと、オブジェクトを書き込んだときのパラメータ
+-- NULLはデフォルト(6+3, 1MiB)を表す
+ALTER TABLE bucket_metadata ADD COLUMN data_shards INTEGER;
+ALTER TABLE bucket_metadata ADD COLUMN parity_shards INTEGER;
+ALTER TABLE bucket_metadata ADD COLUMN stripe_unit INTEGER;
+ALTER TABLE object_metadata ADD COLUMN data_shards INTEGER;
+ALTER TABLE object_metadata ADD COLUMN parity_shards INTEGER;
+ALTER TABLE object_metadata ADD COLUMN stripe_unit INTEGER;
@@ -0,0 +1,15 @@
+-- パックファイル(セグメント)に追記した小さなオブジェクトのシャードの位置
+-- オブジェクトが削除・上書きされても行は残り、コンパクションで取り除く
+CREATE TABLE IF NOT EXISTS pack_entries (
+    bucket_name TEXT NOT NULL,
+    object_id TEXT NOT NULL,
+    generation TEXT NOT NULL,
+    shard_index INTEGER NOT NULL,
+    segment TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    length INTEGER NOT NULL,
+    PRIMARY KEY (bucket_name, object_id, generation, shard_index)
+);
+CREATE INDEX IF NOT EXISTS idx_pack_entries_segment ON pack_entries (segment);
+
+ALTER TABLE object_metadata ADD COLUMN packed INTEGER NOT NULL DEFAULT 0;
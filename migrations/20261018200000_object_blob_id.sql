@@ -0,0 +1,3 @@
+-- シャードファイルの名前に使う内部ID(UUID)。キーやバケットに依存しないので、どんなキーでも安全に置ける
+-- これより前に書かれたオブジェクトはNULLで、キーと世代から作った名前のまま読む
+ALTER TABLE object_metadata ADD COLUMN blob_id TEXT;
@@ -0,0 +1,41 @@
+-- 進行中のマルチパートアップロード。パートを受け取るたびにmultipart_partsに記録し、完了したらオブジェクトに切り替える
+CREATE TABLE IF NOT EXISTS multipart_uploads (
+    upload_id TEXT PRIMARY KEY,
+    bucket_name TEXT NOT NULL REFERENCES bucket_metadata (bucket_name),
+    object_id TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    file_name TEXT,
+    content_type TEXT,
+    user_metadata TEXT,
+    data_shards INTEGER NOT NULL,
+    parity_shards INTEGER NOT NULL,
+    stripe_unit INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_multipart_uploads_created_at ON multipart_uploads (created_at);
+CREATE INDEX IF NOT EXISTS idx_multipart_uploads_bucket ON multipart_uploads (bucket_name);
+
+-- アップロード済みのパート。パートごとに別の内部IDでイレイジャーコーディングして置く
+CREATE TABLE IF NOT EXISTS multipart_parts (
+    upload_id TEXT NOT NULL REFERENCES multipart_uploads (upload_id) ON DELETE CASCADE,
+    part_number INTEGER NOT NULL,
+    blob_id TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    md5 TEXT NOT NULL,
+    sha256 TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (upload_id, part_number)
+);
+
+-- マルチパートアップロードで作ったオブジェクトを構成するパート。blob_idはオブジェクトの内部ID
+CREATE TABLE IF NOT EXISTS object_parts (
+    blob_id TEXT NOT NULL,
+    part_number INTEGER NOT NULL,
+    part_blob_id TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    sha256 TEXT NOT NULL,
+    PRIMARY KEY (blob_id, part_number)
+);
+
+-- マルチパートアップロードで作ったオブジェクトのパート数。NULLなら1つのシャードの組に置いている
+ALTER TABLE object_metadata ADD COLUMN part_count INTEGER;
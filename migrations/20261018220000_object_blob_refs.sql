@@ -0,0 +1,3 @@
+-- コピー・リネームしたオブジェクトは元のオブジェクトと同じ blob_id のシャードを共有する
+-- 同じ blob_id を持つ行の数がシャードの参照数で、0になったときにシャードを削除する
+CREATE INDEX IF NOT EXISTS idx_object_metadata_blob_id ON object_metadata (blob_id);
@@ -1,7 +1,9 @@
-use anyhow::Result;
+use crate::profile::ErasureProfile;
+use anyhow::{Result, bail};
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
 use sqlx::Error;
+use sqlx::SqliteConnection;
 use sqlx::sqlite::SqliteQueryResult;
 
 #[derive(Deserialize, Serialize, Debug, sqlx::FromRow)]
@@ -13,6 +15,143 @@ pub struct ObjectMetadata {
     pub content_type: Option<String>,
     pub content_length: Option<i64>,
     pub created_at: String,
+    // x-amz-meta-* で渡されたユーザー定義メタデータ(JSONオブジェクト)
+    pub user_metadata: Option<String>,
+    // 元データのダイジェスト(16進数)
+    pub md5: Option<String>,
+    pub sha256: Option<String>,
+    pub generation: Option<String>,
+    // シャードファイルの名前に使う内部ID。キーやバケットに依存しない
+    pub blob_id: Option<String>,
+    // スクラブで確認したシャードの状態(healthy, repaired, degraded, lost)
+    pub health: Option<String>,
+    pub last_scrubbed_at: Option<String>,
+    // 書き込んだときのイレイジャーコーディングのパラメータ
+    pub data_shards: Option<i64>,
+    pub parity_shards: Option<i64>,
+    pub stripe_unit: Option<i64>,
+    // シャードをパックファイルに置いたかどうか
+    pub packed: bool,
+    // マルチパートアップロードで作ったオブジェクトのパート数。パートは `object_parts` に記録する
+    pub part_count: Option<i64>,
+}
+
+impl ObjectMetadata {
+    /// このオブジェクトのシャードファイルの名前に使うID。
+    /// 内部IDを持たない古いオブジェクトは、キーと世代から作った名前を使う。
+    pub fn shard_id(&self) -> String {
+        match &self.blob_id {
+            Some(blob_id) => blob_id.clone(),
+            None => crate::shard_id(&self.object_id, self.generation.as_deref()),
+        }
+    }
+
+    /// このオブジェクトを書き込んだときのイレイジャーコーディングのパラメータ
+    pub fn profile(&self) -> ErasureProfile {
+        ErasureProfile::from_columns(self.data_shards, self.parity_shards, self.stripe_unit)
+    }
+}
+
+/// 新しく保存するオブジェクトのメタデータ
+#[derive(Debug, Default)]
+pub struct NewObjectMetadata<'a> {
+    pub file_name: Option<&'a str>,
+    pub content_type: Option<&'a str>,
+    pub content_length: i64,
+    pub user_metadata: Option<&'a str>,
+    pub md5: Option<&'a str>,
+    pub sha256: Option<&'a str>,
+    pub generation: Option<&'a str>,
+    pub blob_id: Option<&'a str>,
+    pub profile: ErasureProfile,
+    pub packed: bool,
+    pub part_count: Option<i64>,
+}
+
+/// パックファイルに追記したシャードの位置
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct PackEntry {
+    pub bucket_name: String,
+    pub object_id: String,
+    pub generation: String,
+    pub shard_index: i64,
+    pub segment: String,
+    pub position: i64,
+    pub length: i64,
+}
+
+/// `MetadataStore::link_object` の結果
+#[derive(Debug)]
+pub enum LinkObject {
+    /// シャードを共有するオブジェクトを作った。同じキーのオブジェクトがあれば置き換える前の行を返す
+    Linked(Option<Box<ObjectMetadata>>),
+    /// コピー元が読んだ後に上書き・削除された
+    SourceChanged,
+    /// `If-None-Match: *` が指定されていて、コピー先が既に存在する
+    AlreadyExists,
+}
+
+/// 進行中のマルチパートアップロード。オブジェクトの属性はアップロードを始めたときに決める。
+#[derive(Deserialize, Serialize, Debug, Clone, sqlx::FromRow)]
+pub struct MultipartUpload {
+    pub upload_id: String,
+    pub bucket_name: String,
+    pub object_id: String,
+    pub created_at: String,
+    pub file_name: Option<String>,
+    pub content_type: Option<String>,
+    pub user_metadata: Option<String>,
+    pub data_shards: i64,
+    pub parity_shards: i64,
+    pub stripe_unit: i64,
+}
+
+impl MultipartUpload {
+    /// パートをエンコードするイレイジャーコーディングのパラメータ
+    pub fn profile(&self) -> ErasureProfile {
+        ErasureProfile::from_columns(
+            Some(self.data_shards),
+            Some(self.parity_shards),
+            Some(self.stripe_unit),
+        )
+    }
+}
+
+/// マルチパートアップロードで受け取ったパート
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct UploadedPart {
+    pub upload_id: String,
+    pub part_number: i64,
+    // パートのシャードの名前に使う内部ID
+    pub blob_id: String,
+    pub size: i64,
+    pub md5: String,
+    pub sha256: String,
+    pub created_at: String,
+}
+
+/// マルチパートアップロードで作ったオブジェクトを構成するパート
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+pub struct ObjectPart {
+    // オブジェクトの内部ID
+    pub blob_id: String,
+    pub part_number: i64,
+    pub part_blob_id: String,
+    // オブジェクトの先頭からのパートの位置
+    pub position: i64,
+    pub size: i64,
+    pub sha256: String,
+}
+
+/// `MetadataStore::complete_multipart_upload` の結果
+#[derive(Debug)]
+pub enum CompleteUpload {
+    /// オブジェクトに切り替えた。同じキーのオブジェクトがあれば置き換える前の行を返す
+    Completed(Option<Box<ObjectMetadata>>),
+    /// アップロードが存在しない(完了・中止済み)
+    NoSuchUpload,
+    /// 確認した後にパートが追加・置き換えられた
+    PartsChanged,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -20,6 +159,16 @@ pub struct Bucket {
     pub id: String,
     pub bucket_name: String,
     pub created_at: String,
+    pub data_shards: Option<i64>,
+    pub parity_shards: Option<i64>,
+    pub stripe_unit: Option<i64>,
+}
+
+impl Bucket {
+    /// このバケットに書き込むオブジェクトのイレイジャーコーディングのパラメータ
+    pub fn profile(&self) -> ErasureProfile {
+        ErasureProfile::from_columns(self.data_shards, self.parity_shards, self.stripe_unit)
+    }
 }
 
 #[derive(Clone)]
@@ -29,35 +178,63 @@ pub struct MetadataStore {
 
 impl MetadataStore {
     pub async fn new(db_path: &str) -> Result<Self> {
-        let pool = sqlx::SqlitePool::connect(&db_path).await?;
-        sqlx::migrate!("./migrations").run(&pool).await?;
+        let pool = sqlx::SqlitePool::connect(db_path).await?;
+        // 古いスキーマを読み込んだ接続が残らないよう、置き換えとマイグレーションは同じ接続で行う
+        let mut conn = pool.acquire().await?;
+        replace_legacy_object_table(&mut conn).await?;
+        sqlx::migrate!("./migrations").run(&mut *conn).await?;
         Ok(Self { pool })
     }
 
+    /// 同じキーのオブジェクトがなければメタデータを登録して行IDを返す。既にある場合は何もせず `None` を返す。
     pub async fn insert_metadata(
         &self,
         bucket_name: &str,
         object_id: &str,
-        file_name: Option<&str>,
-        content_type: Option<&str>,
-        content_length: i32,
-    ) -> Result<i64> {
+        metadata: &NewObjectMetadata<'_>,
+    ) -> Result<Option<i64>> {
         let now = Utc::now().to_rfc3339();
+        let (data_shards, parity_shards, stripe_unit) = profile_columns(&metadata.profile);
         let result = sqlx::query!(
             "
-            INSERT INTO object_metadata (bucket_name, object_id, file_name, content_type, content_length, created_at)
-            VALUES (?, ?, ?, ?, ?, ?)
+            INSERT INTO object_metadata (bucket_name, object_id, file_name, content_type, content_length, created_at, user_metadata, md5, sha256, generation, blob_id, data_shards, parity_shards, stripe_unit, packed, part_count)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (bucket_name, object_id) DO NOTHING
             ",
             bucket_name,
             object_id,
-            file_name,
-            content_type,
-            content_length,
-            now
+            metadata.file_name,
+            metadata.content_type,
+            metadata.content_length,
+            now,
+            metadata.user_metadata,
+            metadata.md5,
+            metadata.sha256,
+            metadata.generation,
+            metadata.blob_id,
+            data_shards,
+            parity_shards,
+            stripe_unit,
+            metadata.packed,
+            metadata.part_count
         )
         .execute(&self.pool)
         .await?;
-        Ok(result.last_insert_rowid())
+        Ok((result.rows_affected() == 1).then(|| result.last_insert_rowid()))
+    }
+
+    /// メタデータを登録する。同じキーのオブジェクトがあれば置き換え、置き換える前の行を返す。
+    pub async fn upsert_metadata(
+        &self,
+        bucket_name: &str,
+        object_id: &str,
+        metadata: &NewObjectMetadata<'_>,
+    ) -> Result<Option<ObjectMetadata>> {
+        // 同時に上書きされても古い世代を取りこぼさないよう、読む前に書き込みロックを取る
+        let mut tx = self.pool.begin_with("BEGIN IMMEDIATE").await?;
+        let previous = upsert_metadata_in(&mut tx, bucket_name, object_id, metadata).await?;
+        tx.commit().await?;
+        Ok(previous)
     }
 
     pub async fn get_metadata(
@@ -67,10 +244,13 @@ impl MetadataStore {
     ) -> Result<Option<ObjectMetadata>> {
         let row = sqlx::query_as!(
             ObjectMetadata,
-            "
-            SELECT * FROM object_metadata
+            r#"
+            SELECT id AS "id!", bucket_name, object_id, file_name, content_type, content_length,
+                created_at, user_metadata, md5, sha256, generation, blob_id, health, last_scrubbed_at,
+                data_shards, parity_shards, stripe_unit, packed AS "packed!: bool", part_count
+            FROM object_metadata
             WHERE bucket_name = ? AND object_id = ?
-            ",
+            "#,
             bucket_name,
             object_id
         )
@@ -79,6 +259,195 @@ impl MetadataStore {
         Ok(row)
     }
 
+    /// `prefix` で始まり `start_after` より後ろのキーを、キーの昇順で最大 `limit` 件返す。
+    pub async fn list_objects(
+        &self,
+        bucket_name: &str,
+        prefix: &str,
+        start_after: &str,
+        limit: i64,
+    ) -> Result<Vec<ObjectMetadata>> {
+        // prefix <= object_id < prefixの次の文字列 でインデックスを使って範囲を絞り、前方一致はsubstrで確認する。
+        // 上限がない(prefixが空の)場合は、どのTEXTよりも大きい空のBLOBと比べる
+        let prefix_end = prefix_successor(prefix);
+        let rows = sqlx::query_as!(
+            ObjectMetadata,
+            r#"
+            SELECT id AS "id!", bucket_name, object_id, file_name, content_type, content_length,
+                created_at, user_metadata, md5, sha256, generation, blob_id, health, last_scrubbed_at,
+                data_shards, parity_shards, stripe_unit, packed AS "packed!: bool", part_count
+            FROM object_metadata
+            WHERE bucket_name = ? AND object_id > ? AND object_id >= ?
+                AND object_id < coalesce(?, x'')
+                AND substr(object_id, 1, length(?)) = ?
+            ORDER BY object_id
+            LIMIT ?
+            "#,
+            bucket_name,
+            start_after,
+            prefix,
+            prefix_end,
+            prefix,
+            prefix,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows)
+    }
+
+    /// 行IDが `after_id` より大きいオブジェクトを、バケットに関係なく行IDの昇順で最大 `limit` 件返す。
+    pub async fn list_objects_after_id(
+        &self,
+        after_id: i64,
+        limit: i64,
+    ) -> Result<Vec<ObjectMetadata>> {
+        let rows = sqlx::query_as!(
+            ObjectMetadata,
+            r#"
+            SELECT id AS "id!", bucket_name, object_id, file_name, content_type, content_length,
+                created_at, user_metadata, md5, sha256, generation, blob_id, health, last_scrubbed_at,
+                data_shards, parity_shards, stripe_unit, packed AS "packed!: bool", part_count
+            FROM object_metadata
+            WHERE id > ?
+            ORDER BY id
+            LIMIT ?
+            "#,
+            after_id,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows)
+    }
+
+    /// スクラブの結果を記録する。スクラブ中にオブジェクトが上書き・削除されていた場合は何もせず `false` を返す。
+    pub async fn update_health(
+        &self,
+        bucket_name: &str,
+        object_id: &str,
+        generation: Option<&str>,
+        health: &str,
+        scrubbed_at: &str,
+    ) -> Result<bool> {
+        let result = sqlx::query!(
+            "
+            UPDATE object_metadata SET health = ?, last_scrubbed_at = ?
+            WHERE bucket_name = ? AND object_id = ? AND generation IS ?
+            ",
+            health,
+            scrubbed_at,
+            bucket_name,
+            object_id,
+            generation
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// パックファイルに追記したシャードの位置を登録する。同じシャードの位置が既にあれば置き換える。
+    pub async fn put_pack_entries(&self, entries: &[PackEntry]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for entry in entries {
+            sqlx::query!(
+                "
+                INSERT OR REPLACE INTO pack_entries (bucket_name, object_id, generation, shard_index, segment, position, length)
+                VALUES (?, ?, ?, ?, ?, ?, ?)
+                ",
+                entry.bucket_name,
+                entry.object_id,
+                entry.generation,
+                entry.shard_index,
+                entry.segment,
+                entry.position,
+                entry.length
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn get_pack_entries(
+        &self,
+        bucket_name: &str,
+        object_id: &str,
+        generation: &str,
+    ) -> Result<Vec<PackEntry>> {
+        let rows = sqlx::query_as!(
+            PackEntry,
+            "
+            SELECT bucket_name, object_id, generation, shard_index, segment, position, length
+            FROM pack_entries
+            WHERE bucket_name = ? AND object_id = ? AND generation = ?
+            ORDER BY shard_index
+            ",
+            bucket_name,
+            object_id,
+            generation
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows)
+    }
+
+    /// セグメントにあるシャードのうち、まだオブジェクトから参照されているものを返す。
+    pub async fn live_pack_entries(&self, segment: &str) -> Result<Vec<PackEntry>> {
+        let rows = sqlx::query_as!(
+            PackEntry,
+            "
+            SELECT e.bucket_name, e.object_id, e.generation, e.shard_index, e.segment, e.position, e.length
+            FROM pack_entries e
+            JOIN object_metadata o
+                ON o.bucket_name = e.bucket_name AND o.object_id = e.object_id
+                AND o.generation = e.generation
+            WHERE e.segment = ? AND o.packed = 1
+            ORDER BY e.position
+            ",
+            segment
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows)
+    }
+
+    /// シャードの位置を付け替える。その間に位置が変わっていた場合は何もせず `false` を返す。
+    pub async fn move_pack_entry(
+        &self,
+        entry: &PackEntry,
+        segment: &str,
+        position: i64,
+    ) -> Result<bool> {
+        let result = sqlx::query!(
+            "
+            UPDATE pack_entries SET segment = ?, position = ?
+            WHERE bucket_name = ? AND object_id = ? AND generation = ? AND shard_index = ?
+                AND segment = ? AND position = ?
+            ",
+            segment,
+            position,
+            entry.bucket_name,
+            entry.object_id,
+            entry.generation,
+            entry.shard_index,
+            entry.segment,
+            entry.position
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// セグメントに残っているシャードの位置を全て削除する。
+    pub async fn delete_pack_entries(&self, segment: &str) -> Result<u64> {
+        let result = sqlx::query!("DELETE FROM pack_entries WHERE segment = ?", segment)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
     pub async fn delete_metadata(
         &self,
         bucket_name: &str,
@@ -102,51 +471,538 @@ impl MetadataStore {
         bucket_id: &str,
         bucket_name: &str,
         created_at: &str,
+        profile: &ErasureProfile,
     ) -> Result<SqliteQueryResult, Error> {
-        let result = sqlx::query!(
+        let (data_shards, parity_shards, stripe_unit) = profile_columns(profile);
+        sqlx::query!(
             "
-            INSERT OR IGNORE INTO bucket_metadata (id, bucket_name, created_at)
-            VALUES (?, ?, ?)
+            INSERT OR IGNORE INTO bucket_metadata (id, bucket_name, created_at, data_shards, parity_shards, stripe_unit)
+            VALUES (?, ?, ?, ?, ?, ?)
             ",
             bucket_id,
             bucket_name,
-            created_at
+            created_at,
+            data_shards,
+            parity_shards,
+            stripe_unit
         )
         .execute(&self.pool)
-        .await;
-
-        result
+        .await
     }
 
     pub async fn get_buckets(&self) -> Result<Vec<Bucket>, Error> {
-        let result = sqlx::query_as!(Bucket, "SELECT * FROM bucket_metadata")
+        sqlx::query_as!(Bucket, "SELECT * FROM bucket_metadata")
             .fetch_all(&self.pool)
-            .await;
+            .await
+    }
 
-        result
+    pub async fn get_bucket(&self, bucket_name: &str) -> Result<Option<Bucket>, Error> {
+        sqlx::query_as!(
+            Bucket,
+            "SELECT * FROM bucket_metadata WHERE bucket_name = ?",
+            bucket_name
+        )
+        .fetch_optional(&self.pool)
+        .await
     }
 
     pub async fn delete_bucket(&self, bucket_name: &str) -> Result<SqliteQueryResult, Error> {
-        let result = sqlx::query!(
+        sqlx::query!(
             "
             DELETE FROM bucket_metadata WHERE bucket_name = ?
             ",
             bucket_name,
         )
         .execute(&self.pool)
-        .await;
+        .await
+    }
 
-        result
+    pub async fn count_objects(&self, bucket_name: &str) -> Result<i64, Error> {
+        sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM object_metadata WHERE bucket_name = ?",
+            bucket_name
+        )
+        .fetch_one(&self.pool)
+        .await
     }
 
     pub async fn exist_buckets(&self, bucket_name: &str) -> Result<i64, Error> {
-        let result = sqlx::query_scalar!(
+        sqlx::query_scalar!(
             "SELECT EXISTS(SELECT 1 FROM bucket_metadata WHERE bucket_name = ?)",
             bucket_name
         )
         .fetch_one(&self.pool)
-        .await;
+        .await
+    }
+
+    /// マルチパートアップロードを登録する。
+    pub async fn create_multipart_upload(&self, upload: &MultipartUpload) -> Result<()> {
+        sqlx::query!(
+            "
+            INSERT INTO multipart_uploads (upload_id, bucket_name, object_id, created_at, file_name, content_type, user_metadata, data_shards, parity_shards, stripe_unit)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ",
+            upload.upload_id,
+            upload.bucket_name,
+            upload.object_id,
+            upload.created_at,
+            upload.file_name,
+            upload.content_type,
+            upload.user_metadata,
+            upload.data_shards,
+            upload.parity_shards,
+            upload.stripe_unit
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_multipart_upload(&self, upload_id: &str) -> Result<Option<MultipartUpload>> {
+        let row = sqlx::query_as!(
+            MultipartUpload,
+            r#"
+            SELECT upload_id AS "upload_id!", bucket_name, object_id, created_at, file_name, content_type,
+                user_metadata, data_shards, parity_shards, stripe_unit
+            FROM multipart_uploads
+            WHERE upload_id = ?
+            "#,
+            upload_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row)
+    }
+
+    /// `created_before` より前に始まったマルチパートアップロードを最大 `limit` 件返す。
+    pub async fn list_expired_multipart_uploads(
+        &self,
+        created_before: &str,
+        limit: i64,
+    ) -> Result<Vec<MultipartUpload>> {
+        let rows = sqlx::query_as!(
+            MultipartUpload,
+            r#"
+            SELECT upload_id AS "upload_id!", bucket_name, object_id, created_at, file_name, content_type,
+                user_metadata, data_shards, parity_shards, stripe_unit
+            FROM multipart_uploads
+            WHERE created_at < ?
+            ORDER BY created_at
+            LIMIT ?
+            "#,
+            created_before,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows)
+    }
+
+    /// バケットの進行中のマルチパートアップロードを最大 `limit` 件返す。
+    pub async fn list_bucket_multipart_uploads(
+        &self,
+        bucket_name: &str,
+        limit: i64,
+    ) -> Result<Vec<MultipartUpload>> {
+        let rows = sqlx::query_as!(
+            MultipartUpload,
+            r#"
+            SELECT upload_id AS "upload_id!", bucket_name, object_id, created_at, file_name, content_type,
+                user_metadata, data_shards, parity_shards, stripe_unit
+            FROM multipart_uploads
+            WHERE bucket_name = ?
+            ORDER BY created_at
+            LIMIT ?
+            "#,
+            bucket_name,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows)
+    }
+
+    /// アップロード済みのパートをパート番号の昇順で返す。
+    pub async fn list_uploaded_parts(&self, upload_id: &str) -> Result<Vec<UploadedPart>> {
+        let rows = sqlx::query_as!(
+            UploadedPart,
+            "
+            SELECT * FROM multipart_parts WHERE upload_id = ?
+            ORDER BY part_number
+            ",
+            upload_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows)
+    }
+
+    /// 受け取ったパートを登録する。同じ番号のパートがあれば置き換え、置き換える前の行を返す。
+    /// アップロードが完了・中止されていた場合は外部キー制約でエラーになる。
+    pub async fn put_uploaded_part(&self, part: &UploadedPart) -> Result<Option<UploadedPart>> {
+        let mut tx = self.pool.begin_with("BEGIN IMMEDIATE").await?;
+        let previous = sqlx::query_as!(
+            UploadedPart,
+            "SELECT * FROM multipart_parts WHERE upload_id = ? AND part_number = ?",
+            part.upload_id,
+            part.part_number
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "
+            INSERT OR REPLACE INTO multipart_parts (upload_id, part_number, blob_id, size, md5, sha256, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            ",
+            part.upload_id,
+            part.part_number,
+            part.blob_id,
+            part.size,
+            part.md5,
+            part.sha256,
+            part.created_at
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(previous)
+    }
+
+    /// マルチパートアップロードをオブジェクトに切り替える。
+    /// `checked` は呼び出し側が確認したときのパートの一覧で、それから変わっていれば何もしない。
+    /// アップロードとパートの記録は削除する(`parts` に含まれないパートのシャードは呼び出し側で削除する)。
+    pub async fn complete_multipart_upload(
+        &self,
+        upload: &MultipartUpload,
+        checked: &[UploadedPart],
+        metadata: &NewObjectMetadata<'_>,
+        parts: &[ObjectPart],
+    ) -> Result<CompleteUpload> {
+        let mut tx = self.pool.begin_with("BEGIN IMMEDIATE").await?;
+        let exists = sqlx::query_scalar!(
+            "SELECT upload_id FROM multipart_uploads WHERE upload_id = ?",
+            upload.upload_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if exists.is_none() {
+            return Ok(CompleteUpload::NoSuchUpload);
+        }
+        let current = sqlx::query_as!(
+            UploadedPart,
+            "
+            SELECT * FROM multipart_parts WHERE upload_id = ?
+            ORDER BY part_number
+            ",
+            upload.upload_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        if current != checked {
+            return Ok(CompleteUpload::PartsChanged);
+        }
+        for part in parts {
+            sqlx::query!(
+                "
+                INSERT INTO object_parts (blob_id, part_number, part_blob_id, position, size, sha256)
+                VALUES (?, ?, ?, ?, ?, ?)
+                ",
+                part.blob_id,
+                part.part_number,
+                part.part_blob_id,
+                part.position,
+                part.size,
+                part.sha256
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        let previous =
+            upsert_metadata_in(&mut tx, &upload.bucket_name, &upload.object_id, metadata).await?;
+        // パートの記録は外部キーで一緒に消える
+        sqlx::query!(
+            "DELETE FROM multipart_uploads WHERE upload_id = ?",
+            upload.upload_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(CompleteUpload::Completed(previous.map(Box::new)))
+    }
+
+    /// マルチパートアップロードを削除し、アップロード済みだったパートを返す。存在しなければ `None` を返す。
+    pub async fn delete_multipart_upload(
+        &self,
+        upload_id: &str,
+    ) -> Result<Option<Vec<UploadedPart>>> {
+        let mut tx = self.pool.begin_with("BEGIN IMMEDIATE").await?;
+        let parts = sqlx::query_as!(
+            UploadedPart,
+            "
+            SELECT * FROM multipart_parts WHERE upload_id = ?
+            ORDER BY part_number
+            ",
+            upload_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        let result = sqlx::query!(
+            "DELETE FROM multipart_uploads WHERE upload_id = ?",
+            upload_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok((result.rows_affected() == 1).then_some(parts))
+    }
+
+    /// `source` と同じシャードを参照するオブジェクトを `target` (バケット名, キー)に作る。
+    /// `move_source` が `true` なら同じトランザクションで `source` の行を削除する(リネーム)。
+    /// `metadata.blob_id` には `source.shard_id()` を渡す。
+    pub async fn link_object(
+        &self,
+        source: &ObjectMetadata,
+        target: (&str, &str),
+        metadata: &NewObjectMetadata<'_>,
+        if_none_match: bool,
+        move_source: bool,
+    ) -> Result<LinkObject> {
+        let (bucket_name, object_id) = target;
+        let mut tx = self.pool.begin_with("BEGIN IMMEDIATE").await?;
+        // 読んだ後に上書き・削除されていれば、シャードが既に消されているかもしれない
+        let current = sqlx::query_scalar!(
+            "SELECT id FROM object_metadata WHERE id = ? AND generation IS ?",
+            source.id,
+            source.generation
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if current.is_none() {
+            return Ok(LinkObject::SourceChanged);
+        }
+        if if_none_match {
+            let exists = sqlx::query_scalar!(
+                "SELECT id FROM object_metadata WHERE bucket_name = ? AND object_id = ?",
+                bucket_name,
+                object_id
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+            if exists.is_some() {
+                return Ok(LinkObject::AlreadyExists);
+            }
+        }
+        // 内部IDを持たない古いオブジェクトは、参照を数えられるようにキーから作った名前を記録する
+        sqlx::query!(
+            "UPDATE object_metadata SET blob_id = ? WHERE id = ? AND blob_id IS NULL",
+            metadata.blob_id,
+            source.id
+        )
+        .execute(&mut *tx)
+        .await?;
+        let previous = upsert_metadata_in(&mut tx, bucket_name, object_id, metadata).await?;
+        if move_source {
+            sqlx::query!("DELETE FROM object_metadata WHERE id = ?", source.id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(LinkObject::Linked(previous.map(Box::new)))
+    }
+
+    /// シャードの内部IDを参照しているオブジェクトの数を返す。
+    pub async fn count_blob_refs(&self, blob_id: &str) -> Result<i64> {
+        let count = sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM object_metadata WHERE blob_id = ?",
+            blob_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(count)
+    }
+
+    /// オブジェクトのその世代がまだあるか、同じ内部IDを参照するオブジェクトがあれば `true` を返す。
+    /// `false` ならオブジェクトのシャードはもう使われない。
+    pub async fn object_in_use(
+        &self,
+        bucket_name: &str,
+        object_id: &str,
+        generation: Option<&str>,
+        blob_id: &str,
+    ) -> Result<bool> {
+        let count = sqlx::query_scalar!(
+            "
+            SELECT COUNT(*) FROM object_metadata
+            WHERE (bucket_name = ? AND object_id = ? AND generation IS ?) OR blob_id = ?
+            ",
+            bucket_name,
+            object_id,
+            generation,
+            blob_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(count > 0)
+    }
+
+    /// 世代が変わっていなければオブジェクトのメタデータを削除し、削除したかどうかを返す。
+    pub async fn delete_metadata_generation(
+        &self,
+        bucket_name: &str,
+        object_id: &str,
+        generation: Option<&str>,
+    ) -> Result<bool> {
+        let result = sqlx::query!(
+            "
+            DELETE FROM object_metadata WHERE bucket_name = ? AND object_id = ? AND generation IS ?
+            ",
+            bucket_name,
+            object_id,
+            generation
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// マルチパートアップロードで作ったオブジェクトのパートを、オブジェクトの先頭から順に返す。
+    pub async fn get_object_parts(&self, blob_id: &str) -> Result<Vec<ObjectPart>> {
+        let rows = sqlx::query_as!(
+            ObjectPart,
+            "
+            SELECT * FROM object_parts WHERE blob_id = ?
+            ORDER BY part_number
+            ",
+            blob_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows)
+    }
+
+    pub async fn delete_object_parts(&self, blob_id: &str) -> Result<u64> {
+        let result = sqlx::query!("DELETE FROM object_parts WHERE blob_id = ?", blob_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+/// マイグレーションの前に、最新の形の `object_metadata` を用意する。
+///
+/// 初期のマイグレーションは空のデータベースに `object_key` 列のテーブルを作るが、コードは最初から `object_id` 列を使うので
+/// そのテーブルには1行も書き込めない。SQLのマイグレーションでは列の有無で分岐できないので、ここで空の古いテーブルを置き換える。
+async fn replace_legacy_object_table(conn: &mut SqliteConnection) -> Result<()> {
+    let legacy: bool = sqlx::query_scalar(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('object_metadata') WHERE name = 'object_key'",
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    if legacy {
+        let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM object_metadata")
+            .fetch_one(&mut *conn)
+            .await?;
+        if rows > 0 {
+            bail!("object_metadata has an object_key column and {rows} rows; migrate it by hand");
+        }
+        sqlx::query("DROP TABLE object_metadata")
+            .execute(&mut *conn)
+            .await?;
+    }
+    sqlx::raw_sql(include_str!(
+        "../migrations/20250509172846_object_metadata_table.sql"
+    ))
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// `upsert_metadata` の本体。書き込みロックを取ったトランザクションの中で呼ぶ。
+async fn upsert_metadata_in(
+    conn: &mut SqliteConnection,
+    bucket_name: &str,
+    object_id: &str,
+    metadata: &NewObjectMetadata<'_>,
+) -> Result<Option<ObjectMetadata>> {
+    let now = Utc::now().to_rfc3339();
+    let (data_shards, parity_shards, stripe_unit) = profile_columns(&metadata.profile);
+    let previous = sqlx::query_as!(
+        ObjectMetadata,
+        r#"
+        SELECT id AS "id!", bucket_name, object_id, file_name, content_type, content_length,
+            created_at, user_metadata, md5, sha256, generation, blob_id, health, last_scrubbed_at,
+            data_shards, parity_shards, stripe_unit, packed AS "packed!: bool", part_count
+        FROM object_metadata
+        WHERE bucket_name = ? AND object_id = ?
+        "#,
+        bucket_name,
+        object_id
+    )
+    .fetch_optional(&mut *conn)
+    .await?;
+    sqlx::query!(
+        "
+        INSERT INTO object_metadata (bucket_name, object_id, file_name, content_type, content_length, created_at, user_metadata, md5, sha256, generation, blob_id, data_shards, parity_shards, stripe_unit, packed, part_count)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT (bucket_name, object_id) DO UPDATE SET
+            file_name = excluded.file_name,
+            content_type = excluded.content_type,
+            content_length = excluded.content_length,
+            created_at = excluded.created_at,
+            user_metadata = excluded.user_metadata,
+            md5 = excluded.md5,
+            sha256 = excluded.sha256,
+            generation = excluded.generation,
+            blob_id = excluded.blob_id,
+            data_shards = excluded.data_shards,
+            parity_shards = excluded.parity_shards,
+            stripe_unit = excluded.stripe_unit,
+            packed = excluded.packed,
+            part_count = excluded.part_count,
+            health = NULL,
+            last_scrubbed_at = NULL
+        ",
+        bucket_name,
+        object_id,
+        metadata.file_name,
+        metadata.content_type,
+        metadata.content_length,
+        now,
+        metadata.user_metadata,
+        metadata.md5,
+        metadata.sha256,
+        metadata.generation,
+        metadata.blob_id,
+        data_shards,
+        parity_shards,
+        stripe_unit,
+        metadata.packed,
+        metadata.part_count
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(previous)
+}
+
+fn profile_columns(profile: &ErasureProfile) -> (i64, i64, i64) {
+    (
+        profile.data_shards as i64,
+        profile.parity_shards as i64,
+        profile.stripe_unit as i64,
+    )
+}
 
-        result
+/// `prefix` で始まる全ての文字列より大きい最小の文字列。`prefix` が空なら上限はない。
+/// SQLiteはTEXTをUTF-8のバイト列として比べるので、文字のコードポイント順と一致する。
+fn prefix_successor(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        // サロゲートの範囲は文字にならないので飛ばす
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
     }
+    None
 }
@@ -1,44 +1,482 @@
-use crate::env::{DATA_SHARDS, PARITY_SHARDS};
-use crate::get_filepath;
-use anyhow::Result;
-use bytes::BytesMut;
+use crate::profile::ErasureProfile;
+use crate::scrub::RepairOwner;
+use crate::shard::{MAGIC, ShardFormat, ShardHeader, StripeLayout, header_len, header_len_of};
+use crate::store::{SharedShardStore, read_range};
+use anyhow::{Result, anyhow, bail};
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, TryStreamExt, future::join_all};
 use reed_solomon_erasure::galois_8::ReedSolomon;
-use tokio::fs;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::ops::Range;
+use std::path::PathBuf;
 use tracing::{error, info, instrument};
 
-#[instrument]
-pub async fn load_shards(object_id: &str) -> Result<Vec<Option<BytesMut>>> {
-    info!("Data loading...");
-    let mut shards = vec![None; DATA_SHARDS + PARITY_SHARDS];
+fn verify_sha256(digest: impl AsRef<[u8]>, expected: &str) -> Result<()> {
+    let actual = hex::encode(digest);
+    if !actual.eq_ignore_ascii_case(expected) {
+        error!(
+            "Decoded data does not match the stored digest: expected {}, got {}",
+            expected, actual
+        );
+        bail!("sha256 mismatch: expected {}, got {}", expected, actual);
+    }
+    Ok(())
+}
+
+/// シャードの置き場所。パックファイルに追記したシャードはファイルの途中(`offset`)から始まる。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardLocation {
+    pub path: PathBuf,
+    pub offset: u64,
+}
+
+/// シャードからストライプ単位でデータを読み出す。
+/// データシャードが揃っているストライプはそのまま返し、欠けている場合だけパリティから復元する。
+pub struct ShardReader {
+    store: SharedShardStore,
+    object_id: String,
+    profile: ErasureProfile,
+    layout: StripeLayout,
+    r: ReedSolomon,
+    files: Vec<Option<ShardFile>>,
+    // 直前に復元したストライプ。同じストライプにかかる範囲を続けて読むときに使い回す
+    cached: Option<(usize, Bytes)>,
+    // 読めなかったシャードのインデックス
+    damaged: BTreeSet<usize>,
+    // read-repairが有効な場合の、元データのSHA-256とシャードを持つオブジェクト
+    read_repair: Option<(Option<String>, RepairOwner)>,
+}
+
+struct ShardFile {
+    index: usize,
+    // パックファイルに追記したシャードはそのセグメントから直接読む
+    segment: Option<PathBuf>,
+    // 読み出し元の先頭からペイロードまでのバイト数
+    payload_offset: u64,
+    // ヘッダを読んで分かったファイルの形式
+    format: ShardFormat,
+}
 
-    for i in 0..(DATA_SHARDS + PARITY_SHARDS) {
-        let filepath = get_filepath(object_id, i).await;
+impl ShardFile {
+    async fn read(
+        &self,
+        store: &SharedShardStore,
+        object_id: &str,
+        range: Range<u64>,
+    ) -> Result<Bytes> {
+        match &self.segment {
+            Some(segment) => read_range(segment, range).await,
+            None => store.get(object_id, self.index, range).await,
+        }
+    }
+}
+
+impl ShardReader {
+    /// `store` に置かれたシャードを読む。`profile` にはオブジェクトを書き込んだときのパラメータを渡す。
+    #[instrument(skip(store))]
+    pub async fn open(
+        store: &SharedShardStore,
+        object_id: &str,
+        content_length: u64,
+        profile: ErasureProfile,
+    ) -> Result<Self> {
+        let files = (0..profile.total_shards())
+            .map(|index| {
+                Some(ShardFile {
+                    index,
+                    segment: None,
+                    payload_offset: 0,
+                    format: ShardFormat::V3,
+                })
+            })
+            .collect();
+        Self::open_files(store, object_id, content_length, profile, files).await
+    }
+
+    /// パックファイルの `locations` の位置からシャードを読む。`locations[i]` が `None` のシャードは欠損として扱う。
+    #[instrument(skip(store))]
+    pub async fn open_at(
+        store: &SharedShardStore,
+        object_id: &str,
+        content_length: u64,
+        profile: ErasureProfile,
+        locations: Vec<Option<ShardLocation>>,
+    ) -> Result<Self> {
+        let files = (0..profile.total_shards())
+            .map(|index| {
+                let location = locations.get(index).cloned().flatten();
+                if location.is_none() {
+                    error!("Shard {} has no location.", index);
+                }
+                location.map(|location| ShardFile {
+                    index,
+                    segment: Some(location.path),
+                    payload_offset: location.offset,
+                    format: ShardFormat::V3,
+                })
+            })
+            .collect();
+        Self::open_files(store, object_id, content_length, profile, files).await
+    }
 
-        match fs::read(&filepath).await {
-            Ok(content) => {
-                shards[i] = Some(BytesMut::from(&content[..]));
+    async fn open_files(
+        store: &SharedShardStore,
+        object_id: &str,
+        content_length: u64,
+        profile: ErasureProfile,
+        candidates: Vec<Option<ShardFile>>,
+    ) -> Result<Self> {
+        // 応答の遅いシャードを待つ間に他のシャードも開けるよう、全てのシャードを同時に開く
+        let opened = join_all(candidates.into_iter().map(|file| async move {
+            let file = file?;
+            let index = file.index;
+            match Self::open_shard(store, file, object_id, content_length, profile).await {
+                Ok(file) => Some(file),
+                Err(e) => {
+                    error!("Shard {} of {} is unavailable: {}", index, object_id, e);
+                    None
+                }
             }
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                error!("Shard {} not found in {:?}.", i, filepath);
+        }))
+        .await;
+        // ストライプに分ける前のシャードはオブジェクト全体を1ストライプにしている。
+        // 作り直したシャードもそれに合わせて書くので、多くのシャードが使っている方に揃える(同数なら今のプロファイル)
+        let stripe_unit = [
+            profile.unstriped(content_length).stripe_unit,
+            profile.stripe_unit,
+        ]
+        .into_iter()
+        .max_by_key(|unit| opened.iter().flatten().filter(|(_, u)| u == unit).count())
+        .unwrap_or(profile.stripe_unit);
+        let profile = ErasureProfile {
+            stripe_unit,
+            ..profile
+        };
+        let files: Vec<Option<ShardFile>> = opened
+            .into_iter()
+            .map(|opened| {
+                let (file, unit) = opened?;
+                if unit != stripe_unit {
+                    error!(
+                        "Shard {} of {} has a different stripe unit from the other shards.",
+                        file.index, object_id
+                    );
+                    return None;
+                }
+                Some(file)
+            })
+            .collect();
+
+        let damaged: BTreeSet<usize> = (0..files.len()).filter(|&i| files[i].is_none()).collect();
+        let available = files.len() - damaged.len();
+        if available < profile.data_shards {
+            bail!(
+                "not enough shards to decode: {} of {} required",
+                available,
+                profile.data_shards
+            );
+        }
+
+        Ok(Self {
+            store: store.clone(),
+            object_id: object_id.to_string(),
+            profile,
+            layout: profile.layout(content_length),
+            r: profile.reed_solomon()?,
+            files,
+            cached: None,
+            damaged,
+            read_repair: None,
+        })
+    }
+
+    /// 読み終わった後(レスポンスを返し終えた後)に、読めなかったシャードをバックグラウンドで作り直す。
+    /// 作り直す前に、復元したデータが `expected_sha256` と一致することと、`owner` がまだあることを確認する。
+    pub fn with_read_repair(mut self, expected_sha256: Option<String>, owner: RepairOwner) -> Self {
+        self.read_repair = Some((expected_sha256, owner));
+        self
+    }
+
+    /// シャードのヘッダを読んで検証し、ペイロードの位置と形式、ストライプ単位を返す。
+    async fn open_shard(
+        store: &SharedShardStore,
+        mut file: ShardFile,
+        object_id: &str,
+        content_length: u64,
+        profile: ErasureProfile,
+    ) -> Result<(ShardFile, usize)> {
+        let start = file.payload_offset;
+        let header = match Self::read_header(store, &file, object_id).await {
+            Ok(mut buf) if buf.starts_with(MAGIC) => ShardHeader::read_from(&mut buf),
+            Ok(_) => Err(anyhow!("invalid shard magic")),
+            Err(e) => Err(e),
+        };
+        let header = match header {
+            Ok(header) => header,
+            Err(e) => {
+                // ヘッダのないベースラインのシャードは、ヘッダより短いこともある
+                Self::legacy_header(store, &file, object_id, content_length, profile)
+                    .await
+                    .ok_or(e)?
             }
-            Err(e) => return Err(e.into()),
+        };
+        header.validate(
+            object_id,
+            file.index,
+            profile.data_shards,
+            profile.parity_shards,
+        )?;
+        // ストライプに分けていない形式は、オブジェクト全体が1ストライプに収まるストライプ単位として扱う
+        let stripe_unit = if header.format.is_striped() {
+            header.stripe_unit as usize
+        } else {
+            profile.unstriped(content_length).stripe_unit
+        };
+        if header.original_len != content_length
+            || (stripe_unit != profile.stripe_unit
+                && stripe_unit != profile.unstriped(content_length).stripe_unit)
+        {
+            bail!(
+                "shard layout mismatch: original_len {}, stripe_unit {}",
+                header.original_len,
+                header.stripe_unit
+            );
         }
+        file.payload_offset = start + header.encoded_len() as u64;
+        file.format = header.format;
+        Ok((file, stripe_unit))
+    }
+
+    /// ヘッダの部分を読む。今の形式のヘッダの長さで足りなければ、足りない分を含めて読み直す。
+    async fn read_header(
+        store: &SharedShardStore,
+        file: &ShardFile,
+        object_id: &str,
+    ) -> Result<Bytes> {
+        let start = file.payload_offset;
+        let buf = file
+            .read(
+                store,
+                object_id,
+                start..start + header_len(object_id) as u64,
+            )
+            .await?;
+        let len = header_len_of(&buf, object_id);
+        if len <= buf.len() {
+            return Ok(buf);
+        }
+        file.read(store, object_id, start..start + len as u64).await
+    }
+
+    /// ファイルの長さがベースラインのシャードと一致すれば、ヘッダのないシャードとして扱う。
+    /// ベースラインのシャードはチェックサムを持たないので、中身が壊れていても検出できない。
+    async fn legacy_header(
+        store: &SharedShardStore,
+        file: &ShardFile,
+        object_id: &str,
+        content_length: u64,
+        profile: ErasureProfile,
+    ) -> Option<ShardHeader> {
+        // パックファイルはベースラインの後に導入したので、ヘッダのないシャードはない
+        if file.segment.is_some() {
+            return None;
+        }
+        let header = ShardHeader::legacy(object_id, file.index, profile, content_length);
+        let stat = store.stat(object_id, file.index).await.ok()??;
+        (stat.len == header.layout().shard_len()).then_some(header)
     }
-    Ok(shards)
-}
 
-#[instrument(skip(shards))]
-pub async fn decode_shards(shards: &mut Vec<Option<BytesMut>>) -> Result<BytesMut> {
-    info!("decoding...");
-    let r = ReedSolomon::new(DATA_SHARDS, PARITY_SHARDS)?;
-    r.reconstruct(shards)?;
+    async fn read_chunk(
+        store: &SharedShardStore,
+        object_id: &str,
+        file: &ShardFile,
+        layout: StripeLayout,
+        stripe: usize,
+    ) -> Result<BytesMut> {
+        // ヘッダのないシャードはストライプが1つだけなので、チャンクの位置はチェックサムの有無によらない
+        let start = file.payload_offset + layout.chunk_offset(stripe);
+        let len = (layout.chunk_len(stripe) + file.format.trailer_len()) as u64;
+        let buf = file.read(store, object_id, start..start + len).await?;
+        Ok(BytesMut::from(file.format.read_chunk(&buf)?))
+    }
+
+    /// 指定したシャードのチャンクを読む。読めなかったものは欠損として `None` にする。
+    async fn read_chunks(&mut self, stripe: usize, indices: Range<usize>) -> Vec<Option<BytesMut>> {
+        let layout = self.layout;
+        let start = indices.start;
+        let store = &self.store;
+        let object_id = self.object_id.as_str();
+        let chunks = join_all(self.files[indices].iter().map(|file| async move {
+            let file = file.as_ref()?;
+            match Self::read_chunk(store, object_id, file, layout, stripe).await {
+                Ok(chunk) => Some(chunk),
+                Err(e) => {
+                    error!(
+                        "Stripe {} of shard {} of {} is unreadable: {}",
+                        stripe, file.index, object_id, e
+                    );
+                    None
+                }
+            }
+        }))
+        .await;
+        self.damaged.extend(
+            chunks
+                .iter()
+                .enumerate()
+                .filter(|(_, chunk)| chunk.is_none())
+                .map(|(i, _)| start + i),
+        );
+        chunks
+    }
+
+    /// これまでに読めなかったシャードのインデックス
+    pub fn damaged_shards(&self) -> Vec<usize> {
+        self.damaged.iter().copied().collect()
+    }
+
+    pub fn profile(&self) -> ErasureProfile {
+        self.profile
+    }
 
-    let mut output = BytesMut::new();
-    for shard in shards.iter().take(DATA_SHARDS) {
-        if let Some(s) = shard {
-            output.extend_from_slice(s);
+    pub fn stripe_count(&self) -> usize {
+        self.layout.stripe_count()
+    }
+
+    /// 開けなかった、またはヘッダが壊れていたシャードのインデックス
+    pub fn unavailable_shards(&self) -> Vec<usize> {
+        (0..self.files.len())
+            .filter(|&i| self.files[i].is_none())
+            .collect()
+    }
+
+    /// ストライプ `stripe` の全てのシャードのチャンクを読み、読めなかったシャードのインデックスを返す。
+    /// チェックサムが合わないチャンクも読めなかったものとして扱う。
+    pub async fn verify_stripe(&mut self, stripe: usize) -> Vec<usize> {
+        self.read_chunks(stripe, 0..self.profile.total_shards())
+            .await
+            .iter()
+            .enumerate()
+            .filter(|(_, chunk)| chunk.is_none())
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// ストライプ `stripe` の元データを返す。
+    #[instrument(skip(self), fields(object_id = %self.object_id))]
+    pub async fn read_stripe(&mut self, stripe: usize) -> Result<BytesMut> {
+        let data_shards = self.profile.data_shards;
+        let mut chunks = self.read_chunks(stripe, 0..data_shards).await;
+        if chunks.iter().any(|c| c.is_none()) {
+            // データシャードが欠けているときだけパリティを読んで復元する
+            chunks.extend(
+                self.read_chunks(stripe, data_shards..self.profile.total_shards())
+                    .await,
+            );
+            info!("reconstructing stripe {}...", stripe);
+            self.r.reconstruct_data(&mut chunks)?;
         }
+
+        let mut output = BytesMut::with_capacity(self.layout.stripe_len());
+        for chunk in chunks.iter().take(data_shards).flatten() {
+            output.extend_from_slice(chunk);
+        }
+        output.truncate(self.layout.stripe_data_len(stripe));
+        Ok(output)
+    }
+
+    /// `range` の先頭を含むストライプを読み、そのストライプに収まる分のデータを返して `range` を進める。
+    pub async fn read_next(&mut self, range: &mut Range<u64>) -> Result<Bytes> {
+        if range.end > self.layout.original_len {
+            bail!(
+                "range {:?} is out of bounds for {} bytes",
+                range,
+                self.layout.original_len
+            );
+        }
+        let stripe_len = self.layout.stripe_len() as u64;
+        let stripe = (range.start / stripe_len) as usize;
+        let data = match &self.cached {
+            Some((cached, data)) if *cached == stripe => data.clone(),
+            _ => {
+                let data = self.read_stripe(stripe).await?.freeze();
+                self.cached = Some((stripe, data.clone()));
+                data
+            }
+        };
+
+        let stripe_start = stripe as u64 * stripe_len;
+        let start = (range.start - stripe_start) as usize;
+        let end = (range.end - stripe_start).min(data.len() as u64) as usize;
+        range.start = stripe_start + end as u64;
+        Ok(data.slice(start..end))
+    }
+
+    /// 元データ全体を流し、最後にSHA-256を検証するストリームに変換する。
+    /// 検証が済むまで最後のチャンクを送らないので、不一致の場合にクライアントが完全なデータを受け取ることはない。
+    pub fn into_verified_stream(
+        self,
+        expected_sha256: String,
+    ) -> impl Stream<Item = Result<Bytes>> {
+        let len = self.layout.original_len;
+        let inner = Box::pin(self.into_stream(0..len));
+        let state = (inner, Some(Sha256::new()), None::<Bytes>, expected_sha256);
+        futures::stream::try_unfold(
+            state,
+            |(mut inner, mut hasher, mut held, expected)| async move {
+                // 検証済みならストリームを終える
+                let Some(digest) = hasher.as_mut() else {
+                    return Ok(None);
+                };
+                while let Some(data) = inner.try_next().await? {
+                    digest.update(&data);
+                    if let Some(previous) = held.replace(data) {
+                        return Ok(Some((previous, (inner, hasher, held, expected))));
+                    }
+                }
+                let digest = hasher.take().unwrap().finalize();
+                verify_sha256(digest, &expected)?;
+                // 0バイトのオブジェクトは送るデータがない
+                Ok(held
+                    .take()
+                    .map(|last| (last, (inner, hasher, None, expected))))
+            },
+        )
     }
 
-    Ok(output)
+    /// 元データの `range` の部分を流すストリームに変換する。必要なストライプだけを読む。
+    pub fn into_stream(self, range: Range<u64>) -> impl Stream<Item = Result<Bytes>> {
+        futures::stream::try_unfold((self, range), |(mut reader, mut range)| async move {
+            if range.is_empty() {
+                return Ok(None);
+            }
+            let data = reader.read_next(&mut range).await?;
+            Ok(Some((data, (reader, range))))
+        })
+    }
+}
+
+impl Drop for ShardReader {
+    fn drop(&mut self) {
+        let Some((expected_sha256, owner)) = self.read_repair.take() else {
+            return;
+        };
+        if self.damaged.is_empty() {
+            return;
+        }
+        // レスポンスの送信が終わってからシャードを作り直す
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        handle.spawn(crate::scrub::read_repair(
+            self.store.clone(),
+            self.object_id.clone(),
+            self.layout.original_len,
+            self.profile,
+            expected_sha256,
+            self.damaged_shards(),
+            owner,
+        ));
+    }
 }
@@ -1,49 +1,414 @@
-use crate::env::{DATA_SHARDS, PARITY_SHARDS};
-use crate::get_filepath;
-use anyhow::Result;
+use crate::profile::ErasureProfile;
+use crate::shard::{CHECKSUM_LEN, ShardHeader, chunk_len_for, write_chunk};
+use crate::store::{ShardUpload, SharedShardStore};
+use anyhow::{Result, bail};
 use bytes::{BufMut, BytesMut};
+use futures::future::{join_all, try_join_all};
+use md5::{Digest, Md5};
 use rayon::prelude::*;
 use reed_solomon_erasure::galois_8::ReedSolomon;
-use tokio::fs;
-use tracing::{info, instrument};
+use sha2::Sha256;
+use tracing::{error, info, instrument};
 
-#[instrument(skip(content))]
-pub fn encode_file(content: BytesMut) -> Result<Vec<BytesMut>> {
-    info!("encoding...");
-    let content_size = content.len();
-    let r = ReedSolomon::new(DATA_SHARDS, PARITY_SHARDS)?;
-    let shard_len = (content_size + DATA_SHARDS - 1) / DATA_SHARDS;
-    let mut shards: Vec<BytesMut> = (0..DATA_SHARDS)
+/// 1ストライプ分のデータをデータシャードとパリティシャードのチャンクにエンコードする。
+pub fn encode_stripe(r: &ReedSolomon, stripe: &[u8]) -> Result<Vec<BytesMut>> {
+    let data_len = stripe.len();
+    let data_shards = r.data_shard_count();
+    let chunk_len = chunk_len_for(data_len, data_shards);
+    let mut chunks: Vec<BytesMut> = (0..data_shards)
         .into_par_iter()
         .map(|i| {
-            let start = i * shard_len;
-            let end = std::cmp::min((i + 1) * shard_len, content_size);
-            let mut shard = BytesMut::from(&content[start..end]);
-            while shard.len() < shard_len {
-                // ライブラリの仕様上、shardの長さは全て等しくなければいけないのでゼロでパディングしている
-                shard.put_u8(0);
-            }
-            shard
+            let start = std::cmp::min(i * chunk_len, data_len);
+            let end = std::cmp::min((i + 1) * chunk_len, data_len);
+            let mut chunk = BytesMut::with_capacity(chunk_len);
+            chunk.extend_from_slice(&stripe[start..end]);
+            // ライブラリの仕様上、シャードの長さは全て等しくなければいけないのでゼロでパディングしている
+            chunk.resize(chunk_len, 0);
+            chunk
         })
         .collect();
 
-    for _ in 0..PARITY_SHARDS {
-        shards.push(BytesMut::zeroed(shard_len));
+    for _ in 0..r.parity_shard_count() {
+        chunks.push(BytesMut::zeroed(chunk_len));
     }
 
-    r.encode(&mut shards)?;
+    r.encode(&mut chunks)?;
+    Ok(chunks)
+}
+
+/// 入力をストライプ単位に区切りながらエンコードする。
+/// 保持するのは高々1ストライプ分のデータなので、オブジェクトのサイズに関わらずメモリ使用量は一定になる。
+pub struct StripeEncoder {
+    r: ReedSolomon,
+    buffer: BytesMut,
+    stripe_len: usize,
+    total_len: u64,
+    stripes: usize,
+}
+
+impl StripeEncoder {
+    pub fn new(profile: ErasureProfile) -> Result<Self> {
+        let stripe_len = profile.stripe_len();
+        Ok(Self {
+            r: profile.reed_solomon()?,
+            buffer: BytesMut::with_capacity(stripe_len),
+            stripe_len,
+            total_len: 0,
+            stripes: 0,
+        })
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+        self.total_len += data.len() as u64;
+    }
+
+    /// 1ストライプ分のデータが溜まっていればエンコードして返す。
+    pub fn next_stripe(&mut self) -> Result<Option<Vec<BytesMut>>> {
+        if self.buffer.len() < self.stripe_len {
+            return Ok(None);
+        }
+        let stripe = self.buffer.split_to(self.stripe_len);
+        self.stripes += 1;
+        encode_stripe(&self.r, &stripe).map(Some)
+    }
+
+    /// 残りのデータを最後のストライプとしてエンコードする。
+    pub fn finish(&mut self) -> Result<Option<Vec<BytesMut>>> {
+        if let Some(stripe) = self.next_stripe()? {
+            return Ok(Some(stripe));
+        }
+        // 0バイトのオブジェクトでも1ストライプは書き込む
+        if self.buffer.is_empty() && self.stripes > 0 {
+            return Ok(None);
+        }
+        let stripe = self.buffer.split();
+        self.stripes += 1;
+        encode_stripe(&self.r, &stripe).map(Some)
+    }
+
+    pub fn total_len(&self) -> u64 {
+        self.total_len
+    }
+}
+
+/// データ全体をメモリ上でエンコードする。
+/// 各シャードはストライプごとのチャンクを連結したもので、シャードファイルのペイロードと同じ並びになる。
+#[instrument(skip(content))]
+pub fn encode_file(content: BytesMut, profile: ErasureProfile) -> Result<Vec<BytesMut>> {
+    info!("encoding...");
+    let layout = profile.layout(content.len() as u64);
+    let mut shards: Vec<BytesMut> = (0..profile.total_shards())
+        .map(|_| BytesMut::with_capacity(layout.shard_len() as usize))
+        .collect();
+    let mut encoder = StripeEncoder::new(profile)?;
+    encoder.push(&content);
+    while let Some(stripe) = encoder.finish()? {
+        for (shard, chunk) in shards.iter_mut().zip(stripe) {
+            shard.put_slice(&chunk);
+        }
+    }
     info!("encoded!");
 
     Ok(shards)
 }
 
-#[instrument(skip(shards))]
-pub async fn save_shards(shards: &Vec<BytesMut>, object_id: &str) -> Result<()> {
-    info!("Starting save data...");
-    for i in 0..shards.len() {
-        let filepath = get_filepath(object_id, i).await;
-        fs::write(&filepath, &shards[i]).await?;
-        info!("Saved shard {} to {:?}", i, filepath);
+/// 書き込みが完了したオブジェクトの長さとダイジェスト(16進数)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncodedObject {
+    pub content_length: u64,
+    pub md5: String,
+    pub sha256: String,
+    pub crc32c: u32,
+}
+
+impl EncodedObject {
+    /// メモリ上のデータの長さとダイジェストを計算する。
+    pub fn of(data: &[u8]) -> Self {
+        Self {
+            content_length: data.len() as u64,
+            md5: hex::encode(Md5::digest(data)),
+            sha256: hex::encode(Sha256::digest(data)),
+            crc32c: crc32c::crc32c(data),
+        }
+    }
+}
+
+/// `ShardWriter::finish_with` の各段階。障害の注入に使う。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteStep {
+    /// 全てのストライプとヘッダを書き込み領域(ローカルでは一時ファイル)に書き終えた
+    Written,
+    /// 書き込み領域を永続化した
+    Synced,
+    /// シャード `n` をcommitした(ローカルでは最終的なパスにリネームした)
+    Renamed(usize),
+    /// commitしたシャードを永続化した(ローカルではディレクトリをfsyncした)
+    DirSynced,
+}
+
+struct PendingShard {
+    index: usize,
+    upload: Box<dyn ShardUpload>,
+    // ヘッダを含めて書き込んだバイト数
+    len: u64,
+}
+
+/// 受け取ったデータをストライプ単位でエンコードし、シャードの書き込み領域に追記していく。
+/// 同時に元データのMD5、SHA-256、CRC32Cを計算する。
+/// `finish` するまでシャードは読めるようにならないので、途中で失敗しても壊れたシャードは残らない。
+pub struct ShardWriter {
+    store: SharedShardStore,
+    object_id: String,
+    profile: ErasureProfile,
+    encoder: StripeEncoder,
+    files: Vec<PendingShard>,
+    // commitに成功しなければならないシャードの数
+    quorum: usize,
+    md5: Md5,
+    sha256: Sha256,
+    crc32c: u32,
+}
+
+impl ShardWriter {
+    pub async fn create(
+        store: &SharedShardStore,
+        object_id: &str,
+        profile: ErasureProfile,
+    ) -> Result<Self> {
+        let indices: Vec<usize> = (0..profile.total_shards()).collect();
+        let quorum = store.write_quorum(profile);
+        Self::open(store, object_id, profile, &indices, quorum).await
+    }
+
+    /// `indices` のシャードだけを書き込む。スクラブで壊れたシャードを作り直すときに使う。
+    /// 作り直す場合は全てのシャードのcommitに成功しなければならない。
+    pub async fn create_for_shards(
+        store: &SharedShardStore,
+        object_id: &str,
+        profile: ErasureProfile,
+        indices: &[usize],
+    ) -> Result<Self> {
+        Self::open(store, object_id, profile, indices, indices.len()).await
+    }
+
+    #[instrument(skip(store))]
+    async fn open(
+        store: &SharedShardStore,
+        object_id: &str,
+        profile: ErasureProfile,
+        indices: &[usize],
+        quorum: usize,
+    ) -> Result<Self> {
+        if let Some(max) = store.max_shards()
+            && profile.total_shards() > max
+        {
+            bail!(
+                "cannot place {} shards on {} distinct targets",
+                profile.total_shards(),
+                max
+            );
+        }
+        let mut writer = Self {
+            store: store.clone(),
+            object_id: object_id.to_string(),
+            profile,
+            encoder: StripeEncoder::new(profile)?,
+            files: Vec::with_capacity(indices.len()),
+            quorum,
+            md5: Md5::new(),
+            sha256: Sha256::new(),
+            crc32c: 0,
+        };
+        for &i in indices {
+            let upload = match store.create(object_id, i).await {
+                Ok(upload) => upload,
+                Err(e) => {
+                    writer.abort().await;
+                    return Err(e);
+                }
+            };
+            writer.files.push(PendingShard {
+                index: i,
+                upload,
+                len: 0,
+            });
+        }
+        // 元データの長さは書き終わるまで分からないので、ヘッダは最後に書き直す
+        if let Err(e) = writer.write_headers(0).await {
+            writer.abort().await;
+            return Err(e);
+        }
+        Ok(writer)
+    }
+
+    fn header(
+        object_id: &str,
+        profile: ErasureProfile,
+        shard_index: usize,
+        original_len: u64,
+    ) -> Result<BytesMut> {
+        let header = ShardHeader::new(
+            object_id,
+            shard_index,
+            profile.data_shards,
+            profile.parity_shards,
+            profile.stripe_unit,
+            original_len,
+        );
+        let mut buf = BytesMut::with_capacity(header.encoded_len());
+        header.write_to(&mut buf)?;
+        Ok(buf)
+    }
+
+    async fn write_headers(&mut self, original_len: u64) -> Result<()> {
+        let object_id = &self.object_id;
+        let profile = self.profile;
+        try_join_all(self.files.iter_mut().map(|shard| async move {
+            let header = Self::header(object_id, profile, shard.index, original_len)?;
+            shard.upload.write_at(0, &header).await?;
+            shard.len = shard.len.max(header.len() as u64);
+            anyhow::Ok(())
+        }))
+        .await?;
+        Ok(())
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.md5.update(data);
+        self.sha256.update(data);
+        self.crc32c = crc32c::crc32c_append(self.crc32c, data);
+        self.encoder.push(data);
+        while let Some(stripe) = self.encoder.next_stripe()? {
+            self.write_stripe(stripe).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_stripe(&mut self, stripe: Vec<BytesMut>) -> Result<()> {
+        try_join_all(self.files.iter_mut().map(|shard| {
+            let chunk = &stripe[shard.index];
+            async move {
+                let mut buf = BytesMut::with_capacity(chunk.len() + CHECKSUM_LEN);
+                write_chunk(chunk, &mut buf);
+                shard.upload.write_at(shard.len, &buf).await?;
+                shard.len += buf.len() as u64;
+                anyhow::Ok(())
+            }
+        }))
+        .await?;
+        Ok(())
+    }
+
+    /// これまでに受け取ったデータの長さとダイジェストを返す。
+    pub fn digests(&self) -> EncodedObject {
+        EncodedObject {
+            content_length: self.encoder.total_len(),
+            md5: hex::encode(self.md5.clone().finalize()),
+            sha256: hex::encode(self.sha256.clone().finalize()),
+            crc32c: self.crc32c,
+        }
+    }
+
+    /// 書きかけのシャードを捨てる。
+    #[instrument(skip(self), fields(object_id = %self.object_id))]
+    pub async fn abort(self) {
+        for shard in self.files {
+            shard.upload.abort().await;
+        }
+        info!("Aborted writing shards.");
+    }
+
+    /// 残りのデータを書き込んでヘッダを確定させ、シャードを読めるようにして元データの長さとダイジェストを返す。
+    pub async fn finish(self) -> Result<EncodedObject> {
+        self.finish_with(|_| Ok(())).await
+    }
+
+    /// `finish` と同じだが、各段階の後で `hook` を呼ぶ。`hook` がエラーを返すとその段階で失敗したものとして扱う。
+    /// 失敗した場合は書きかけのシャードとcommit済みのシャードを全て削除する。
+    #[instrument(skip(self, hook), fields(object_id = %self.object_id))]
+    pub async fn finish_with<F>(mut self, mut hook: F) -> Result<EncodedObject>
+    where
+        F: FnMut(WriteStep) -> Result<()>,
+    {
+        let digests = self.digests();
+        let store = self.store.clone();
+        let object_id = self.object_id.clone();
+        let mut committed = Vec::new();
+        match self.commit(&mut hook, &mut committed).await {
+            Ok(()) => Ok(EncodedObject {
+                content_length: self.encoder.total_len(),
+                ..digests
+            }),
+            Err(e) => {
+                error!("Failed to commit shards: {}", e);
+                for index in committed {
+                    if let Err(e) = store.delete(&object_id, index).await {
+                        error!("Failed to remove shard {}: {}", index, e);
+                    }
+                }
+                self.abort().await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn commit<F>(&mut self, hook: &mut F, committed: &mut Vec<usize>) -> Result<()>
+    where
+        F: FnMut(WriteStep) -> Result<()>,
+    {
+        while let Some(stripe) = self.encoder.finish()? {
+            self.write_stripe(stripe).await?;
+        }
+        self.write_headers(self.encoder.total_len()).await?;
+        hook(WriteStep::Written)?;
+
+        // 読めるようにする前に中身を永続化しておく
+        try_join_all(self.files.iter_mut().map(|shard| shard.upload.sync())).await?;
+        hook(WriteStep::Synced)?;
+
+        // 全てのシャードを並行してcommitし、書き込めた数がクォーラムに届かなければ失敗にする
+        let total = self.files.len();
+        let results = join_all(
+            std::mem::take(&mut self.files)
+                .into_iter()
+                .map(|shard| async move { (shard.index, shard.upload.commit().await) }),
+        )
+        .await;
+        for (index, result) in results {
+            match result {
+                Ok(()) => committed.push(index),
+                Err(e) => error!(
+                    "Failed to commit shard {} of {}: {}",
+                    index, self.object_id, e
+                ),
+            }
+        }
+        if committed.len() < self.quorum {
+            bail!(
+                "only {} of {} shards were written (quorum {})",
+                committed.len(),
+                total,
+                self.quorum
+            );
+        }
+        for &index in committed.iter() {
+            info!("Saved shard {} of {}", index, self.object_id);
+            hook(WriteStep::Renamed(index))?;
+        }
+
+        self.store.flush(&self.object_id, committed).await?;
+        hook(WriteStep::DirSynced)?;
+        Ok(())
+    }
+}
+
+/// 保存済みのシャードを全て削除する。メタデータの登録に失敗したときのロールバックに使う。
+#[instrument(skip(store))]
+pub async fn remove_shards(store: &SharedShardStore, object_id: &str, profile: ErasureProfile) {
+    for i in 0..profile.total_shards() {
+        if let Err(e) = store.delete(object_id, i).await {
+            error!("Failed to remove shard {} of {}: {}", i, object_id, e);
+        }
     }
-    Ok(())
 }
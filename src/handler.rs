@@ -1,5 +1,13 @@
 pub mod api;
 pub mod bucket;
+pub mod checksum;
+pub mod copy;
 pub mod delete;
 pub mod get;
+pub mod head;
+pub mod job;
+pub mod list;
+pub mod multipart;
 pub mod post;
+pub mod put;
+pub mod range;
@@ -1,16 +1,23 @@
+use anyhow::bail;
 use axum::{
-    extract::{Path, State},
+    Json,
+    extract::{Path, Query, State},
     http::StatusCode,
     response::IntoResponse,
 };
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
-use tracing::{info, instrument};
+use std::net::Ipv4Addr;
+use tracing::{error, info, instrument};
 use uuid::Uuid;
 
 use crate::{
     db::{Bucket, MetadataStore},
     handler::api::ApiResult,
+    job::{JobRegistry, JobStatus},
+    multipart,
+    profile::ErasureProfile,
+    store::SharedShardStore,
 };
 
 #[derive(Deserialize, Serialize)]
@@ -18,21 +25,122 @@ pub struct BucketListResponse {
     buckets: Vec<Bucket>,
 }
 
-#[instrument(skip(store))]
+/// バケット作成時に指定できるイレイジャーコーディングのパラメータ。省略した項目はデフォルトになる。
+#[derive(Debug, Default, Deserialize)]
+pub struct CreateBucketRequest {
+    data_shards: Option<usize>,
+    parity_shards: Option<usize>,
+    stripe_unit: Option<usize>,
+}
+
+impl CreateBucketRequest {
+    /// `max_shards` はシャードを別々に置ける置き場所の数。それより多いシャードは同じ置き場所に重なるので拒否する。
+    fn profile(&self, max_shards: Option<usize>) -> anyhow::Result<ErasureProfile> {
+        let default = ErasureProfile::default();
+        let profile = ErasureProfile::new(
+            self.data_shards.unwrap_or(default.data_shards),
+            self.parity_shards.unwrap_or(default.parity_shards),
+            self.stripe_unit.unwrap_or(default.stripe_unit),
+        )?;
+        if let Some(max) = max_shards
+            && profile.total_shards() > max
+        {
+            bail!(
+                "total shards ({}) must not exceed the number of placement targets ({})",
+                profile.total_shards(),
+                max
+            );
+        }
+        Ok(profile)
+    }
+}
+
+// S3と同じく、ホスト名の一部として使えない名前や紛らわしい名前は拒否する
+const BUCKET_NAME_MIN_LEN: usize = 3;
+const BUCKET_NAME_MAX_LEN: usize = 63;
+const RESERVED_BUCKET_PREFIXES: &[&str] = &["xn--", "sthree-", "amzn-s3-demo-"];
+const RESERVED_BUCKET_SUFFIXES: &[&str] = &["-s3alias", "--ol-s3", ".mrap", "--x-s3", "--table-s3"];
+
+/// S3のバケット名の規則に従っているか確認する。
+/// 3〜63文字の英小文字・数字・`.`・`-` で、先頭と末尾は英小文字か数字、`.` は連続せず、IPアドレスの形でないこと。
+pub fn validate_bucket_name(name: &str) -> anyhow::Result<()> {
+    if !(BUCKET_NAME_MIN_LEN..=BUCKET_NAME_MAX_LEN).contains(&name.len()) {
+        bail!(
+            "bucket name must be between {} and {} characters long",
+            BUCKET_NAME_MIN_LEN,
+            BUCKET_NAME_MAX_LEN
+        );
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !matches!(c, 'a'..='z' | '0'..='9' | '.' | '-'))
+    {
+        bail!("bucket name contains an invalid character: {:?}", c);
+    }
+    let alphanumeric = |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric());
+    if !alphanumeric(name.chars().next()) || !alphanumeric(name.chars().last()) {
+        bail!("bucket name must begin and end with a letter or number");
+    }
+    if name.contains("..") {
+        bail!("bucket name must not contain two adjacent periods");
+    }
+    if name.parse::<Ipv4Addr>().is_ok() {
+        bail!("bucket name must not be formatted as an IP address");
+    }
+    if let Some(prefix) = RESERVED_BUCKET_PREFIXES
+        .iter()
+        .find(|prefix| name.starts_with(*prefix))
+    {
+        bail!("bucket name must not start with {:?}", prefix);
+    }
+    if let Some(suffix) = RESERVED_BUCKET_SUFFIXES
+        .iter()
+        .find(|suffix| name.ends_with(*suffix))
+    {
+        bail!("bucket name must not end with {:?}", suffix);
+    }
+    Ok(())
+}
+
+#[instrument(skip(store, shards))]
 pub async fn create_bucket(
     Path(bucket_name): Path<String>,
     State(store): State<MetadataStore>,
+    State(shards): State<SharedShardStore>,
+    request: Option<Json<CreateBucketRequest>>,
 ) -> impl IntoResponse {
+    if let Err(e) = validate_bucket_name(&bucket_name) {
+        info!("Create bucket rejected: {}", e);
+        return ApiResult::Error(StatusCode::BAD_REQUEST, format!("InvalidBucketName: {}", e));
+    }
+    let profile = match request.unwrap_or_default().profile(shards.max_shards()) {
+        Ok(profile) => profile,
+        Err(e) => {
+            info!("Create bucket rejected: {}", e);
+            return ApiResult::Error(
+                StatusCode::BAD_REQUEST,
+                format!("InvalidErasureProfile: {}", e),
+            );
+        }
+    };
     let now = Utc::now().to_rfc3339();
     let id = Uuid::new_v4().to_string();
     let bucket = Bucket {
         id,
         bucket_name,
         created_at: now,
+        data_shards: Some(profile.data_shards as i64),
+        parity_shards: Some(profile.parity_shards as i64),
+        stripe_unit: Some(profile.stripe_unit as i64),
     };
     // すでにbucket_nameが存在している場合は作成しない
     let result = store
-        .create_bucket(&bucket.id, &bucket.bucket_name, &bucket.created_at)
+        .create_bucket(
+            &bucket.id,
+            &bucket.bucket_name,
+            &bucket.created_at,
+            &profile,
+        )
         .await;
     match result {
         Ok(r) => {
@@ -67,26 +175,89 @@ pub async fn list_buckets(State(store): State<MetadataStore>) -> impl IntoRespon
     }
 }
 
-#[instrument(skip(store))]
+#[derive(Debug, Deserialize)]
+pub struct DeleteBucketQuery {
+    #[serde(default)]
+    force: bool,
+}
+
+// 強制削除で1回に読み込むオブジェクトの数
+const DELETE_BATCH_SIZE: i64 = 100;
+// 削除中にオブジェクトが追加された場合に、バケットの削除をやり直す回数
+const DELETE_RETRIES: usize = 3;
+
+#[instrument(skip(store, shards, jobs))]
 pub async fn delete_bucket(
     Path(bucket_name): Path<String>,
+    Query(query): Query<DeleteBucketQuery>,
     State(store): State<MetadataStore>,
+    State(shards): State<SharedShardStore>,
+    State(jobs): State<JobRegistry>,
 ) -> impl IntoResponse {
+    let objects = match store.count_objects(&bucket_name).await {
+        Ok(count) => count,
+        Err(e) => {
+            info!("Failed to count objects in bucket '{}': {}", bucket_name, e);
+            return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+        }
+    };
+    // オブジェクトがなくても、進行中のマルチパートアップロードが残っていれば強制削除で中止する
+    let uploads = if objects == 0 && query.force {
+        match store.list_bucket_multipart_uploads(&bucket_name, 1).await {
+            Ok(uploads) => uploads.len() as i64,
+            Err(e) => {
+                info!("Failed to list uploads in bucket '{}': {}", bucket_name, e);
+                return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+            }
+        }
+    } else {
+        0
+    };
+    if objects > 0 || uploads > 0 {
+        if !query.force {
+            info!("Bucket '{}' is not empty.", bucket_name);
+            return bucket_not_empty(&bucket_name);
+        }
+        match exist_buckets(&bucket_name, &store).await {
+            Ok(true) => {}
+            Ok(false) => return bucket_not_found(&bucket_name),
+            Err(e) => return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+        }
+        // オブジェクトの削除には時間がかかるので、バックグラウンドで実行して進捗は /jobs/{id} で返す
+        let (job, started) = jobs.start(&bucket_name, objects as u64);
+        if started {
+            info!(
+                "Started force delete job {} for bucket '{}'.",
+                job.id, bucket_name
+            );
+            tokio::spawn(force_delete_bucket(
+                store,
+                shards,
+                jobs,
+                job.id.clone(),
+                bucket_name,
+            ));
+        }
+        return ApiResult::Success(StatusCode::ACCEPTED, BucketDeleteResponse::Job(job));
+    }
+
     let result = store.delete_bucket(&bucket_name).await;
 
     match result {
         Ok(r) => {
             if r.rows_affected() == 0 {
                 info!("Bucket '{}' NOT_FOUND", bucket_name);
-                ApiResult::Error(
-                    StatusCode::NOT_FOUND,
-                    format!("Bucket '{}' not found.", bucket_name),
-                )
+                bucket_not_found(&bucket_name)
             } else {
                 info!("Bucket '{}' deleted.", bucket_name);
-                ApiResult::Success(StatusCode::OK, format!("Bucket '{}' deleted.", bucket_name))
+                ApiResult::Success(
+                    StatusCode::OK,
+                    BucketDeleteResponse::Deleted(format!("Bucket '{}' deleted.", bucket_name)),
+                )
             }
         }
+        // 数えた後にオブジェクトが追加された
+        Err(e) if is_foreign_key_violation(&e) => bucket_not_empty(&bucket_name),
         Err(e) => {
             info!("Failed to delete bucket '{}': {}", bucket_name, e);
             ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
@@ -94,10 +265,107 @@ pub async fn delete_bucket(
     }
 }
 
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum BucketDeleteResponse {
+    Deleted(String),
+    Job(JobStatus),
+}
+
+fn bucket_not_found<T>(bucket_name: &str) -> ApiResult<T> {
+    ApiResult::Error(
+        StatusCode::NOT_FOUND,
+        format!("Bucket '{}' not found.", bucket_name),
+    )
+}
+
+fn bucket_not_empty<T>(bucket_name: &str) -> ApiResult<T> {
+    ApiResult::Error(
+        StatusCode::CONFLICT,
+        format!(
+            "BucketNotEmpty: Bucket '{}' is not empty. Delete all objects or use ?force=true.",
+            bucket_name
+        ),
+    )
+}
+
+pub fn is_foreign_key_violation(e: &sqlx::Error) -> bool {
+    e.as_database_error()
+        .is_some_and(|e| e.is_foreign_key_violation())
+}
+
+/// バケット内の全てのオブジェクトのメタデータとシャードを削除してから、バケットを削除する。
+#[instrument(skip(store, shards, jobs))]
+async fn force_delete_bucket(
+    store: MetadataStore,
+    shards: SharedShardStore,
+    jobs: JobRegistry,
+    job_id: String,
+    bucket_name: String,
+) {
+    let result = delete_bucket_contents(&store, &shards, &jobs, &job_id, &bucket_name).await;
+    match &result {
+        Ok(()) => info!("Bucket '{}' deleted.", bucket_name),
+        Err(e) => error!("Failed to force delete bucket '{}': {}", bucket_name, e),
+    }
+    jobs.finish(&job_id, result.err().map(|e| e.to_string()));
+}
+
+async fn delete_bucket_contents(
+    store: &MetadataStore,
+    shards: &SharedShardStore,
+    jobs: &JobRegistry,
+    job_id: &str,
+    bucket_name: &str,
+) -> anyhow::Result<()> {
+    let mut retries = 0;
+    loop {
+        let objects = store
+            .list_objects(bucket_name, "", "", DELETE_BATCH_SIZE)
+            .await?;
+        if objects.is_empty() {
+            // 進行中のマルチパートアップロードも中止する
+            loop {
+                let uploads = store
+                    .list_bucket_multipart_uploads(bucket_name, DELETE_BATCH_SIZE)
+                    .await?;
+                if uploads.is_empty() {
+                    break;
+                }
+                for upload in uploads {
+                    multipart::abort_upload(store, shards, &upload.upload_id, upload.profile())
+                        .await?;
+                }
+            }
+            match store.delete_bucket(bucket_name).await {
+                Ok(_) => return Ok(()),
+                Err(e) if is_foreign_key_violation(&e) && retries < DELETE_RETRIES => {
+                    retries += 1;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        for object in objects {
+            // 途中で止まってもシャードを失ったメタデータが残らないよう、メタデータを先に消す。
+            // 一覧を読んだ後に上書きされていれば、新しい世代のシャードは消さずに次の一覧で削除する
+            let deleted = store
+                .delete_metadata_generation(
+                    &object.bucket_name,
+                    &object.object_id,
+                    object.generation.as_deref(),
+                )
+                .await?;
+            if !deleted {
+                continue;
+            }
+            multipart::remove_object_data(store, shards, &object).await;
+            jobs.add_deleted(job_id, 1);
+        }
+    }
+}
+
 #[instrument(skip(store))]
 pub async fn exist_buckets(bucket_name: &str, store: &MetadataStore) -> Result<bool, sqlx::Error> {
-    store
-        .exist_buckets(bucket_name)
-        .await
-        .map(|i| if i == 1 { true } else { false })
+    store.exist_buckets(bucket_name).await.map(|i| i == 1)
 }
@@ -0,0 +1,114 @@
+use crate::encode::EncodedObject;
+use anyhow::{Result, anyhow, bail};
+use axum::http::HeaderMap;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use std::fmt;
+
+pub const CONTENT_MD5: &str = "content-md5";
+pub const CHECKSUM_CRC32C: &str = "x-amz-checksum-crc32c";
+pub const CHECKSUM_SHA256: &str = "x-amz-checksum-sha256";
+
+/// クライアントが送ってきたチェックサムと受け取ったデータが一致しないことを表すエラー。
+/// ハンドラはこれを `400 BadDigest` として返す。
+#[derive(Debug)]
+pub struct BadDigest(pub String);
+
+impl fmt::Display for BadDigest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BadDigest: {}", self.0)
+    }
+}
+
+impl std::error::Error for BadDigest {}
+
+/// アップロード時に検証するチェックサム。値はS3と同じくbase64で受け取る。
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExpectedChecksums {
+    pub md5: Option<Vec<u8>>,
+    pub crc32c: Option<u32>,
+    pub sha256: Option<Vec<u8>>,
+}
+
+impl ExpectedChecksums {
+    /// リクエストヘッダ(またはmultipartのパートのヘッダ)からチェックサムを取り出す。
+    pub fn from_headers(headers: &HeaderMap) -> Result<Self> {
+        let mut checksums = Self::default();
+        for name in [CONTENT_MD5, CHECKSUM_CRC32C, CHECKSUM_SHA256] {
+            if let Some(value) = headers.get(name) {
+                checksums.set(name, value.to_str()?)?;
+            }
+        }
+        Ok(checksums)
+    }
+
+    /// チェックサムを指定するフィールドの名前かどうか。大文字小文字を区別しない。
+    pub fn is_checksum(name: &str) -> bool {
+        [CONTENT_MD5, CHECKSUM_CRC32C, CHECKSUM_SHA256]
+            .iter()
+            .any(|n| n.eq_ignore_ascii_case(name))
+    }
+
+    /// `name` がチェックサムの名前なら値を設定して `true` を返す。名前は大文字小文字を区別しない。
+    pub fn set(&mut self, name: &str, value: &str) -> Result<bool> {
+        let decoded = || {
+            STANDARD
+                .decode(value.trim())
+                .map_err(|e| anyhow!("invalid {}: {}", name, e))
+        };
+        match name.to_ascii_lowercase().as_str() {
+            CONTENT_MD5 => self.md5 = Some(expect_len(name, decoded()?, 16)?),
+            CHECKSUM_SHA256 => self.sha256 = Some(expect_len(name, decoded()?, 32)?),
+            CHECKSUM_CRC32C => {
+                let bytes = expect_len(name, decoded()?, 4)?;
+                self.crc32c = Some(u32::from_be_bytes(bytes.try_into().unwrap()));
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// `other` で指定されたものを上書きする。
+    pub fn merge(&mut self, other: Self) {
+        self.md5 = other.md5.or(self.md5.take());
+        self.crc32c = other.crc32c.or(self.crc32c);
+        self.sha256 = other.sha256.or(self.sha256.take());
+    }
+
+    /// 受け取ったデータのダイジェストと照合する。
+    pub fn verify(&self, encoded: &EncodedObject) -> Result<(), BadDigest> {
+        if let Some(md5) = &self.md5
+            && hex::encode(md5) != encoded.md5
+        {
+            return Err(BadDigest(
+                "The Content-MD5 you specified did not match what was received.".to_string(),
+            ));
+        }
+        if let Some(crc32c) = self.crc32c
+            && crc32c != encoded.crc32c
+        {
+            return Err(BadDigest(
+                "The CRC32C you specified did not match what was received.".to_string(),
+            ));
+        }
+        if let Some(sha256) = &self.sha256
+            && hex::encode(sha256) != encoded.sha256
+        {
+            return Err(BadDigest(
+                "The SHA256 you specified did not match what was received.".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn expect_len(name: &str, bytes: Vec<u8>, len: usize) -> Result<Vec<u8>> {
+    if bytes.len() != len {
+        bail!(
+            "invalid {}: expected {} bytes, got {}",
+            name,
+            len,
+            bytes.len()
+        );
+    }
+    Ok(bytes)
+}
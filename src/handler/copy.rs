@@ -0,0 +1,253 @@
+use super::api::ApiResult;
+use super::checksum::ExpectedChecksums;
+use super::post::ObjectUpload;
+use super::put::{file_name_from_disposition, percent_decode};
+use crate::{
+    db::{LinkObject, MetadataStore, NewObjectMetadata, ObjectMetadata},
+    multipart,
+    server::ServerConfig,
+    store::SharedShardStore,
+};
+use axum::http::{HeaderMap, StatusCode, header};
+use futures::TryStreamExt;
+use serde::Serialize;
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+// S3と同じく、コピー元は `PUT` のヘッダで `/{bucket_name}/{object_id}` (URLエンコード)の形で指定する。
+// コピー元のイレイジャーコーディングのパラメータがコピー先のバケットと同じなら、シャードを書き直さずに
+// 同じ内部IDを参照するメタデータを作る。シャードは参照しているメタデータがなくなったときに削除する。
+pub const COPY_SOURCE: &str = "x-amz-copy-source";
+pub const RENAME_SOURCE: &str = "x-amz-rename-source";
+// `REPLACE` を指定すると、コピー元ではなくリクエストの Content-Type などをコピー先に記録する
+pub const METADATA_DIRECTIVE: &str = "x-amz-metadata-directive";
+
+#[derive(Serialize)]
+pub struct CopyResponse {
+    bucket_name: String,
+    object_id: String,
+    etag: String,
+    // シャードを書き直さずにコピー元と共有したかどうか
+    linked: bool,
+}
+
+/// `x-amz-copy-source` の値からバケット名とキーを取り出す。先頭の `/` は省略できる。
+/// バージョン管理はしていないので、`?versionId=` が付いたものは受け付けない。
+pub fn parse_copy_source(value: &str) -> Option<(String, String)> {
+    if value.contains('?') {
+        return None;
+    }
+    let decoded = percent_decode(value.strip_prefix('/').unwrap_or(value))?;
+    let (bucket_name, object_id) = decoded.split_once('/')?;
+    if bucket_name.is_empty() || object_id.is_empty() {
+        return None;
+    }
+    Some((bucket_name.to_string(), object_id.to_string()))
+}
+
+/// `headers` の `source_header` で指定されたオブジェクトを `bucket_name`/`object_id` にコピーする。
+/// `rename` が `true` ならコピーした後にコピー元を削除する。
+#[instrument(skip(store, shards, config, headers))]
+pub async fn copy_object(
+    store: &MetadataStore,
+    shards: &SharedShardStore,
+    config: &ServerConfig,
+    (bucket_name, object_id): (String, String),
+    headers: &HeaderMap,
+    rename: bool,
+) -> ApiResult<CopyResponse> {
+    let source_header = if rename { RENAME_SOURCE } else { COPY_SOURCE };
+    let Some((source_bucket, source_key)) = headers
+        .get(source_header)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_copy_source)
+    else {
+        info!("Copy rejected: invalid {}", source_header);
+        return ApiResult::Error(
+            StatusCode::BAD_REQUEST,
+            format!("InvalidArgument: {} must be /bucket/key.", source_header),
+        );
+    };
+    if source_bucket == bucket_name && source_key == object_id {
+        return ApiResult::Error(
+            StatusCode::BAD_REQUEST,
+            "InvalidRequest: source and destination must be different objects.".to_string(),
+        );
+    }
+    let source = match store.get_metadata(&source_bucket, &source_key).await {
+        Ok(Some(source)) => source,
+        Ok(None) => {
+            info!("Copy source '{}/{}' not found.", source_bucket, source_key);
+            return ApiResult::Error(
+                StatusCode::NOT_FOUND,
+                format!(
+                    "NoSuchKey: source object '{}/{}' does not exist.",
+                    source_bucket, source_key
+                ),
+            );
+        }
+        Err(e) => return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+    let mut upload = match ObjectUpload::begin(store, bucket_name, object_id, headers).await {
+        Ok(upload) => upload,
+        Err(e) => return e,
+    };
+    // コピー元のシャードを読み直すので、リクエストのチェックサムは使わない
+    upload.expected = ExpectedChecksums::default();
+    let replace = !rename
+        && headers
+            .get(METADATA_DIRECTIVE)
+            .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"REPLACE"));
+    if replace {
+        // x-amz-meta-* は `begin` でリクエストヘッダから読み取っている
+        let header_str = |name| headers.get(name).and_then(|v| v.to_str().ok());
+        upload.content_type = header_str(header::CONTENT_TYPE).map(str::to_string);
+        upload.file_name =
+            header_str(header::CONTENT_DISPOSITION).and_then(file_name_from_disposition);
+    } else {
+        upload.content_type = source.content_type.clone();
+        upload.file_name = source.file_name.clone();
+        upload.user_metadata = source.user_metadata.clone();
+    }
+
+    // パックファイルに置いた小さなオブジェクトは書き直す(位置はキーごとに記録しているため)
+    let profile = source.profile();
+    let linkable = !source.packed
+        && (profile == upload.bucket_profile || profile == upload.bucket_profile.replicated());
+    if linkable {
+        link(store, shards, &source, upload, rename).await
+    } else {
+        rewrite(store, shards, config, &source, upload, rename).await
+    }
+}
+
+/// コピー元のシャードを共有するメタデータを作る。
+async fn link(
+    store: &MetadataStore,
+    shards: &SharedShardStore,
+    source: &ObjectMetadata,
+    upload: ObjectUpload,
+    rename: bool,
+) -> ApiResult<CopyResponse> {
+    let generation = Uuid::new_v4().simple().to_string();
+    let blob_id = source.shard_id();
+    let metadata = NewObjectMetadata {
+        file_name: upload.file_name.as_deref(),
+        content_type: upload.content_type.as_deref(),
+        content_length: source.content_length.unwrap_or(0),
+        user_metadata: upload.user_metadata.as_deref(),
+        md5: source.md5.as_deref(),
+        sha256: source.sha256.as_deref(),
+        generation: Some(&generation),
+        blob_id: Some(&blob_id),
+        profile: source.profile(),
+        packed: false,
+        part_count: source.part_count,
+    };
+    let target = (upload.bucket_name.as_str(), upload.object_id.as_str());
+    match store
+        .link_object(source, target, &metadata, upload.if_none_match, rename)
+        .await
+    {
+        Ok(LinkObject::Linked(previous)) => {
+            info!("Linked {} to '{}/{}'.", blob_id, target.0, target.1);
+            if let Some(previous) = previous {
+                info!(
+                    "Replaced object. Removing previous generation {:?}",
+                    previous.generation
+                );
+                multipart::remove_object_data(store, shards, &previous).await;
+            }
+        }
+        Ok(LinkObject::SourceChanged) => return source_changed(source),
+        Ok(LinkObject::AlreadyExists) => return already_exists(&upload.object_id),
+        Err(e) => {
+            error!("Copy failed: database error: {}", e);
+            return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+        }
+    }
+    ApiResult::Success(
+        StatusCode::OK,
+        CopyResponse {
+            bucket_name: upload.bucket_name,
+            object_id: upload.object_id,
+            etag: format!("\"{}\"", source.md5.as_deref().unwrap_or_default()),
+            linked: true,
+        },
+    )
+}
+
+/// コピー元を読み出し、コピー先のバケットのパラメータでエンコードし直して保存する。
+async fn rewrite(
+    store: &MetadataStore,
+    shards: &SharedShardStore,
+    config: &ServerConfig,
+    source: &ObjectMetadata,
+    upload: ObjectUpload,
+    rename: bool,
+) -> ApiResult<CopyResponse> {
+    let content_length = source.content_length.unwrap_or(0) as u64;
+    let reader = match multipart::open_object(store, shards, source, content_length, false).await {
+        Ok(reader) => reader,
+        Err(e) => {
+            error!("Copy failed: cannot read source: {}", e);
+            return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+        }
+    };
+    let body = Box::pin(
+        reader
+            .into_stream(0..content_length)
+            .map_err(std::io::Error::other),
+    );
+    let (bucket_name, object_id) = (upload.bucket_name.clone(), upload.object_id.clone());
+    let saved = match upload.save(store, shards, config, body).await {
+        ApiResult::Success(_, saved) => saved,
+        ApiResult::Error(status, message) => return ApiResult::Error(status, message),
+    };
+    if rename {
+        match store
+            .delete_metadata_generation(
+                &source.bucket_name,
+                &source.object_id,
+                source.generation.as_deref(),
+            )
+            .await
+        {
+            Ok(true) => multipart::remove_object_data(store, shards, source).await,
+            // コピーしている間に上書きされた。新しいオブジェクトは残す
+            Ok(false) => info!("Source was replaced during rename. Keeping it."),
+            Err(e) => {
+                error!("Rename failed: cannot delete source: {}", e);
+                return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+            }
+        }
+    }
+    ApiResult::Success(
+        StatusCode::OK,
+        CopyResponse {
+            bucket_name,
+            object_id,
+            etag: saved.etag,
+            linked: false,
+        },
+    )
+}
+
+fn source_changed<T>(source: &ObjectMetadata) -> ApiResult<T> {
+    info!("Copy source was replaced or deleted during copy.");
+    ApiResult::Error(
+        StatusCode::CONFLICT,
+        format!(
+            "OperationAborted: source object '{}/{}' was modified during the copy.",
+            source.bucket_name, source.object_id
+        ),
+    )
+}
+
+fn already_exists<T>(object_id: &str) -> ApiResult<T> {
+    info!("Copy rejected: object '{}' already exists.", object_id);
+    ApiResult::Error(
+        StatusCode::PRECONDITION_FAILED,
+        format!("PreconditionFailed: object '{}' already exists.", object_id),
+    )
+}
@@ -1,6 +1,7 @@
 use super::api::ApiResult;
 use crate::db::MetadataStore;
-use crate::{env, get_filepath};
+use crate::multipart;
+use crate::store::SharedShardStore;
 use axum::{
     extract::{Path, State},
     http::StatusCode,
@@ -14,10 +15,11 @@ struct DeleteResponse {
     success: bool,
 }
 
-#[instrument(skip(store))]
+#[instrument(skip(store, shards))]
 pub async fn delete_object(
     Path((bucket_name, object_id)): Path<(String, String)>,
     State(store): State<MetadataStore>,
+    State(shards): State<SharedShardStore>,
 ) -> impl IntoResponse {
     let metadata = match store.get_metadata(&bucket_name, &object_id).await {
         Ok(Some(data)) => data,
@@ -33,24 +35,36 @@ pub async fn delete_object(
             return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
         }
     };
-    for i in 0..(env::DATA_SHARDS + env::PARITY_SHARDS) {
-        let filepath = get_filepath(&metadata.object_id, i).await;
-        if let Some(e) = tokio::fs::remove_file(&filepath).await.err() {
-            // ここちょっと怪しい
-            error!("DELETE request failed: {}", e);
-            return ApiResult::Error(StatusCode::NOT_FOUND, e.to_string());
-        }
-    }
-
+    // シャードはメタデータを消した後に削除する。コピー・リネームした他のオブジェクトが参照していれば残し、
+    // パックファイルに置いたシャードはコンパクションで回収する
+    // 読んだ世代の行だけを消すので、その間に上書きされた新しいオブジェクトのシャードを消すことはない
     match store
-        .delete_metadata(&metadata.bucket_name, &metadata.object_id)
+        .delete_metadata_generation(
+            &metadata.bucket_name,
+            &metadata.object_id,
+            metadata.generation.as_deref(),
+        )
         .await
     {
-        Ok(_) => {
+        Ok(true) => {
             info!("metadata '{}' deleted.", metadata.object_id);
+            multipart::remove_object_data(&store, &shards, &metadata).await;
             info!("Delete data successfully!");
             ApiResult::Success(StatusCode::OK, DeleteResponse { success: true })
         }
+        Ok(false) => {
+            info!(
+                "Object '{}' was replaced during delete.",
+                metadata.object_id
+            );
+            ApiResult::Error(
+                StatusCode::CONFLICT,
+                format!(
+                    "OperationAborted: object '{}' was modified during the delete.",
+                    metadata.object_id
+                ),
+            )
+        }
         Err(e) => {
             info!("Failed to delete metadata '{}': {}", metadata.object_id, e);
             ApiResult::Error(StatusCode::NOT_FOUND, e.to_string())
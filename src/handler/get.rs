@@ -1,38 +1,26 @@
-use crate::{db::MetadataStore, decode};
+use super::head::object_headers;
+use super::range::{self, RangeRequest};
+use crate::{
+    db::MetadataStore,
+    multipart::{self, ObjectReader},
+    server::ServerConfig,
+    store::SharedShardStore,
+};
 use axum::{
     body::Body,
     extract::{Path, State},
-    http::{StatusCode, header},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
     response::IntoResponse,
 };
-use bytes::BytesMut;
-use std::pin::Pin;
-use std::task::{Context as stdContext, Poll};
-use tokio::io::{self, AsyncRead, ReadBuf};
-use tokio_util::io::ReaderStream;
 use tracing::{error, info, instrument};
 
-struct MyBytesMut(pub BytesMut);
-
-impl AsyncRead for MyBytesMut {
-    fn poll_read(
-        mut self: Pin<&mut Self>,
-        _cx: &mut stdContext<'_>,
-        buf: &mut ReadBuf<'_>,
-    ) -> Poll<io::Result<()>> {
-        let amt = std::cmp::min(self.0.len(), buf.remaining());
-        if amt > 0 {
-            let data = self.0.split_to(amt);
-            buf.put_slice(&data);
-        }
-        Poll::Ready(Ok(()))
-    }
-}
-
-#[instrument(skip(store))]
+#[instrument(skip(store, shards, config))]
 pub async fn get_object(
     Path((bucket_name, object_id)): Path<(String, String)>,
     State(store): State<MetadataStore>,
+    State(shards): State<SharedShardStore>,
+    State(config): State<ServerConfig>,
+    request_headers: HeaderMap,
 ) -> impl IntoResponse {
     info!("Handling GET request for object.");
     let metadata = match store.get_metadata(&bucket_name, &object_id).await {
@@ -46,33 +34,64 @@ pub async fn get_object(
             return StatusCode::INTERNAL_SERVER_ERROR.into_response();
         }
     };
-    let file_name = metadata.file_name.unwrap();
-    let file_path = std::path::PathBuf::from(&file_name);
-    let content_type = mime_guess::from_path(&file_path).first_or_octet_stream();
-    match decode::load_shards(&metadata.object_id).await {
-        Ok(mut shards) => match decode::decode_shards(&mut shards).await {
-            Ok(data) => {
-                let reader = ReaderStream::new(MyBytesMut(data));
-                let body = Body::from_stream(reader);
-                let headers = [
-                    (header::CONTENT_TYPE, content_type.to_string()),
-                    (
-                        header::CONTENT_DISPOSITION,
-                        format!("attachment; filename=\"{}\"", file_name),
-                    ),
-                ];
+    let Some(content_length) = metadata.content_length else {
+        error!("content_length is missing in metadata.");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let content_length = content_length as u64;
+    let range_request = range::parse_range(
+        request_headers
+            .get(header::RANGE)
+            .and_then(|v| v.to_str().ok()),
+        content_length,
+    );
+    if range_request == RangeRequest::Unsatisfiable {
+        info!("Range not satisfiable.");
+        return range::unsatisfiable_response(content_length);
+    }
 
-                info!("Load and decode success!");
-                (StatusCode::OK, headers, body).into_response()
-            }
-            Err(e) => {
-                error!("GET request failed: decode error: {}", e);
-                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    let opened = multipart::open_object(
+        &store,
+        &shards,
+        &metadata,
+        content_length,
+        config.read_repair,
+    )
+    .await;
+    match opened {
+        Ok(reader) => {
+            let mut headers = object_headers(&metadata);
+
+            info!("Load shards success!");
+            match range_request {
+                RangeRequest::Partial(ranges) => {
+                    let content_type = headers
+                        .get(header::CONTENT_TYPE)
+                        .and_then(|v| v.to_str().ok())
+                        .unwrap_or("application/octet-stream")
+                        .to_string();
+                    range::partial_response(reader, ranges, content_length, &content_type, headers)
+                }
+                _ => {
+                    // ストライプごとに復元しながら送るので、オブジェクト全体をメモリに載せない
+                    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(content_length));
+                    let body = match (reader, metadata.sha256) {
+                        // 復元したデータがダイジェストと一致しない場合はボディを途中で打ち切る
+                        (ObjectReader::Shards(reader), Some(sha256)) => {
+                            Body::from_stream(reader.into_verified_stream(sha256))
+                        }
+                        (ObjectReader::Parts(reader), _) => {
+                            Body::from_stream(reader.into_verified_stream())
+                        }
+                        (reader, _) => Body::from_stream(reader.into_stream(0..content_length)),
+                    };
+                    (StatusCode::OK, headers, body).into_response()
+                }
             }
-        },
+        }
         Err(e) => {
-            info!("GET request failed: load error: {}", e);
-            StatusCode::NOT_FOUND.into_response()
+            error!("GET request failed: load error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
         }
     }
 }
@@ -0,0 +1,144 @@
+use crate::db::{MetadataStore, ObjectMetadata};
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header},
+    response::IntoResponse,
+};
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use tracing::{error, info, instrument};
+
+pub const USER_METADATA_PREFIX: &str = "x-amz-meta-";
+
+/// メタデータだけからオブジェクトのレスポンスヘッダを作る。GETとHEADで共通。
+pub fn object_headers(metadata: &ObjectMetadata) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    // アップロード時に指定されたContent-Typeを返す。指定がなければファイル名から推測する
+    let guessed = match &metadata.file_name {
+        Some(file_name) => mime_guess::from_path(file_name).first_or_octet_stream(),
+        None => mime_guess::mime::APPLICATION_OCTET_STREAM,
+    };
+    let content_type = metadata
+        .content_type
+        .as_deref()
+        .and_then(|ctype| HeaderValue::from_str(ctype).ok())
+        .or_else(|| HeaderValue::from_str(guessed.as_ref()).ok());
+    if let Some(value) = content_type {
+        headers.insert(header::CONTENT_TYPE, value);
+    }
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if let Some(Ok(value)) = metadata
+        .file_name
+        .as_deref()
+        .map(|name| HeaderValue::from_str(&content_disposition(name)))
+    {
+        headers.insert(header::CONTENT_DISPOSITION, value);
+    }
+    if let Ok(created_at) = DateTime::parse_from_rfc3339(&metadata.created_at) {
+        let last_modified = created_at
+            .with_timezone(&Utc)
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+        headers.insert(
+            header::LAST_MODIFIED,
+            HeaderValue::from_str(&last_modified).unwrap(),
+        );
+    }
+    headers.insert(
+        header::ETAG,
+        HeaderValue::from_str(&etag(metadata)).unwrap(),
+    );
+    for (key, value) in user_metadata(metadata) {
+        let name = HeaderName::try_from(format!("{}{}", USER_METADATA_PREFIX, key));
+        if let (Ok(name), Ok(value)) = (name, HeaderValue::from_str(&value)) {
+            headers.insert(name, value);
+        }
+    }
+    headers
+}
+
+/// ファイル名から `Content-Disposition` の値を作る。RFC 6266 と同じく、`filename*=` にUTF-8をパーセントエンコードした
+/// ファイル名を入れ、古いクライアント向けの `filename=` には引用符やASCII以外の文字を `_` に置き換えたものを入れる。
+pub fn content_disposition(file_name: &str) -> String {
+    let fallback: String = file_name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if !matches!(c, '"' | '\\' | ';' | '%') => c,
+            _ => '_',
+        })
+        .collect();
+    let mut encoded = String::new();
+    for &b in file_name.as_bytes() {
+        // RFC 5987 の attr-char はそのまま、それ以外は `%XX` にする
+        if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+/// S3と同じく元データのMD5をETagにする。ダイジェストを持たない古いオブジェクトはIDと長さから作る。
+pub fn etag(metadata: &ObjectMetadata) -> String {
+    match &metadata.md5 {
+        Some(md5) => format!("\"{}\"", md5),
+        None => format!(
+            "\"{:x}-{:x}\"",
+            metadata.id,
+            metadata.content_length.unwrap_or_default()
+        ),
+    }
+}
+
+fn user_metadata(metadata: &ObjectMetadata) -> BTreeMap<String, String> {
+    metadata
+        .user_metadata
+        .as_deref()
+        .and_then(|json| serde_json::from_str(json).ok())
+        .unwrap_or_default()
+}
+
+/// リクエストヘッダから `x-amz-meta-*` を取り出してJSONにする。
+pub fn user_metadata_from_headers(headers: &HeaderMap) -> Option<String> {
+    let metadata: BTreeMap<&str, &str> = headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let key = name.as_str().strip_prefix(USER_METADATA_PREFIX)?;
+            Some((key, value.to_str().ok()?))
+        })
+        .collect();
+    if metadata.is_empty() {
+        None
+    } else {
+        serde_json::to_string(&metadata).ok()
+    }
+}
+
+#[instrument(skip(store))]
+pub async fn head_object(
+    Path((bucket_name, object_id)): Path<(String, String)>,
+    State(store): State<MetadataStore>,
+) -> impl IntoResponse {
+    info!("Handling HEAD request for object.");
+    // シャードには一切触れず、メタデータだけで応答する
+    let metadata = match store.get_metadata(&bucket_name, &object_id).await {
+        Ok(Some(data)) => data,
+        Ok(None) => {
+            info!("data not found.");
+            return StatusCode::NOT_FOUND.into_response();
+        }
+        Err(e) => {
+            error!("database error: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let mut headers = object_headers(&metadata);
+    if let Some(content_length) = metadata.content_length {
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(content_length));
+    }
+    (StatusCode::OK, headers).into_response()
+}
@@ -0,0 +1,25 @@
+use super::api::ApiResult;
+use crate::job::JobRegistry;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use tracing::{info, instrument};
+
+#[instrument(skip(jobs))]
+pub async fn get_job(
+    Path(job_id): Path<String>,
+    State(jobs): State<JobRegistry>,
+) -> impl IntoResponse {
+    match jobs.get(&job_id) {
+        Some(job) => ApiResult::Success(StatusCode::OK, job),
+        None => {
+            info!("Job '{}' not found.", job_id);
+            ApiResult::Error(
+                StatusCode::NOT_FOUND,
+                format!("Job '{}' not found.", job_id),
+            )
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use super::api::ApiResult;
+use crate::{
+    db::{MetadataStore, ObjectMetadata},
+    handler::bucket::exist_buckets,
+};
+use anyhow::{Result, bail};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use tracing::{error, info, instrument};
+
+const DEFAULT_MAX_KEYS: usize = 1000;
+// 1回のクエリで読み込む行数
+const BATCH_SIZE: i64 = 1000;
+
+#[derive(Debug, Deserialize)]
+pub struct ListObjectsQuery {
+    #[serde(default)]
+    prefix: String,
+    delimiter: Option<String>,
+    #[serde(rename = "max-keys")]
+    max_keys: Option<usize>,
+    #[serde(rename = "continuation-token")]
+    continuation_token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ObjectEntry {
+    key: String,
+    size: Option<i64>,
+    content_type: Option<String>,
+    last_modified: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ListObjectsResponse {
+    bucket_name: String,
+    prefix: String,
+    delimiter: Option<String>,
+    max_keys: usize,
+    is_truncated: bool,
+    next_continuation_token: Option<String>,
+    contents: Vec<ObjectEntry>,
+    common_prefixes: Vec<String>,
+}
+
+enum Entry {
+    Object(Box<ObjectMetadata>),
+    CommonPrefix(String),
+}
+
+impl Entry {
+    /// 次のページをどこから始めるか。共通プレフィックスの場合はそのプレフィックスで始まるキーを全て飛ばす
+    fn cursor(&self) -> String {
+        match self {
+            Entry::Object(metadata) => metadata.object_id.clone(),
+            Entry::CommonPrefix(prefix) => format!("{}{}", prefix, char::MAX),
+        }
+    }
+}
+
+// 継続トークンは次のページの開始位置を16進数にしたもの
+fn encode_token(cursor: &str) -> String {
+    cursor.bytes().fold(String::new(), |mut token, b| {
+        let _ = write!(token, "{:02x}", b);
+        token
+    })
+}
+
+fn decode_token(token: &str) -> Result<String> {
+    if !token.len().is_multiple_of(2) || !token.is_ascii() {
+        bail!("invalid continuation token");
+    }
+    let bytes = (0..token.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&token[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()?;
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// `start_after` の後ろから最大 `max_keys + 1` 件のエントリを集める。
+/// 1件多く集めることで、続きがあるかどうかを判定する。
+async fn collect_entries(
+    store: &MetadataStore,
+    bucket_name: &str,
+    prefix: &str,
+    delimiter: Option<&str>,
+    start_after: &str,
+    max_keys: usize,
+) -> Result<Vec<Entry>> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut cursor = start_after.to_string();
+    'query: loop {
+        let rows = store
+            .list_objects(bucket_name, prefix, &cursor, BATCH_SIZE)
+            .await?;
+        let exhausted = (rows.len() as i64) < BATCH_SIZE;
+        for row in rows {
+            cursor = row.object_id.clone();
+            if let Some(Entry::CommonPrefix(last)) = entries.last()
+                && row.object_id.starts_with(last.as_str())
+            {
+                continue;
+            }
+            let folded = delimiter.and_then(|d| {
+                let rest = &row.object_id[prefix.len()..];
+                rest.find(d)
+                    .map(|i| format!("{}{}", prefix, &rest[..i + d.len()]))
+            });
+            match folded {
+                Some(common_prefix) => {
+                    let entry = Entry::CommonPrefix(common_prefix);
+                    // 同じプレフィックスを持つキーは読み飛ばして次のクエリを投げる
+                    cursor = entry.cursor();
+                    entries.push(entry);
+                    if entries.len() > max_keys {
+                        break 'query;
+                    }
+                    continue 'query;
+                }
+                None => entries.push(Entry::Object(Box::new(row))),
+            }
+            if entries.len() > max_keys {
+                break 'query;
+            }
+        }
+        if exhausted {
+            break;
+        }
+    }
+    Ok(entries)
+}
+
+#[instrument(skip(store))]
+pub async fn list_objects(
+    Path(bucket_name): Path<String>,
+    Query(query): Query<ListObjectsQuery>,
+    State(store): State<MetadataStore>,
+) -> impl IntoResponse {
+    info!("Handling list objects request.");
+    match exist_buckets(&bucket_name, &store).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return ApiResult::Error(
+                StatusCode::NOT_FOUND,
+                format!("Bucket '{}' not found.", bucket_name),
+            );
+        }
+        Err(e) => return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+
+    let start_after = match query.continuation_token.as_deref().map(decode_token) {
+        Some(Ok(cursor)) => cursor,
+        Some(Err(e)) => {
+            info!("Invalid continuation token: {}", e);
+            return ApiResult::Error(
+                StatusCode::BAD_REQUEST,
+                "invalid continuation token".to_string(),
+            );
+        }
+        None => String::new(),
+    };
+    let max_keys = query
+        .max_keys
+        .unwrap_or(DEFAULT_MAX_KEYS)
+        .min(DEFAULT_MAX_KEYS);
+    let delimiter = query.delimiter.filter(|d| !d.is_empty());
+
+    // S3と同じく、`max-keys=0` は空の一覧を続きなしで返す。続きありにすると同じトークンを返し続けてしまう
+    let mut entries = if max_keys == 0 {
+        Vec::new()
+    } else {
+        match collect_entries(
+            &store,
+            &bucket_name,
+            &query.prefix,
+            delimiter.as_deref(),
+            &start_after,
+            max_keys,
+        )
+        .await
+        {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("List objects failed: {}", e);
+                return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+            }
+        }
+    };
+
+    let is_truncated = entries.len() > max_keys;
+    entries.truncate(max_keys);
+    let next_continuation_token = is_truncated
+        .then(|| encode_token(&entries.last().map(Entry::cursor).unwrap_or(start_after)));
+
+    let mut contents = Vec::new();
+    let mut common_prefixes = Vec::new();
+    for entry in entries {
+        match entry {
+            Entry::Object(metadata) => contents.push(ObjectEntry {
+                key: metadata.object_id,
+                size: metadata.content_length,
+                content_type: metadata.content_type,
+                last_modified: metadata.created_at,
+            }),
+            Entry::CommonPrefix(prefix) => common_prefixes.push(prefix),
+        }
+    }
+
+    ApiResult::Success(
+        StatusCode::OK,
+        ListObjectsResponse {
+            bucket_name,
+            prefix: query.prefix,
+            delimiter,
+            max_keys,
+            is_truncated,
+            next_continuation_token,
+            contents,
+            common_prefixes,
+        },
+    )
+}
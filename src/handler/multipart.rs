@@ -0,0 +1,562 @@
+use super::api::ApiResult;
+use super::bucket::is_foreign_key_violation;
+use super::checksum::{BadDigest, ExpectedChecksums};
+use super::copy::{COPY_SOURCE, copy_object};
+use super::delete::delete_object;
+use super::get::get_object;
+use super::head::user_metadata_from_headers;
+use super::post::{invalid_digest, post_object, store_data};
+use super::put::{file_name_from_disposition, put_object};
+use crate::{
+    db::{
+        CompleteUpload, MetadataStore, MultipartUpload, NewObjectMetadata, ObjectPart, UploadedPart,
+    },
+    encode::remove_shards,
+    multipart::{self, MAX_PART_NUMBER},
+    new_blob_id,
+    server::AppState,
+    store::SharedShardStore,
+};
+use axum::{
+    body::{Body, to_bytes},
+    extract::{Path, Query, Request, State},
+    handler::Handler,
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+// S3と同じく、オブジェクトのURLにクエリを付けてマルチパートアップロードやリネームを操作する。
+//   POST   ?uploads                    アップロードを始めてIDを返す
+//   PUT    ?partNumber=N&uploadId=ID   パートNをアップロードする(同じ番号は置き換える)
+//   GET    ?uploadId=ID                アップロード済みのパートを返す
+//   POST   ?uploadId=ID                パートを繋げてオブジェクトにする
+//   DELETE ?uploadId=ID                アップロードを中止する
+//   PUT    ?renameObject               x-amz-rename-source のオブジェクトをリネームする
+// x-amz-copy-source が付いたPUTはオブジェクトのコピーとして扱い(handler/copy.rs)、
+// それ以外のリクエストは通常のオブジェクトのハンドラに渡す。
+
+// 完了リクエストのボディの上限
+const COMPLETE_BODY_LIMIT: usize = 1024 * 1024;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ObjectQuery {
+    uploads: Option<String>,
+    #[serde(rename = "uploadId")]
+    upload_id: Option<String>,
+    #[serde(rename = "partNumber")]
+    part_number: Option<i64>,
+    #[serde(rename = "renameObject")]
+    rename_object: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct InitiateResponse {
+    bucket_name: String,
+    object_id: String,
+    upload_id: String,
+}
+
+#[derive(Serialize)]
+pub struct PartResponse {
+    part_number: i64,
+    etag: String,
+    size: i64,
+}
+
+#[derive(Serialize)]
+pub struct ListPartsResponse {
+    bucket_name: String,
+    object_id: String,
+    upload_id: String,
+    parts: Vec<PartResponse>,
+}
+
+/// 完了リクエストのボディ。オブジェクトにするパートを番号の昇順で並べる。
+#[derive(Debug, Deserialize)]
+pub struct CompleteRequest {
+    parts: Vec<CompletePart>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompletePart {
+    part_number: i64,
+    etag: String,
+}
+
+#[derive(Serialize)]
+pub struct CompleteResponse {
+    object_id: String,
+    etag: String,
+    content_length: i64,
+}
+
+#[derive(Serialize)]
+pub struct AbortResponse {
+    success: bool,
+}
+
+pub async fn dispatch_post(
+    Path((bucket_name, object_id)): Path<(String, String)>,
+    Query(query): Query<ObjectQuery>,
+    State(state): State<AppState>,
+    request: Request,
+) -> Response {
+    if query.uploads.is_some() {
+        let headers = request.headers().clone();
+        return create_upload(&state.store, bucket_name, object_id, &headers)
+            .await
+            .into_response();
+    }
+    match query.upload_id {
+        Some(upload_id) => complete_upload(
+            &state.store,
+            &state.shards,
+            &bucket_name,
+            &object_id,
+            &upload_id,
+            request.into_body(),
+        )
+        .await
+        .into_response(),
+        None => post_object.call(request, state).await,
+    }
+}
+
+pub async fn dispatch_put(
+    Path((bucket_name, object_id)): Path<(String, String)>,
+    Query(query): Query<ObjectQuery>,
+    State(state): State<AppState>,
+    request: Request,
+) -> Response {
+    match (query.upload_id, query.part_number) {
+        (Some(upload_id), Some(part_number)) => {
+            let (parts, body) = request.into_parts();
+            upload_part(
+                &state.store,
+                &state.shards,
+                (&bucket_name, &object_id),
+                &upload_id,
+                part_number,
+                &parts.headers,
+                body,
+            )
+            .await
+            .into_response()
+        }
+        (Some(_), None) | (None, Some(_)) => ApiResult::<()>::Error(
+            StatusCode::BAD_REQUEST,
+            "InvalidArgument: both partNumber and uploadId are required.".to_string(),
+        )
+        .into_response(),
+        (None, None) => {
+            let rename = query.rename_object.is_some();
+            if rename || request.headers().contains_key(COPY_SOURCE) {
+                let headers = request.headers().clone();
+                return copy_object(
+                    &state.store,
+                    &state.shards,
+                    &state.config,
+                    (bucket_name, object_id),
+                    &headers,
+                    rename,
+                )
+                .await
+                .into_response();
+            }
+            put_object.call(request, state).await
+        }
+    }
+}
+
+pub async fn dispatch_get(
+    Path((bucket_name, object_id)): Path<(String, String)>,
+    Query(query): Query<ObjectQuery>,
+    State(state): State<AppState>,
+    request: Request,
+) -> Response {
+    match query.upload_id {
+        Some(upload_id) => list_parts(&state.store, &bucket_name, &object_id, &upload_id)
+            .await
+            .into_response(),
+        None => get_object.call(request, state).await,
+    }
+}
+
+pub async fn dispatch_delete(
+    Path((bucket_name, object_id)): Path<(String, String)>,
+    Query(query): Query<ObjectQuery>,
+    State(state): State<AppState>,
+    request: Request,
+) -> Response {
+    match query.upload_id {
+        Some(upload_id) => abort_upload(
+            &state.store,
+            &state.shards,
+            &bucket_name,
+            &object_id,
+            &upload_id,
+        )
+        .await
+        .into_response(),
+        None => delete_object.call(request, state).await,
+    }
+}
+
+/// アップロードを始める。Content-Type、Content-Dispositionのファイル名、x-amz-meta-* はここで指定する。
+#[instrument(skip(store, headers))]
+async fn create_upload(
+    store: &MetadataStore,
+    bucket_name: String,
+    object_id: String,
+    headers: &HeaderMap,
+) -> ApiResult<InitiateResponse> {
+    let bucket = match store.get_bucket(&bucket_name).await {
+        Ok(Some(bucket)) => bucket,
+        Ok(None) => {
+            return ApiResult::Error(
+                StatusCode::NOT_FOUND,
+                "bucket not found. Please create the bucket first.".to_string(),
+            );
+        }
+        Err(e) => return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+    let header_str = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    let profile = bucket.profile();
+    let upload = MultipartUpload {
+        upload_id: Uuid::new_v4().simple().to_string(),
+        bucket_name,
+        object_id,
+        created_at: Utc::now().to_rfc3339(),
+        file_name: header_str(header::CONTENT_DISPOSITION).and_then(file_name_from_disposition),
+        content_type: header_str(header::CONTENT_TYPE).map(str::to_string),
+        user_metadata: user_metadata_from_headers(headers),
+        data_shards: profile.data_shards as i64,
+        parity_shards: profile.parity_shards as i64,
+        stripe_unit: profile.stripe_unit as i64,
+    };
+    match store.create_multipart_upload(&upload).await {
+        Ok(()) => {
+            info!("Started upload {}.", upload.upload_id);
+            ApiResult::Success(
+                StatusCode::OK,
+                InitiateResponse {
+                    bucket_name: upload.bucket_name,
+                    object_id: upload.object_id,
+                    upload_id: upload.upload_id,
+                },
+            )
+        }
+        Err(e) => {
+            error!("Failed to start upload: {}", e);
+            ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        }
+    }
+}
+
+/// このオブジェクトの進行中のアップロードを返す。
+async fn find_upload<T>(
+    store: &MetadataStore,
+    bucket_name: &str,
+    object_id: &str,
+    upload_id: &str,
+) -> Result<MultipartUpload, ApiResult<T>> {
+    match store.get_multipart_upload(upload_id).await {
+        Ok(Some(upload)) if upload.bucket_name == bucket_name && upload.object_id == object_id => {
+            Ok(upload)
+        }
+        Ok(_) => Err(no_such_upload(upload_id)),
+        Err(e) => Err(ApiResult::Error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            e.to_string(),
+        )),
+    }
+}
+
+fn no_such_upload<T>(upload_id: &str) -> ApiResult<T> {
+    info!("Upload {} does not exist.", upload_id);
+    ApiResult::Error(
+        StatusCode::NOT_FOUND,
+        format!("NoSuchUpload: upload '{}' does not exist.", upload_id),
+    )
+}
+
+fn invalid_part<T>(message: String) -> ApiResult<T> {
+    info!("Complete rejected: {}", message);
+    ApiResult::Error(StatusCode::BAD_REQUEST, message)
+}
+
+/// パートを受け取った分からエンコードしてシャードに書き込む。パートはそれぞれ別の内部IDで置く。
+#[instrument(skip(store, shards, headers, body))]
+async fn upload_part(
+    store: &MetadataStore,
+    shards: &SharedShardStore,
+    (bucket_name, object_id): (&str, &str),
+    upload_id: &str,
+    part_number: i64,
+    headers: &HeaderMap,
+    body: Body,
+) -> ApiResult<PartResponse> {
+    if !(1..=MAX_PART_NUMBER).contains(&part_number) {
+        return ApiResult::Error(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "InvalidArgument: part number must be between 1 and {}.",
+                MAX_PART_NUMBER
+            ),
+        );
+    }
+    let upload = match find_upload(store, bucket_name, object_id, upload_id).await {
+        Ok(upload) => upload,
+        Err(e) => return e,
+    };
+    let expected = match ExpectedChecksums::from_headers(headers) {
+        Ok(expected) => expected,
+        Err(e) => return invalid_digest(e),
+    };
+    let profile = upload.profile();
+    let blob_id = new_blob_id();
+    let stored = store_data(
+        shards,
+        body.into_data_stream(),
+        &blob_id,
+        profile,
+        Vec::new(),
+        &expected,
+    )
+    .await;
+    let encoded = match stored {
+        Ok(encoded) => encoded,
+        Err(e) if e.is::<BadDigest>() => {
+            info!("Part rejected: {}", e);
+            return ApiResult::Error(StatusCode::BAD_REQUEST, e.to_string());
+        }
+        Err(e) => {
+            error!("Part upload failed: {}", e);
+            return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+        }
+    };
+    let part = UploadedPart {
+        upload_id: upload.upload_id,
+        part_number,
+        blob_id,
+        size: encoded.content_length as i64,
+        md5: encoded.md5,
+        sha256: encoded.sha256,
+        created_at: Utc::now().to_rfc3339(),
+    };
+    match store.put_uploaded_part(&part).await {
+        Ok(previous) => {
+            if let Some(previous) = previous {
+                info!("Replaced part {}.", part_number);
+                remove_shards(shards, &previous.blob_id, profile).await;
+            }
+        }
+        Err(e) => {
+            // 書き込み中にアップロードが完了・中止された
+            remove_shards(shards, &part.blob_id, profile).await;
+            if e.downcast_ref().is_some_and(is_foreign_key_violation) {
+                return no_such_upload(upload_id);
+            }
+            error!("Failed to record part: {}", e);
+            return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+        }
+    }
+    info!("Saved part {} of upload {}.", part_number, upload_id);
+    ApiResult::Success(StatusCode::OK, part_response(&part))
+}
+
+fn part_response(part: &UploadedPart) -> PartResponse {
+    PartResponse {
+        part_number: part.part_number,
+        etag: format!("\"{}\"", part.md5),
+        size: part.size,
+    }
+}
+
+#[instrument(skip(store))]
+async fn list_parts(
+    store: &MetadataStore,
+    bucket_name: &str,
+    object_id: &str,
+    upload_id: &str,
+) -> ApiResult<ListPartsResponse> {
+    let upload = match find_upload(store, bucket_name, object_id, upload_id).await {
+        Ok(upload) => upload,
+        Err(e) => return e,
+    };
+    match store.list_uploaded_parts(upload_id).await {
+        Ok(parts) => ApiResult::Success(
+            StatusCode::OK,
+            ListPartsResponse {
+                bucket_name: upload.bucket_name,
+                object_id: upload.object_id,
+                upload_id: upload.upload_id,
+                parts: parts.iter().map(part_response).collect(),
+            },
+        ),
+        Err(e) => ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+/// 指定されたパートを繋げてオブジェクトにする。指定されなかったパートは削除する。
+#[instrument(skip(store, shards, body))]
+async fn complete_upload(
+    store: &MetadataStore,
+    shards: &SharedShardStore,
+    bucket_name: &str,
+    object_id: &str,
+    upload_id: &str,
+    body: Body,
+) -> ApiResult<CompleteResponse> {
+    let request: CompleteRequest = match to_bytes(body, COMPLETE_BODY_LIMIT)
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|body| Ok(serde_json::from_slice(&body)?))
+    {
+        Ok(request) => request,
+        Err(e) => {
+            return ApiResult::Error(StatusCode::BAD_REQUEST, format!("MalformedRequest: {}", e));
+        }
+    };
+    let upload = match find_upload(store, bucket_name, object_id, upload_id).await {
+        Ok(upload) => upload,
+        Err(e) => return e,
+    };
+    let uploaded = match store.list_uploaded_parts(upload_id).await {
+        Ok(parts) => parts,
+        Err(e) => return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+
+    if request.parts.is_empty() {
+        return invalid_part("InvalidPart: at least one part must be specified.".to_string());
+    }
+    if request
+        .parts
+        .windows(2)
+        .any(|pair| pair[0].part_number >= pair[1].part_number)
+    {
+        return invalid_part(
+            "InvalidPartOrder: parts must be listed in ascending order.".to_string(),
+        );
+    }
+    let mut chosen = Vec::with_capacity(request.parts.len());
+    for requested in &request.parts {
+        let etag = requested.etag.trim().trim_matches('"');
+        match uploaded
+            .iter()
+            .find(|part| part.part_number == requested.part_number)
+        {
+            Some(part) if part.md5 == etag => chosen.push(part),
+            _ => {
+                return invalid_part(format!(
+                    "InvalidPart: part {} was not uploaded or its etag does not match.",
+                    requested.part_number
+                ));
+            }
+        }
+    }
+
+    let blob_id = new_blob_id();
+    let mut position = 0;
+    let parts: Vec<ObjectPart> = chosen
+        .iter()
+        .map(|part| {
+            let object_part = ObjectPart {
+                blob_id: blob_id.clone(),
+                part_number: part.part_number,
+                part_blob_id: part.blob_id.clone(),
+                position,
+                size: part.size,
+                sha256: part.sha256.clone(),
+            };
+            position += part.size;
+            object_part
+        })
+        .collect();
+    let etag = match multipart::multipart_etag(&chosen) {
+        Ok(etag) => etag,
+        Err(e) => return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+    let generation = Uuid::new_v4().simple().to_string();
+    let profile = upload.profile();
+    let metadata = NewObjectMetadata {
+        file_name: upload.file_name.as_deref(),
+        content_type: upload.content_type.as_deref(),
+        content_length: position,
+        user_metadata: upload.user_metadata.as_deref(),
+        md5: Some(&etag),
+        // オブジェクト全体のSHA-256は計算しない。GETではobject_partsに記録したパートごとのSHA-256で検証する
+        sha256: None,
+        generation: Some(&generation),
+        blob_id: Some(&blob_id),
+        profile,
+        packed: false,
+        part_count: Some(parts.len() as i64),
+    };
+    let completed = store
+        .complete_multipart_upload(&upload, &uploaded, &metadata, &parts)
+        .await;
+    match completed {
+        Ok(CompleteUpload::Completed(previous)) => {
+            for part in &uploaded {
+                if !chosen.contains(&part) {
+                    remove_shards(shards, &part.blob_id, profile).await;
+                }
+            }
+            if let Some(previous) = previous {
+                info!(
+                    "Replaced object. Removing previous generation {:?}",
+                    previous.generation
+                );
+                multipart::remove_object_data(store, shards, &previous).await;
+            }
+        }
+        Ok(CompleteUpload::NoSuchUpload) => return no_such_upload(upload_id),
+        Ok(CompleteUpload::PartsChanged) => {
+            info!("Parts of upload {} changed during completion.", upload_id);
+            return ApiResult::Error(
+                StatusCode::CONFLICT,
+                "Conflict: parts were uploaded while completing. Please retry.".to_string(),
+            );
+        }
+        Err(e) => {
+            error!("Failed to complete upload: {}", e);
+            return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+        }
+    }
+    info!("Completed upload {} with {} parts.", upload_id, parts.len());
+    ApiResult::Success(
+        StatusCode::OK,
+        CompleteResponse {
+            object_id: object_id.to_string(),
+            etag: format!("\"{}\"", etag),
+            content_length: position,
+        },
+    )
+}
+
+#[instrument(skip(store, shards))]
+async fn abort_upload(
+    store: &MetadataStore,
+    shards: &SharedShardStore,
+    bucket_name: &str,
+    object_id: &str,
+    upload_id: &str,
+) -> ApiResult<AbortResponse> {
+    let upload = match find_upload(store, bucket_name, object_id, upload_id).await {
+        Ok(upload) => upload,
+        Err(e) => return e,
+    };
+    match multipart::abort_upload(store, shards, upload_id, upload.profile()).await {
+        Ok(true) => ApiResult::Success(StatusCode::OK, AbortResponse { success: true }),
+        Ok(false) => no_such_upload(upload_id),
+        Err(e) => {
+            error!("Failed to abort upload: {}", e);
+            ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        }
+    }
+}
@@ -1,93 +1,373 @@
 use super::api::ApiResult;
-use crate::{db::MetadataStore, encode, handler::bucket::exist_buckets};
+use super::checksum::{BadDigest, ExpectedChecksums};
+use super::head::user_metadata_from_headers;
+use crate::{
+    db::{MetadataStore, NewObjectMetadata},
+    encode::{EncodedObject, ShardWriter, remove_shards},
+    multipart, new_blob_id,
+    pack::{self, PackKey},
+    profile::ErasureProfile,
+    server::ServerConfig,
+    store::SharedShardStore,
+};
+use anyhow::{Context, Result};
 use axum::{
     extract::{Multipart, Path, State},
-    http::StatusCode,
+    http::{HeaderMap, StatusCode, header},
     response::IntoResponse,
 };
 use bytes::Bytes;
+use futures::{Stream, TryStreamExt};
 use serde::Serialize;
 use tracing::{error, info, instrument};
+use uuid::Uuid;
 
 #[derive(Serialize)]
-struct PostResponse {
-    object_id: String,
+pub struct PostResponse {
+    pub object_id: String,
+    pub etag: String,
+    pub md5: String,
+    pub sha256: String,
 }
 
-#[instrument(skip(store, multipart))]
+#[instrument(skip(store, shards, config, headers, multipart))]
 pub async fn post_object(
     Path((bucket_name, object_id)): Path<(String, String)>,
     State(store): State<MetadataStore>,
+    State(shards): State<SharedShardStore>,
+    State(config): State<ServerConfig>,
+    headers: HeaderMap,
     mut multipart: Multipart,
 ) -> impl IntoResponse {
     info!("Handling POST request for object.");
 
-    // bucketが存在していない場合はエラー
-    match exist_buckets(&bucket_name, &store).await {
-        Ok(exists) => {
-            if !exists {
-                return ApiResult::Error(
-                    StatusCode::NOT_FOUND,
-                    "bucket not found. Please create the bucket first.".to_string(),
-                );
-            }
-        }
-        Err(e) => return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    let mut upload = match ObjectUpload::begin(&store, bucket_name, object_id, &headers).await {
+        Ok(upload) => upload,
+        Err(e) => return e,
     };
+    // Content-MD5 / x-amz-checksum-* はリクエストヘッダ、fileより前のフォームフィールド、fileパートのヘッダのどれでも指定できる
     while let Ok(Some(field)) = multipart.next_field().await {
-        let name = field.name();
+        let name = field.name().map(|name| name.to_string());
 
         if name.as_deref() == Some("file") {
-            let file_name = field.file_name().map(|name| name.to_string());
-            let content_type = field.content_type().map(|ctype| ctype.to_string());
-            match field.bytes().await {
-                Ok(bytes) => {
-                    let content_length = bytes.len() as i32;
-                    info!(
-                        "bucket_name: {}, object_id: {}, file_name: {:?}, content_type: {:?}, content_length: {}",
-                        bucket_name, object_id, file_name, content_type, content_length
-                    );
-                    let _ = store
-                        .insert_metadata(
-                            &bucket_name,
-                            &object_id,
-                            file_name.as_deref(),
-                            content_type.as_deref(),
-                            content_length,
-                        )
-                        .await
-                        .unwrap();
-                    return store_data(bytes, object_id).await;
-                }
-                Err(e) => {
-                    error!("POST request failed: {}: {}", e.status(), e.body_text());
-                    return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
-                }
+            match ExpectedChecksums::from_headers(field.headers()) {
+                Ok(part) => upload.expected.merge(part),
+                Err(e) => return invalid_digest(e),
+            }
+            upload.file_name = field.file_name().map(|name| name.to_string());
+            upload.content_type = field.content_type().map(|ctype| ctype.to_string());
+            return upload.save(&store, &shards, &config, field).await;
+        }
+
+        if let Some(name) = name.filter(|name| ExpectedChecksums::is_checksum(name)) {
+            let value = match field.text().await {
+                Ok(value) => value,
+                Err(e) => return ApiResult::Error(StatusCode::BAD_REQUEST, e.to_string()),
+            };
+            if let Err(e) = upload.expected.set(&name, &value) {
+                return invalid_digest(e);
             }
         }
     }
 
-    return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, "error".to_string());
+    ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, "error".to_string())
 }
 
-#[instrument(skip(bytes))]
-async fn store_data(bytes: Bytes, id: String) -> ApiResult<PostResponse> {
-    // bytesからbytesmutへの変換.失敗しないことを祈ってunwrap.
-    let data = bytes.try_into_mut().unwrap();
-    match encode::encode_file(data) {
-        Ok(shards) => match encode::save_shards(&shards, &id).await {
-            Ok(_) => {
-                info!("Saved data successfully. object_id: {}", id);
-                return ApiResult::Success(StatusCode::OK, PostResponse { object_id: id });
+/// 受信を始める前に確認したアップロード先と、リクエストで指定されたオブジェクトの属性。
+/// multipartのPOSTと生のボディのPUTで共通。
+pub struct ObjectUpload {
+    pub bucket_name: String,
+    pub object_id: String,
+    // オブジェクトをエンコードするバケットのプロファイル
+    pub bucket_profile: ErasureProfile,
+    // `If-None-Match: *` が指定された場合は既存のオブジェクトを上書きしない
+    pub if_none_match: bool,
+    pub user_metadata: Option<String>,
+    pub file_name: Option<String>,
+    pub content_type: Option<String>,
+    pub expected: ExpectedChecksums,
+}
+
+impl ObjectUpload {
+    /// バケットの存在と `If-None-Match` を確認し、リクエストヘッダのメタデータとチェックサムを読み取る。
+    /// ボディを受信する前にエラーを返せるよう、先に呼ぶ。
+    pub async fn begin<T>(
+        store: &MetadataStore,
+        bucket_name: String,
+        object_id: String,
+        headers: &HeaderMap,
+    ) -> Result<Self, ApiResult<T>> {
+        // bucketが存在していない場合はエラー
+        let bucket_profile = match store.get_bucket(&bucket_name).await {
+            Ok(Some(bucket)) => bucket.profile(),
+            Ok(None) => {
+                return Err(ApiResult::Error(
+                    StatusCode::NOT_FOUND,
+                    "bucket not found. Please create the bucket first.".to_string(),
+                ));
             }
             Err(e) => {
-                error!("POST request failed: save error: {}", e);
+                return Err(ApiResult::Error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    e.to_string(),
+                ));
+            }
+        };
+        let if_none_match = headers
+            .get(header::IF_NONE_MATCH)
+            .is_some_and(|v| v.as_bytes().trim_ascii() == b"*");
+        if if_none_match {
+            match store.get_metadata(&bucket_name, &object_id).await {
+                Ok(None) => {}
+                Ok(Some(_)) => return Err(precondition_failed(&object_id)),
+                Err(e) => {
+                    return Err(ApiResult::Error(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        e.to_string(),
+                    ));
+                }
+            }
+        }
+        let expected = ExpectedChecksums::from_headers(headers).map_err(invalid_digest)?;
+        Ok(Self {
+            bucket_name,
+            object_id,
+            bucket_profile,
+            if_none_match,
+            user_metadata: user_metadata_from_headers(headers),
+            file_name: None,
+            content_type: None,
+            expected,
+        })
+    }
+
+    /// `body` を受け取った分からエンコードしてシャードに書き込み、メタデータを登録する。
+    #[instrument(skip_all, fields(bucket_name = %self.bucket_name, object_id = %self.object_id))]
+    pub async fn save<S, E>(
+        self,
+        store: &MetadataStore,
+        shards: &SharedShardStore,
+        config: &ServerConfig,
+        mut body: S,
+    ) -> ApiResult<PostResponse>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Unpin,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let Self {
+            bucket_name,
+            object_id,
+            bucket_profile,
+            if_none_match,
+            user_metadata,
+            file_name,
+            content_type,
+            expected,
+        } = self;
+        // 上書きの場合も古い世代のシャードには触れず、新しい世代として書き込む。
+        // シャードはキーではなく新しい内部IDで名前を付ける
+        let generation = Uuid::new_v4().simple().to_string();
+        let shard_id = new_blob_id();
+        let (head, complete) = match read_head(&mut body, config.small_object_threshold).await {
+            Ok(head) => head,
+            Err(e) => {
+                error!("Upload failed: receive error: {}", e);
                 return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
             }
-        },
-        Err(e) => {
-            error!("POST request failed: encode error: {}", e);
-            return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+        };
+        // 小さなオブジェクトはシャードに分割せず、複製をパックファイルに追記する
+        let (profile, packed) = if complete {
+            (
+                bucket_profile.replicated(),
+                config.pack_small_objects && shards.segment_root().is_some(),
+            )
+        } else {
+            (bucket_profile, false)
+        };
+        let stored = if packed {
+            let encoded = EncodedObject::of(&head);
+            match expected.verify(&encoded) {
+                Ok(()) => store_packed(
+                    store,
+                    shards,
+                    PackKey {
+                        bucket_name: &bucket_name,
+                        object_id: &object_id,
+                        generation: &generation,
+                        shard_id: &shard_id,
+                    },
+                    profile,
+                    &head,
+                )
+                .await
+                .map(|()| encoded),
+                Err(e) => Err(e.into()),
+            }
+        } else {
+            // ボディを丸ごとメモリに載せず、受け取った分からエンコードして書き込む
+            store_data(shards, body, &shard_id, profile, head, &expected).await
+        };
+        let encoded = match stored {
+            Ok(encoded) => encoded,
+            Err(e) if e.is::<BadDigest>() => {
+                info!("Upload rejected: {}", e);
+                return ApiResult::Error(StatusCode::BAD_REQUEST, e.to_string());
+            }
+            Err(e) => {
+                error!("Upload failed: save error: {}", e);
+                return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+            }
+        };
+        info!(
+            "bucket_name: {}, object_id: {}, file_name: {:?}, content_type: {:?}, content_length: {}, md5: {}, profile: {:?}",
+            bucket_name,
+            object_id,
+            file_name,
+            content_type,
+            encoded.content_length,
+            encoded.md5,
+            profile
+        );
+        let metadata = NewObjectMetadata {
+            file_name: file_name.as_deref(),
+            content_type: content_type.as_deref(),
+            content_length: encoded.content_length as i64,
+            user_metadata: user_metadata.as_deref(),
+            md5: Some(&encoded.md5),
+            sha256: Some(&encoded.sha256),
+            generation: Some(&generation),
+            blob_id: Some(&shard_id),
+            profile,
+            packed,
+            part_count: None,
+        };
+        // メタデータを書き換えた時点で新しい世代に切り替わる
+        let saved = if if_none_match {
+            store
+                .insert_metadata(&bucket_name, &object_id, &metadata)
+                .await
+                .map(|id| id.is_some())
+        } else {
+            match store
+                .upsert_metadata(&bucket_name, &object_id, &metadata)
+                .await
+            {
+                Ok(Some(previous)) => {
+                    info!(
+                        "Replaced object. Removing previous generation {:?}",
+                        previous.generation
+                    );
+                    multipart::remove_object_data(store, shards, &previous).await;
+                    Ok(true)
+                }
+                Ok(None) => Ok(true),
+                Err(e) => Err(e),
+            }
+        };
+        match saved {
+            Ok(true) => {}
+            Ok(false) => {
+                // 書き込み中に他のリクエストが同じキーを作った
+                remove_shards(shards, &shard_id, profile).await;
+                return precondition_failed(&object_id);
+            }
+            Err(e) => {
+                error!("Upload failed: database error: {}", e);
+                // メタデータのないシャードを残さない
+                remove_shards(shards, &shard_id, profile).await;
+                return ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+            }
+        }
+        info!("Saved data successfully. object_id: {}", object_id);
+        ApiResult::Success(
+            StatusCode::OK,
+            PostResponse {
+                object_id,
+                etag: format!("\"{}\"", encoded.md5),
+                md5: encoded.md5,
+                sha256: encoded.sha256,
+            },
+        )
+    }
+}
+
+fn precondition_failed<T>(object_id: &str) -> ApiResult<T> {
+    info!("Upload rejected: object '{}' already exists.", object_id);
+    ApiResult::Error(
+        StatusCode::PRECONDITION_FAILED,
+        format!("PreconditionFailed: object '{}' already exists.", object_id),
+    )
+}
+
+pub fn invalid_digest<T>(e: anyhow::Error) -> ApiResult<T> {
+    info!("Upload rejected: {}", e);
+    ApiResult::Error(StatusCode::BAD_REQUEST, format!("InvalidDigest: {}", e))
+}
+
+/// ボディの先頭を最大 `threshold` バイトまで読み込む。
+/// `threshold` 未満でボディが終わった(小さなオブジェクトだった)場合は `true` も返す。
+async fn read_head<S, E>(body: &mut S, threshold: usize) -> Result<(Vec<u8>, bool)>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let mut head = Vec::new();
+    while head.len() < threshold {
+        match body.try_next().await? {
+            Some(chunk) => head.extend_from_slice(&chunk),
+            None => return Ok((head, true)),
         }
     }
+    Ok((head, false))
+}
+
+/// チェックサムを確認済みの小さなオブジェクトをパックファイルに追記し、シャードの位置を登録する。
+/// 位置を登録した後でメタデータの登録に失敗しても、追記したシャードはコンパクションで回収される。
+#[instrument(skip(store, shards, data))]
+async fn store_packed(
+    store: &MetadataStore,
+    shards: &SharedShardStore,
+    key: PackKey<'_>,
+    profile: ErasureProfile,
+    data: &[u8],
+) -> Result<()> {
+    let root = shards
+        .segment_root()
+        .context("shard store has no local directory for segments")?;
+    let indices: Vec<usize> = (0..profile.total_shards()).collect();
+    let entries = pack::write_shards(root, key, profile, data, &indices).await?;
+    store.put_pack_entries(&entries).await?;
+    Ok(())
+}
+
+/// ボディをシャードに書き込む。`head` は `read_head` で先に読み込んだ分。
+/// 受信に失敗した場合やチェックサムが一致しない場合(`BadDigest`)は、書きかけのシャードを捨ててエラーを返す。
+#[instrument(skip(shards, body))]
+pub async fn store_data<S, E>(
+    shards: &SharedShardStore,
+    mut body: S,
+    id: &str,
+    profile: ErasureProfile,
+    head: Vec<u8>,
+    expected: &ExpectedChecksums,
+) -> Result<EncodedObject>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let mut writer = ShardWriter::create(shards, id, profile).await?;
+    let received = async {
+        writer.write(&head).await?;
+        while let Some(chunk) = body.try_next().await? {
+            writer.write(&chunk).await?;
+        }
+        expected.verify(&writer.digests())?;
+        anyhow::Ok(())
+    }
+    .await;
+    if let Err(e) = received {
+        writer.abort().await;
+        return Err(e);
+    }
+    writer.finish().await
 }
@@ -0,0 +1,90 @@
+use super::api::ApiResult;
+use super::post::{ObjectUpload, PostResponse};
+use crate::{db::MetadataStore, server::ServerConfig, store::SharedShardStore};
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{HeaderMap, header},
+};
+use tracing::{info, instrument};
+
+/// リクエストボディをそのままオブジェクトとして保存する。
+/// `Content-Type` と `Content-Disposition` のファイル名はメタデータに記録する。
+/// `Content-Length` と長さの違うボディは受信エラーになり、オブジェクトは作られない。
+#[instrument(skip(store, shards, config, headers, body))]
+pub async fn put_object(
+    Path((bucket_name, object_id)): Path<(String, String)>,
+    State(store): State<MetadataStore>,
+    State(shards): State<SharedShardStore>,
+    State(config): State<ServerConfig>,
+    headers: HeaderMap,
+    body: Body,
+) -> ApiResult<PostResponse> {
+    info!("Handling PUT request for object.");
+
+    let mut upload = match ObjectUpload::begin(&store, bucket_name, object_id, &headers).await {
+        Ok(upload) => upload,
+        Err(e) => return e,
+    };
+    upload.content_type = header_str(&headers, header::CONTENT_TYPE).map(str::to_string);
+    upload.file_name =
+        header_str(&headers, header::CONTENT_DISPOSITION).and_then(file_name_from_disposition);
+    upload
+        .save(&store, &shards, &config, body.into_data_stream())
+        .await
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// `Content-Disposition` からファイル名を取り出す。
+/// RFC 6266 と同じく、`filename*=UTF-8''...` があれば `filename=` より優先する。
+pub fn file_name_from_disposition(value: &str) -> Option<String> {
+    let mut file_name = None;
+    for param in value.split(';').skip(1) {
+        let Some((name, value)) = param.split_once('=') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "filename*" => {
+                // charset'language'値 の形。UTF-8以外は読めないので `filename=` を使う
+                let mut parts = value.splitn(3, '\'');
+                if let (Some(charset), Some(_language), Some(encoded)) =
+                    (parts.next(), parts.next(), parts.next())
+                    && charset.eq_ignore_ascii_case("utf-8")
+                    && let Some(decoded) = percent_decode(encoded)
+                {
+                    return Some(decoded);
+                }
+            }
+            "filename" => {
+                let unquoted = value
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .unwrap_or(value);
+                file_name = Some(unquoted.to_string());
+            }
+            _ => {}
+        }
+    }
+    file_name.filter(|name| !name.is_empty())
+}
+
+/// `%XX` をデコードする。UTF-8として読めなければ `None` を返す。
+pub fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
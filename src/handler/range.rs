@@ -0,0 +1,174 @@
+use crate::multipart::ObjectReader;
+use anyhow::Result;
+use axum::{
+    body::Body,
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::ops::Range;
+use uuid::Uuid;
+
+// これより多くの範囲が指定された場合はRangeヘッダを無視してオブジェクト全体を返す
+const MAX_RANGES: usize = 64;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// Rangeヘッダがない、または解釈できないのでオブジェクト全体を返す
+    Full,
+    Partial(Vec<Range<u64>>),
+    Unsatisfiable,
+}
+
+/// `Range: bytes=...` ヘッダを解釈する。
+/// 文法的に正しくないヘッダは RFC 9110 に従って無視する。単位名は大文字小文字を区別しない。
+pub fn parse_range(value: Option<&str>, len: u64) -> RangeRequest {
+    let Some(specs) = value
+        .and_then(|v| v.trim().split_once('='))
+        .and_then(|(unit, specs)| unit.trim().eq_ignore_ascii_case("bytes").then_some(specs))
+    else {
+        return RangeRequest::Full;
+    };
+
+    let mut ranges = Vec::new();
+    let mut count = 0;
+    for spec in specs.split(',') {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            continue;
+        }
+        count += 1;
+        let Some((first, last)) = spec.split_once('-') else {
+            return RangeRequest::Full;
+        };
+        let range = match (first.trim(), last.trim()) {
+            ("", suffix) => {
+                let Ok(suffix) = suffix.parse::<u64>() else {
+                    return RangeRequest::Full;
+                };
+                len.saturating_sub(suffix)..len
+            }
+            (first, "") => {
+                let Ok(first) = first.parse::<u64>() else {
+                    return RangeRequest::Full;
+                };
+                first..len
+            }
+            (first, last) => {
+                let (Ok(first), Ok(last)) = (first.parse::<u64>(), last.parse::<u64>()) else {
+                    return RangeRequest::Full;
+                };
+                if last < first {
+                    return RangeRequest::Full;
+                }
+                first..last.saturating_add(1).min(len)
+            }
+        };
+        // オブジェクトの範囲外を指す指定は満たせないので捨てる
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+
+    if count == 0 || count > MAX_RANGES {
+        RangeRequest::Full
+    } else if ranges.is_empty() {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Partial(ranges)
+    }
+}
+
+fn content_range(range: &Range<u64>, len: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, len)
+}
+
+/// 要求された範囲だけを読み出す `206 Partial Content` のレスポンスを作る。
+/// 範囲が複数ある場合は `multipart/byteranges` で返す。
+pub fn partial_response(
+    reader: ObjectReader,
+    mut ranges: Vec<Range<u64>>,
+    len: u64,
+    content_type: &str,
+    mut headers: HeaderMap,
+) -> Response {
+    if ranges.len() == 1 {
+        let range = ranges.remove(0);
+        headers.insert(
+            header::CONTENT_RANGE,
+            HeaderValue::from_str(&content_range(&range, len)).unwrap(),
+        );
+        headers.insert(
+            header::CONTENT_LENGTH,
+            HeaderValue::from(range.end - range.start),
+        );
+        let body = Body::from_stream(reader.into_stream(range));
+        return (StatusCode::PARTIAL_CONTENT, headers, body).into_response();
+    }
+
+    let boundary = Uuid::new_v4().simple().to_string();
+    let mut parts = VecDeque::new();
+    let mut content_length = 0;
+    for (i, range) in ranges.into_iter().enumerate() {
+        let head = format!(
+            "{}--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+            if i == 0 { "" } else { "\r\n" },
+            boundary,
+            content_type,
+            content_range(&range, len)
+        );
+        content_length += head.len() as u64 + (range.end - range.start);
+        parts.push_back(Part::Literal(Bytes::from(head)));
+        parts.push_back(Part::Range(range));
+    }
+    let tail = format!("\r\n--{}--\r\n", boundary);
+    content_length += tail.len() as u64;
+    parts.push_back(Part::Literal(Bytes::from(tail)));
+
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(&format!("multipart/byteranges; boundary={}", boundary)).unwrap(),
+    );
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(content_length));
+    headers.remove(header::CONTENT_DISPOSITION);
+    let body = Body::from_stream(multipart_stream(reader, parts));
+    (StatusCode::PARTIAL_CONTENT, headers, body).into_response()
+}
+
+/// どの範囲も満たせない場合の `416 Range Not Satisfiable` のレスポンス
+pub fn unsatisfiable_response(len: u64) -> Response {
+    (
+        StatusCode::RANGE_NOT_SATISFIABLE,
+        [
+            (header::CONTENT_RANGE, format!("bytes */{}", len)),
+            (header::ACCEPT_RANGES, "bytes".to_string()),
+        ],
+    )
+        .into_response()
+}
+
+enum Part {
+    Literal(Bytes),
+    Range(Range<u64>),
+}
+
+fn multipart_stream(
+    reader: ObjectReader,
+    parts: VecDeque<Part>,
+) -> impl futures::Stream<Item = Result<Bytes>> {
+    futures::stream::try_unfold((reader, parts), |(mut reader, mut parts)| async move {
+        let data = match parts.pop_front() {
+            None => return Ok(None),
+            Some(Part::Literal(data)) => data,
+            Some(Part::Range(mut range)) => {
+                let data = reader.read_next(&mut range).await?;
+                if !range.is_empty() {
+                    parts.push_front(Part::Range(range));
+                }
+                data
+            }
+        };
+        Ok(Some((data, (reader, parts))))
+    })
+}
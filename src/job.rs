@@ -0,0 +1,127 @@
+use chrono::Utc;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Completed,
+    Failed,
+}
+
+/// バックグラウンドで実行しているバケット削除の進捗
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub id: String,
+    pub bucket_name: String,
+    pub state: JobState,
+    // ジョブ開始時点でバケットにあったオブジェクトの数
+    pub total_objects: u64,
+    pub deleted_objects: u64,
+    pub error: Option<String>,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+}
+
+// 終わったジョブの進捗を問い合わせられる期間
+const FINISHED_JOB_TTL: Duration = Duration::from_secs(60 * 60);
+
+struct Job {
+    status: JobStatus,
+    finished: Option<Instant>,
+}
+
+/// 実行中・実行済みのジョブを保持する。プロセスを再起動すると消える。
+/// 終わったジョブは `ttl` が過ぎると消えるので、強制削除を繰り返してもジョブが溜まり続けることはない。
+#[derive(Clone)]
+pub struct JobRegistry {
+    jobs: Arc<Mutex<HashMap<String, Job>>>,
+    ttl: Duration,
+}
+
+impl Default for JobRegistry {
+    fn default() -> Self {
+        Self::with_ttl(FINISHED_JOB_TTL)
+    }
+}
+
+impl JobRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 終わってから `ttl` が過ぎたジョブを消すレジストリを作る。
+    pub fn with_ttl(ttl: Duration) -> Self {
+        Self {
+            jobs: Arc::default(),
+            ttl,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Job>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, job| job.finished.is_none_or(|at| at.elapsed() < self.ttl));
+        jobs
+    }
+
+    /// `bucket_name` の削除ジョブを登録して `(ジョブ, true)` を返す。
+    /// 同じバケットのジョブが既に実行中なら、新しく登録せずに `(実行中のジョブ, false)` を返す。
+    pub fn start(&self, bucket_name: &str, total_objects: u64) -> (JobStatus, bool) {
+        let mut jobs = self.lock();
+        if let Some(running) = jobs.values().find(|job| {
+            job.status.bucket_name == bucket_name && job.status.state == JobState::Running
+        }) {
+            return (running.status.clone(), false);
+        }
+        let job = JobStatus {
+            id: Uuid::new_v4().to_string(),
+            bucket_name: bucket_name.to_string(),
+            state: JobState::Running,
+            total_objects,
+            deleted_objects: 0,
+            error: None,
+            started_at: Utc::now().to_rfc3339(),
+            finished_at: None,
+        };
+        jobs.insert(
+            job.id.clone(),
+            Job {
+                status: job.clone(),
+                finished: None,
+            },
+        );
+        (job, true)
+    }
+
+    pub fn get(&self, id: &str) -> Option<JobStatus> {
+        self.lock().get(id).map(|job| job.status.clone())
+    }
+
+    pub fn add_deleted(&self, id: &str, count: u64) {
+        if let Some(Job { status: job, .. }) = self.lock().get_mut(id) {
+            job.deleted_objects += count;
+            // 削除中に追加されたオブジェクトの分だけ総数を増やす
+            job.total_objects = job.total_objects.max(job.deleted_objects);
+        }
+    }
+
+    pub fn finish(&self, id: &str, error: Option<String>) {
+        if let Some(Job {
+            status: job,
+            finished,
+        }) = self.lock().get_mut(id)
+        {
+            *finished = Some(Instant::now());
+            job.state = match error {
+                Some(_) => JobState::Failed,
+                None => JobState::Completed,
+            };
+            job.error = error;
+            job.finished_at = Some(Utc::now().to_rfc3339());
+        }
+    }
+}
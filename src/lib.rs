@@ -1,14 +1,25 @@
+use crate::placement::{Placement, Target};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
-use std::path::{Path, PathBuf};
-use tokio::fs;
-use tracing::instrument;
+use std::path::PathBuf;
+use std::sync::LazyLock;
+use uuid::Uuid;
 
 pub mod db;
 pub mod decode;
 pub mod encode;
 pub mod handler;
+pub mod job;
+pub mod multipart;
+pub mod node;
+pub mod pack;
+pub mod placement;
+pub mod profile;
+pub mod remote;
+pub mod scrub;
 pub mod server;
+pub mod shard;
+pub mod store;
 
 // データシャード数とパリティシャード数
 pub mod env {
@@ -16,23 +27,52 @@ pub mod env {
     pub const PARITY_SHARDS: usize = 3;
     pub const NUM_OUTPUT_DIRS: usize = 9;
     pub const OUTPUT_DIR_PREFIX: &str = "outputs/output";
+    // 1ストライプあたりの各シャードのチャンク長
+    pub const STRIPE_UNIT: usize = 1024 * 1024;
 }
 
-#[instrument(skip(object_id, i))]
-pub async fn get_filepath(object_id: &str, i: usize) -> PathBuf {
+/// 新しく書き込むオブジェクトの内部ID。シャードファイルの名前に使う。
+/// キーをファイル名に使わないので、`/` や `..`、非ASCII文字を含むキーでもシャードは `outputs/` の外に出ず、
+/// 別のバケットの同じキーとも重ならない。
+pub fn new_blob_id() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+/// 内部IDを持たない古いオブジェクトのシャードファイルの名前に使うID。
+/// 上書きのたびに新しい世代のシャードを書くので、キーと世代を組み合わせる。世代を持たない古いオブジェクトはキーをそのまま使う。
+pub fn shard_id(object_id: &str, generation: Option<&str>) -> String {
+    match generation {
+        Some(generation) => format!("{}.{}", object_id, generation),
+        None => object_id.to_string(),
+    }
+}
+
+// outputs/output1 〜 outputs/output9 を置き場所とする配置
+static OUTPUT_DIRS: LazyLock<Placement> = LazyLock::new(|| {
+    let dirs = (1..=env::NUM_OUTPUT_DIRS)
+        .map(|i| Target::new(format!("{}{}", env::OUTPUT_DIR_PREFIX, i)))
+        .collect();
+    Placement::new(dirs).expect("output directories are distinct")
+});
+
+/// シャード `i` を置くディレクトリの、データディレクトリからの相対パス。
+/// 同じオブジェクトのシャードは全て別々のディレクトリに置かれるので、`i` はディレクトリの数より小さくなければならない。
+pub fn output_dir_name(object_id: &str, i: usize) -> anyhow::Result<PathBuf> {
+    let target = OUTPUT_DIRS.target_for(object_id, i)?;
+    Ok(PathBuf::from(&OUTPUT_DIRS.targets()[target].name))
+}
+
+/// 配置を決める前に書かれたシャードのディレクトリ。
+/// object_idとシャードインデックスを組み合わせたハッシュで選んでいたので、同じオブジェクトのシャードが重なることがある。
+pub fn legacy_output_dir_name(object_id: &str, i: usize) -> PathBuf {
     let mut hasher = DefaultHasher::new();
-    // object_idとシャードインデックスを組み合わせてハッシュ化
     format!("{}{}", object_id, i).hash(&mut hasher);
     let hash = hasher.finish();
     let dir_index = (hash % env::NUM_OUTPUT_DIRS as u64) as usize;
-    let output_dir_name = format!("{}{}", env::OUTPUT_DIR_PREFIX, dir_index + 1);
-    let output_path = Path::new(&output_dir_name);
-    fs::create_dir_all(output_path)
-        .await
-        .expect("Directory creation failed.");
-
-    let filename = format!("{}_{:02}.bin", object_id, i);
-    let filepath = output_path.join(filename);
+    PathBuf::from(format!("{}{}", env::OUTPUT_DIR_PREFIX, dir_index + 1))
+}
 
-    filepath
+/// シャード `i` のファイル名
+pub fn shard_filename(object_id: &str, i: usize) -> String {
+    format!("{}_{:02}.bin", object_id, i)
 }
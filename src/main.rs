@@ -1,5 +1,5 @@
 use anyhow::Result;
-use t3::server;
+use t3::{node, server};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[tokio::main]
@@ -11,7 +11,11 @@ async fn main() -> Result<()> {
         )
         .with(tracing_subscriber::fmt::layer().pretty())
         .init();
-    server::run_server().await?;
+    // `t3 node` でストレージノードとして起動する。引数がなければゲートウェイとして起動する
+    match std::env::args().nth(1).as_deref() {
+        Some("node") => node::run_node().await?,
+        _ => server::run_server().await?,
+    }
 
     Ok(())
 }
@@ -0,0 +1,290 @@
+use crate::db::{MetadataStore, ObjectMetadata, ObjectPart, UploadedPart};
+use crate::decode::ShardReader;
+use crate::encode::remove_shards;
+use crate::pack;
+use crate::profile::ErasureProfile;
+use crate::scrub::RepairOwner;
+use crate::store::SharedShardStore;
+use anyhow::{Context, Result, bail};
+use bytes::Bytes;
+use chrono::Utc;
+use futures::{Stream, StreamExt, TryStreamExt};
+use md5::{Digest, Md5};
+use std::ops::Range;
+use std::time::Duration;
+use tracing::{error, info, instrument};
+
+// マルチパートアップロードでは、パートごとに別の内部IDでイレイジャーコーディングしてシャードに置く。
+// 完了すると、オブジェクトの内部IDにパートの並び(object_parts)を記録し、読むときはパートを順に繋げる。
+// 完了も中止もされないまま放置されたアップロードは、リーパーがパートのシャードごと削除する。
+
+// S3と同じく、パート番号は1〜10000
+pub const MAX_PART_NUMBER: i64 = 10_000;
+// リーパーが1回のクエリで読み込むアップロードの数
+const REAP_BATCH_SIZE: i64 = 100;
+
+/// S3と同じく、各パートのMD5を繋げたもののMD5にパート数を付けたものをマルチパートオブジェクトのETagにする。
+pub fn multipart_etag(parts: &[&UploadedPart]) -> Result<String> {
+    let mut hasher = Md5::new();
+    for part in parts {
+        hasher.update(hex::decode(&part.md5)?);
+    }
+    Ok(format!(
+        "{}-{}",
+        hex::encode(hasher.finalize()),
+        parts.len()
+    ))
+}
+
+/// マルチパートオブジェクトをパートを順に繋げて読む。パートのシャードは読む範囲にかかったときに開く。
+pub struct PartsReader {
+    shards: SharedShardStore,
+    profile: ErasureProfile,
+    parts: Vec<ObjectPart>,
+    current: Option<(usize, ShardReader)>,
+    // read-repairが有効なら、パートを持つオブジェクト
+    read_repair: Option<RepairOwner>,
+}
+
+impl PartsReader {
+    pub fn new(
+        shards: &SharedShardStore,
+        profile: ErasureProfile,
+        parts: Vec<ObjectPart>,
+        read_repair: Option<RepairOwner>,
+    ) -> Self {
+        Self {
+            shards: shards.clone(),
+            profile,
+            parts,
+            current: None,
+            read_repair,
+        }
+    }
+
+    fn len(&self) -> u64 {
+        self.parts
+            .last()
+            .map_or(0, |part| (part.position + part.size) as u64)
+    }
+
+    /// `range` の先頭を含むパートから、そのパートに収まる分のデータを返して `range` を進める。
+    pub async fn read_next(&mut self, range: &mut Range<u64>) -> Result<Bytes> {
+        if range.end > self.len() {
+            bail!(
+                "range {:?} is out of bounds for {} bytes",
+                range,
+                self.len()
+            );
+        }
+        let index = self
+            .parts
+            .partition_point(|part| (part.position + part.size) as u64 <= range.start);
+        let part = self.parts.get(index).context("range is out of bounds")?;
+        let (start, size) = (part.position as u64, part.size as u64);
+        let reader = match &mut self.current {
+            Some((current, reader)) if *current == index => reader,
+            current => {
+                let reader =
+                    open_part(&self.shards, self.profile, part, self.read_repair.as_ref()).await?;
+                &mut current.insert((index, reader)).1
+            }
+        };
+        let mut within = range.start - start..range.end.min(start + size) - start;
+        let data = reader.read_next(&mut within).await?;
+        range.start = start + within.start;
+        Ok(data)
+    }
+
+    /// オブジェクト全体を流し、パートごとにSHA-256を検証するストリームに変換する。
+    /// 各パートの最後のチャンクはそのパートの検証が済むまで送らないので、壊れたパートがあればそこで打ち切る。
+    pub fn into_verified_stream(self) -> impl Stream<Item = Result<Bytes>> {
+        let Self {
+            shards,
+            profile,
+            parts,
+            read_repair,
+            ..
+        } = self;
+        futures::stream::iter(parts)
+            .then(move |part| {
+                let (shards, read_repair) = (shards.clone(), read_repair.clone());
+                async move {
+                    let reader = open_part(&shards, profile, &part, read_repair.as_ref()).await?;
+                    anyhow::Ok(reader.into_verified_stream(part.sha256))
+                }
+            })
+            .try_flatten()
+    }
+}
+
+async fn open_part(
+    shards: &SharedShardStore,
+    profile: ErasureProfile,
+    part: &ObjectPart,
+    read_repair: Option<&RepairOwner>,
+) -> Result<ShardReader> {
+    let reader = ShardReader::open(shards, &part.part_blob_id, part.size as u64, profile).await?;
+    Ok(match read_repair {
+        Some(owner) => reader.with_read_repair(Some(part.sha256.clone()), owner.clone()),
+        None => reader,
+    })
+}
+
+/// オブジェクトのデータを読む。1つのシャードの組に置いたオブジェクトとマルチパートオブジェクトを同じように扱う。
+pub enum ObjectReader {
+    Shards(ShardReader),
+    Parts(PartsReader),
+}
+
+impl ObjectReader {
+    pub async fn read_next(&mut self, range: &mut Range<u64>) -> Result<Bytes> {
+        match self {
+            ObjectReader::Shards(reader) => reader.read_next(range).await,
+            ObjectReader::Parts(reader) => reader.read_next(range).await,
+        }
+    }
+
+    /// 元データの `range` の部分を流すストリームに変換する。
+    pub fn into_stream(self, range: Range<u64>) -> impl Stream<Item = Result<Bytes>> {
+        futures::stream::try_unfold((self, range), |(mut reader, mut range)| async move {
+            if range.is_empty() {
+                return Ok(None);
+            }
+            let data = reader.read_next(&mut range).await?;
+            Ok(Some((data, (reader, range))))
+        })
+    }
+}
+
+/// オブジェクトを読む `ObjectReader` を開く。`read_repair` が有効なら、読めなかったシャードを後で作り直す。
+pub async fn open_object(
+    store: &MetadataStore,
+    shards: &SharedShardStore,
+    object: &ObjectMetadata,
+    content_length: u64,
+    read_repair: bool,
+) -> Result<ObjectReader> {
+    if object.part_count.is_some() {
+        let parts = store.get_object_parts(&object.shard_id()).await?;
+        return Ok(ObjectReader::Parts(PartsReader::new(
+            shards,
+            object.profile(),
+            parts,
+            read_repair.then(|| RepairOwner::new(store, object)),
+        )));
+    }
+    let reader = pack::open_reader(store, shards, object, content_length).await?;
+    // パックファイルに置いたオブジェクトはスクラブで修復する
+    Ok(ObjectReader::Shards(if read_repair && !object.packed {
+        reader.with_read_repair(object.sha256.clone(), RepairOwner::new(store, object))
+    } else {
+        reader
+    }))
+}
+
+/// 削除・上書きされたオブジェクトのシャードを削除する。メタデータの行を消した後に呼ぶ。
+/// パックファイルに置いたシャードはコンパクションで回収するので何もしない。
+/// コピー・リネームした他のオブジェクトがまだ同じシャードを参照していれば残す。
+#[instrument(skip(store, shards, object), fields(object_id = %object.object_id))]
+pub async fn remove_object_data(
+    store: &MetadataStore,
+    shards: &SharedShardStore,
+    object: &ObjectMetadata,
+) {
+    if object.packed {
+        return;
+    }
+    let shard_id = object.shard_id();
+    match store.count_blob_refs(&shard_id).await {
+        Ok(0) => {}
+        Ok(refs) => {
+            info!("{} is still referenced by {} objects.", shard_id, refs);
+            return;
+        }
+        Err(e) => {
+            // 参照が残っているか分からないので、シャードは消さない
+            error!("Failed to count references to {}: {}", shard_id, e);
+            return;
+        }
+    }
+    if object.part_count.is_none() {
+        remove_shards(shards, &shard_id, object.profile()).await;
+        return;
+    }
+    let parts = match store.get_object_parts(&shard_id).await {
+        Ok(parts) => parts,
+        Err(e) => {
+            error!("Failed to load parts of {}: {}", shard_id, e);
+            return;
+        }
+    };
+    for part in &parts {
+        remove_shards(shards, &part.part_blob_id, object.profile()).await;
+    }
+    if let Err(e) = store.delete_object_parts(&shard_id).await {
+        error!("Failed to delete parts of {}: {}", shard_id, e);
+    }
+}
+
+/// マルチパートアップロードを中止し、アップロード済みのパートのシャードを削除する。
+/// アップロードが存在しなければ `false` を返す。
+#[instrument(skip(store, shards))]
+pub async fn abort_upload(
+    store: &MetadataStore,
+    shards: &SharedShardStore,
+    upload_id: &str,
+    profile: ErasureProfile,
+) -> Result<bool> {
+    let Some(parts) = store.delete_multipart_upload(upload_id).await? else {
+        return Ok(false);
+    };
+    for part in &parts {
+        remove_shards(shards, &part.blob_id, profile).await;
+    }
+    info!("Aborted upload {} with {} parts.", upload_id, parts.len());
+    Ok(true)
+}
+
+/// `expiry` より前に始まったアップロードを全て中止し、中止した数を返す。
+#[instrument(skip(store, shards))]
+pub async fn reap_expired_uploads(
+    store: &MetadataStore,
+    shards: &SharedShardStore,
+    expiry: Duration,
+) -> Result<u64> {
+    let cutoff = (Utc::now() - chrono::Duration::from_std(expiry)?).to_rfc3339();
+    let mut reaped = 0;
+    loop {
+        let uploads = store
+            .list_expired_multipart_uploads(&cutoff, REAP_BATCH_SIZE)
+            .await?;
+        if uploads.is_empty() {
+            break;
+        }
+        for upload in uploads {
+            if abort_upload(store, shards, &upload.upload_id, upload.profile()).await? {
+                reaped += 1;
+            }
+        }
+    }
+    Ok(reaped)
+}
+
+/// `interval` ごとに、`expiry` を過ぎても完了・中止されていないアップロードを中止し続ける。
+#[instrument(skip(store, shards))]
+pub async fn run_reaper(
+    store: MetadataStore,
+    shards: SharedShardStore,
+    expiry: Duration,
+    interval: Duration,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        match reap_expired_uploads(&store, &shards, expiry).await {
+            Ok(0) => {}
+            Ok(reaped) => info!("Reaped {} abandoned uploads.", reaped),
+            Err(e) => error!("Reaping uploads failed: {}", e),
+        }
+    }
+}
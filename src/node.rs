@@ -0,0 +1,220 @@
+use crate::handler::api::ApiResult;
+use crate::handler::range::{self, RangeRequest};
+use crate::store::{LocalShardStore, SharedShardStore};
+use anyhow::Result;
+use axum::{
+    Router,
+    body::Body,
+    extract::{DefaultBodyLimit, Path, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use futures::TryStreamExt;
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::{error, info, instrument};
+
+// ストレージノード(`t3 node`)のシャードAPI。ゲートウェイの `RemoteShardStore` から呼ばれる。
+//   PUT    /shards/{shard_id}/{index}  シャードを置き換える
+//   GET    /shards/{shard_id}/{index}  シャードを読む(`Range: bytes=a-b` で一部分)
+//   HEAD   /shards/{shard_id}/{index}  シャードの長さを返す
+//   DELETE /shards/{shard_id}/{index}  シャードを削除する
+//   GET    /shards/{shard_id}          ノードにあるシャードのインデックスを返す
+// `shard_id` はノードのファイル名になるので、`local_shard_id` でエンコードしてからストアに渡す。
+
+const DEFAULT_NODE_ADDR: &str = "127.0.0.1:9000";
+
+/// ノードのストアに渡すID。URLでデコードされた `shard_id` がパスの区切りにならないように、
+/// `%`・`/`・`\`・NULをパーセントエンコードする。`..` は区切りがなければファイル名の一部にしかならない。
+pub fn local_shard_id(shard_id: &str) -> String {
+    let mut encoded = String::with_capacity(shard_id.len());
+    for c in shard_id.chars() {
+        match c {
+            '%' | '/' | '\\' | '\0' => encoded.push_str(&format!("%{:02X}", c as u32)),
+            c => encoded.push(c),
+        }
+    }
+    encoded
+}
+
+#[instrument]
+pub async fn run_node() -> Result<()> {
+    info!("Starting the storage node.");
+
+    dotenvy::dotenv().ok();
+    // NODE_DATA_DIRの下の outputs/outputN にシャードを置く。同じホストで複数のノードを動かすときはそれぞれ別にする
+    let store: SharedShardStore = match env::var("NODE_DATA_DIR") {
+        Ok(dir) => Arc::new(LocalShardStore::with_root(dir)),
+        Err(_) => Arc::new(LocalShardStore::new()),
+    };
+    let addr: SocketAddr = env::var("NODE_ADDR")
+        .unwrap_or_else(|_| DEFAULT_NODE_ADDR.to_string())
+        .parse()?;
+    info!("listening on {}", addr);
+    axum::serve(TcpListener::bind(addr).await?, app(store)).await?;
+
+    Ok(())
+}
+
+#[instrument(skip(store))]
+pub fn app(store: SharedShardStore) -> Router {
+    Router::new()
+        .route("/shards/{:shard_id}", get(list_shards))
+        .route(
+            "/shards/{:shard_id}/{:index}",
+            get(get_shard)
+                .head(head_shard)
+                .put(put_shard)
+                .delete(delete_shard),
+        )
+        .layer(DefaultBodyLimit::disable())
+        .with_state(store)
+}
+
+#[instrument(skip(store, body))]
+async fn put_shard(
+    Path((shard_id, index)): Path<(String, usize)>,
+    State(store): State<SharedShardStore>,
+    body: Body,
+) -> StatusCode {
+    let shard_id = local_shard_id(&shard_id);
+    let mut upload = match store.create(&shard_id, index).await {
+        Ok(upload) => upload,
+        Err(e) => {
+            error!("Failed to create shard: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    };
+    let received = async {
+        let mut stream = body.into_data_stream();
+        let mut offset = 0;
+        while let Some(chunk) = stream.try_next().await? {
+            upload.write_at(offset, &chunk).await?;
+            offset += chunk.len() as u64;
+        }
+        upload.sync().await
+    }
+    .await;
+    if let Err(e) = received {
+        error!("Failed to receive shard: {}", e);
+        upload.abort().await;
+        return StatusCode::INTERNAL_SERVER_ERROR;
+    }
+    let committed = async {
+        upload.commit().await?;
+        store.flush(&shard_id, &[index]).await
+    }
+    .await;
+    match committed {
+        Ok(()) => {
+            info!("Saved shard.");
+            StatusCode::NO_CONTENT
+        }
+        Err(e) => {
+            error!("Failed to commit shard: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+#[instrument(skip(store, headers))]
+async fn get_shard(
+    Path((shard_id, index)): Path<(String, usize)>,
+    State(store): State<SharedShardStore>,
+    headers: HeaderMap,
+) -> Response {
+    let shard_id = local_shard_id(&shard_id);
+    let len = match store.stat(&shard_id, index).await {
+        Ok(Some(stat)) => stat.len,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!("Failed to stat shard: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let requested = range::parse_range(
+        headers.get(header::RANGE).and_then(|v| v.to_str().ok()),
+        len,
+    );
+    // ゲートウェイは1つの範囲しか要求しない
+    let (status, range) = match requested {
+        RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+            (StatusCode::PARTIAL_CONTENT, ranges[0].clone())
+        }
+        RangeRequest::Unsatisfiable => return range::unsatisfiable_response(len),
+        _ => (StatusCode::OK, 0..len),
+    };
+    match store.get(&shard_id, index, range.clone()).await {
+        Ok(data) => {
+            let mut response = (status, data).into_response();
+            if status == StatusCode::PARTIAL_CONTENT {
+                let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, len);
+                if let Ok(value) = content_range.parse() {
+                    response.headers_mut().insert(header::CONTENT_RANGE, value);
+                }
+            }
+            response
+        }
+        Err(e) => {
+            error!("Failed to read shard: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[instrument(skip(store))]
+async fn head_shard(
+    Path((shard_id, index)): Path<(String, usize)>,
+    State(store): State<SharedShardStore>,
+) -> Response {
+    let shard_id = local_shard_id(&shard_id);
+    match store.stat(&shard_id, index).await {
+        Ok(Some(stat)) => (
+            StatusCode::OK,
+            [(header::CONTENT_LENGTH, stat.len.to_string())],
+        )
+            .into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!("Failed to stat shard: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[instrument(skip(store))]
+async fn delete_shard(
+    Path((shard_id, index)): Path<(String, usize)>,
+    State(store): State<SharedShardStore>,
+) -> StatusCode {
+    let shard_id = local_shard_id(&shard_id);
+    match store.delete(&shard_id, index).await {
+        Ok(true) => {
+            info!("Deleted shard.");
+            StatusCode::NO_CONTENT
+        }
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(e) => {
+            error!("Failed to delete shard: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+#[instrument(skip(store))]
+async fn list_shards(
+    Path(shard_id): Path<String>,
+    State(store): State<SharedShardStore>,
+) -> ApiResult<Vec<usize>> {
+    let shard_id = local_shard_id(&shard_id);
+    match store.list(&shard_id).await {
+        Ok(indices) => ApiResult::Success(StatusCode::OK, indices),
+        Err(e) => {
+            error!("Failed to list shards: {}", e);
+            ApiResult::Error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        }
+    }
+}
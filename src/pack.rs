@@ -0,0 +1,324 @@
+use crate::db::{MetadataStore, ObjectMetadata, PackEntry};
+use crate::decode::{ShardLocation, ShardReader};
+use crate::encode::encode_file;
+use crate::env::{NUM_OUTPUT_DIRS, OUTPUT_DIR_PREFIX};
+use crate::output_dir_name;
+use crate::profile::ErasureProfile;
+use crate::shard::{ShardHeader, encode_shard};
+use crate::store::SharedShardStore;
+use anyhow::{Context, Result};
+use bytes::BytesMut;
+use futures::future::try_join_all;
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::{error, info, instrument};
+use uuid::Uuid;
+
+// 小さなオブジェクトのシャードは1つずつファイルにせず、ディレクトリごとのセグメント(パックファイル)に追記していく。
+// シャードの位置(セグメント, 位置, 長さ)はメタデータの pack_entries に記録する。
+// オブジェクトを削除・上書きしてもセグメントはそのまま残り、コンパクションで生きているシャードだけを書き直して領域を回収する。
+// セグメントは `ShardStore` を通さずローカルのファイルとして読み書きするので、`ShardStore::segment_root` を
+// 返すストア(`LocalShardStore`)でしか使えない。ストレージノードを使う場合、小さなオブジェクトもシャードとして置く。
+
+// セグメントの大きさの上限。これを超えたら次のセグメントに切り替える
+pub const SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+pub const SEGMENT_EXTENSION: &str = "pack";
+// 生きているシャードの割合がこれを下回ったセグメントを書き直す
+const COMPACTION_LIVE_RATIO: f64 = 0.5;
+// 書き込み中のリクエストと競合しないよう、最後の追記からこの時間が経ったセグメントだけを書き直す。
+// 書き直した後も、古い位置を読んでいるGETが終わるまでこの時間だけ古いセグメントを残しておく
+pub const COMPACTION_GRACE: Duration = Duration::from_secs(60);
+
+struct ActiveSegment {
+    path: PathBuf,
+    file: File,
+    len: u64,
+}
+
+type SegmentSlot = Arc<tokio::sync::Mutex<Option<ActiveSegment>>>;
+
+// ディレクトリごとの追記中のセグメント。プロセスを再起動すると新しいセグメントから追記する
+static ACTIVE_SEGMENTS: LazyLock<Mutex<HashMap<PathBuf, SegmentSlot>>> =
+    LazyLock::new(Default::default);
+
+fn segment_slot(dir: &Path) -> SegmentSlot {
+    ACTIVE_SEGMENTS
+        .lock()
+        .unwrap()
+        .entry(dir.to_path_buf())
+        .or_default()
+        .clone()
+}
+
+async fn open_segment(dir: &Path) -> Result<ActiveSegment> {
+    let path = dir.join(format!(
+        "segment-{}.{}",
+        Uuid::new_v4().simple(),
+        SEGMENT_EXTENSION
+    ));
+    let file = OpenOptions::new()
+        .create_new(true)
+        .append(true)
+        .open(&path)
+        .await?;
+    // セグメントの作成自体を永続化する
+    File::open(dir).await?.sync_all().await?;
+    info!("Opened segment {:?}", path);
+    Ok(ActiveSegment { path, file, len: 0 })
+}
+
+/// `dir` の追記中のセグメントに `data` を追記してfsyncし、セグメントのパスと追記した位置を返す。
+#[instrument(skip(data))]
+pub async fn append(dir: &Path, data: &[u8]) -> Result<(PathBuf, u64)> {
+    let slot = segment_slot(dir);
+    let mut active = slot.lock().await;
+    // 失敗した場合は途中まで書いたかもしれないので、そのセグメントにはもう追記しない
+    let mut segment = match active.take() {
+        Some(segment) if segment.len < SEGMENT_SIZE => segment,
+        _ => open_segment(dir).await?,
+    };
+    let position = segment.len;
+    segment.file.write_all(data).await?;
+    segment.file.sync_data().await?;
+    segment.len += data.len() as u64;
+    let path = segment.path.clone();
+    *active = Some(segment);
+    Ok((path, position))
+}
+
+/// `segment` が追記中なら、それ以上追記しないようにする。
+async fn seal(segment: &Path) {
+    let Some(dir) = segment.parent() else {
+        return;
+    };
+    let slot = segment_slot(dir);
+    let mut active = slot.lock().await;
+    if active.as_ref().is_some_and(|active| active.path == segment) {
+        *active = None;
+    }
+}
+
+/// パックファイルに置くオブジェクトの世代。
+/// `shard_id` はシャードのヘッダと置き場所に使うID(`ObjectMetadata::shard_id`)。
+#[derive(Debug, Clone, Copy)]
+pub struct PackKey<'a> {
+    pub bucket_name: &'a str,
+    pub object_id: &'a str,
+    pub generation: &'a str,
+    pub shard_id: &'a str,
+}
+
+/// 小さなオブジェクトをメモリ上でエンコードし、`indices` のシャードを `root` の下のそれぞれのディレクトリのセグメントに追記する。
+/// 返した位置を `MetadataStore::put_pack_entries` で登録するまで、シャードは読み出せない。
+#[instrument(skip(data))]
+pub async fn write_shards(
+    root: &Path,
+    key: PackKey<'_>,
+    profile: ErasureProfile,
+    data: &[u8],
+    indices: &[usize],
+) -> Result<Vec<PackEntry>> {
+    let PackKey {
+        bucket_name,
+        object_id,
+        generation,
+        shard_id,
+    } = key;
+    let payloads = encode_file(BytesMut::from(data), profile)?;
+    try_join_all(indices.iter().map(|&i| {
+        let payload = &payloads[i];
+        async move {
+            let header = ShardHeader::new(
+                shard_id,
+                i,
+                profile.data_shards,
+                profile.parity_shards,
+                profile.stripe_unit,
+                data.len() as u64,
+            );
+            let content = encode_shard(&header, payload)?;
+            let dir = root.join(output_dir_name(shard_id, i)?);
+            fs::create_dir_all(&dir).await?;
+            let (segment, position) = append(&dir, &content).await?;
+            anyhow::Ok(PackEntry {
+                bucket_name: bucket_name.to_string(),
+                object_id: object_id.to_string(),
+                generation: generation.to_string(),
+                shard_index: i as i64,
+                segment: segment.to_string_lossy().into_owned(),
+                position: position as i64,
+                length: content.len() as i64,
+            })
+        }
+    }))
+    .await
+}
+
+/// オブジェクトのシャードを読む `ShardReader` を開く。パックファイルに置いたオブジェクトは登録した位置から読む。
+pub async fn open_reader(
+    store: &MetadataStore,
+    shards: &SharedShardStore,
+    object: &ObjectMetadata,
+    content_length: u64,
+) -> Result<ShardReader> {
+    let shard_id = object.shard_id();
+    let profile = object.profile();
+    if !object.packed {
+        return ShardReader::open(shards, &shard_id, content_length, profile).await;
+    }
+    let generation = object
+        .generation
+        .as_deref()
+        .context("packed object has no generation")?;
+    let entries = store
+        .get_pack_entries(&object.bucket_name, &object.object_id, generation)
+        .await?;
+    let mut locations = vec![None; profile.total_shards()];
+    for entry in entries {
+        if let Some(location) = locations.get_mut(entry.shard_index as usize) {
+            *location = Some(ShardLocation {
+                path: PathBuf::from(entry.segment),
+                offset: entry.position as u64,
+            });
+        }
+    }
+    ShardReader::open_at(shards, &shard_id, content_length, profile, locations).await
+}
+
+#[derive(Debug, Default)]
+pub struct CompactionSummary {
+    pub segments: u64,
+    pub moved_shards: u64,
+    pub reclaimed_bytes: u64,
+}
+
+/// 生きているシャードの割合が少ないセグメントを書き直し、削除・上書きされたオブジェクトの領域を回収する。
+/// 最後の追記から `grace` が経っていないセグメントは書き直さない。
+#[instrument(skip(store))]
+pub async fn compact(
+    store: &MetadataStore,
+    root: &Path,
+    grace: Duration,
+) -> Result<CompactionSummary> {
+    let mut summary = CompactionSummary::default();
+    let mut rewritten = Vec::new();
+    for segment in list_segments(root).await? {
+        match compact_segment(store, &segment, grace).await {
+            Ok(Some((moved, reclaimed))) => {
+                summary.segments += 1;
+                summary.moved_shards += moved;
+                summary.reclaimed_bytes += reclaimed;
+                rewritten.push(segment);
+            }
+            Ok(None) => {}
+            Err(e) => error!("Failed to compact {:?}: {}", segment, e),
+        }
+    }
+    if rewritten.is_empty() {
+        return Ok(summary);
+    }
+
+    // 書き直す前の位置を読み始めたGETが終わるまで待ってから消す
+    tokio::time::sleep(grace).await;
+    for segment in rewritten {
+        store
+            .delete_pack_entries(&segment.to_string_lossy())
+            .await?;
+        if let Err(e) = fs::remove_file(&segment).await {
+            error!("Failed to remove {:?}: {}", segment, e);
+        }
+        info!("Removed segment {:?}", segment);
+    }
+    Ok(summary)
+}
+
+async fn list_segments(root: &Path) -> Result<Vec<PathBuf>> {
+    let mut segments = Vec::new();
+    for i in 1..=NUM_OUTPUT_DIRS {
+        let dir = root.join(format!("{}{}", OUTPUT_DIR_PREFIX, i));
+        let mut entries = match fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == SEGMENT_EXTENSION) {
+                segments.push(path);
+            }
+        }
+    }
+    Ok(segments)
+}
+
+async fn is_idle(segment: &Path, grace: Duration) -> Result<bool> {
+    let modified = fs::metadata(segment).await?.modified()?;
+    Ok(modified.elapsed().unwrap_or_default() >= grace)
+}
+
+/// 生きているシャードの割合が少なければ、それらを追記中のセグメントに移して `Some((移したシャード数, 回収できるバイト数))` を返す。
+async fn compact_segment(
+    store: &MetadataStore,
+    segment: &Path,
+    grace: Duration,
+) -> Result<Option<(u64, u64)>> {
+    if !is_idle(segment, grace).await? {
+        return Ok(None);
+    }
+    seal(segment).await;
+    // 封じる直前に追記されていたら、まだ位置が登録されていないかもしれない
+    if !is_idle(segment, grace).await? {
+        return Ok(None);
+    }
+
+    let name = segment.to_string_lossy();
+    let size = fs::metadata(segment).await?.len();
+    let live = store.live_pack_entries(&name).await?;
+    let live_bytes: u64 = live.iter().map(|entry| entry.length as u64).sum();
+    if size > 0 && live_bytes as f64 >= size as f64 * COMPACTION_LIVE_RATIO {
+        return Ok(None);
+    }
+
+    info!(
+        "Compacting {:?}: {} of {} bytes are live.",
+        segment, live_bytes, size
+    );
+    let dir = segment.parent().context("segment has no directory")?;
+    let mut file = File::open(segment).await?;
+    let mut moved = 0;
+    for entry in live {
+        let mut content = vec![0u8; entry.length as usize];
+        file.seek(SeekFrom::Start(entry.position as u64)).await?;
+        file.read_exact(&mut content).await?;
+        let (new_segment, position) = append(dir, &content).await?;
+        // 移している間にスクラブで作り直された場合は、新しい位置をそのまま使う
+        if store
+            .move_pack_entry(&entry, &new_segment.to_string_lossy(), position as i64)
+            .await?
+        {
+            moved += 1;
+        }
+    }
+    Ok(Some((moved, size - live_bytes)))
+}
+
+/// `interval` ごとに `root` の下のセグメントのコンパクションを実行し続ける。
+#[instrument(skip(store))]
+pub async fn run(store: MetadataStore, root: PathBuf, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        info!("Starting compaction.");
+        match compact(&store, &root, COMPACTION_GRACE).await {
+            Ok(summary) => info!(
+                "Compaction finished: rewrote {} segments, moved {} shards, reclaimed {} bytes",
+                summary.segments, summary.moved_shards, summary.reclaimed_bytes
+            ),
+            Err(e) => error!("Compaction failed: {}", e),
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use anyhow::{Result, bail};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+
+// シャードの置き場所(ディレクトリやストレージノード)を決める。
+// キーと置き場所の名前から計算したスコアの高い順に置き場所を並べる(rendezvous hashing)ので、
+// 置き場所を1つ増減しても、その置き場所に関係しないシャードは動かない。
+// 1つのオブジェクトのシャードは全て別々の置き場所に置き、
+// ゾーンが設定されていればできるだけ別々のゾーンに散らす。置き場所より多いシャードは置けない。
+
+/// シャードの置き場所
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub name: String,
+    // 同時に失われうる置き場所のまとまり(ラック、ホストなど)
+    pub zone: Option<String>,
+}
+
+impl Target {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            zone: None,
+        }
+    }
+
+    pub fn in_zone(name: impl Into<String>, zone: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            zone: Some(zone.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Placement {
+    targets: Vec<Target>,
+}
+
+impl Placement {
+    pub fn new(targets: Vec<Target>) -> Result<Self> {
+        if targets.is_empty() {
+            bail!("no placement targets");
+        }
+        let mut names = HashSet::new();
+        for target in &targets {
+            if !names.insert(target.name.as_str()) {
+                bail!("duplicate placement target: {}", target.name);
+            }
+        }
+        Ok(Self { targets })
+    }
+
+    pub fn targets(&self) -> &[Target] {
+        &self.targets
+    }
+
+    /// `key` のシャード `0..count` を置く置き場所のインデックスを返す。全て別々の置き場所になる。
+    /// `count` が置き場所の数を超えると同じ置き場所に複数のシャードが重なるので、エラーにする。
+    pub fn place(&self, key: &str, count: usize) -> Result<Vec<usize>> {
+        if count > self.targets.len() {
+            bail!(
+                "cannot place {} shards on {} distinct targets",
+                count,
+                self.targets.len()
+            );
+        }
+        let mut ranked: Vec<(u64, usize)> = self
+            .targets
+            .iter()
+            .enumerate()
+            .map(|(i, target)| (score(key, &target.name), i))
+            .collect();
+        // スコアが同じ場合も順序が決まるよう、名前の順で並べる
+        ranked.sort_by(|(a, i), (b, j)| {
+            b.cmp(a)
+                .then_with(|| self.targets[*i].name.cmp(&self.targets[*j].name))
+        });
+
+        // 使ったゾーンの少ない置き場所をスコアの高い順に選ぶ
+        let mut placed = Vec::with_capacity(count);
+        let mut used = vec![false; ranked.len()];
+        let mut zones: HashMap<Option<&str>, usize> = HashMap::new();
+        for _ in 0..count {
+            let (position, _) = ranked
+                .iter()
+                .enumerate()
+                .filter(|(position, _)| !used[*position])
+                .min_by_key(|(position, (_, i))| {
+                    let zone = self.targets[*i].zone.as_deref();
+                    (
+                        zone.map_or(0, |_| zones.get(&zone).copied().unwrap_or(0)),
+                        *position,
+                    )
+                })
+                .expect("an unused target remains");
+            used[position] = true;
+            let i = ranked[position].1;
+            *zones.entry(self.targets[i].zone.as_deref()).or_default() += 1;
+            placed.push(i);
+        }
+        Ok(placed)
+    }
+
+    /// `key` のシャード `index` を置く置き場所のインデックス
+    pub fn target_for(&self, key: &str, index: usize) -> Result<usize> {
+        Ok(self.place(key, index + 1)?[index])
+    }
+}
+
+/// Rustのバージョンによって変わりうる `DefaultHasher` の代わりに、SHA-256の先頭8バイトを使う
+fn score(key: &str, target: &str) -> u64 {
+    let mut hasher = Sha256::new();
+    hasher.update((key.len() as u64).to_be_bytes());
+    hasher.update(key.as_bytes());
+    hasher.update(target.as_bytes());
+    let digest = hasher.finalize();
+    u64::from_be_bytes(digest[..8].try_into().expect("digest is 32 bytes"))
+}
@@ -0,0 +1,109 @@
+use crate::env::{DATA_SHARDS, PARITY_SHARDS, STRIPE_UNIT};
+use crate::shard::{StripeLayout, chunk_len_for};
+use anyhow::{Result, bail};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
+
+// シャード数の上限。シャードヘッダはu16だが、シャードファイルが増えすぎないように抑える
+pub const MAX_TOTAL_SHARDS: usize = 64;
+pub const MIN_STRIPE_UNIT: usize = 4 * 1024;
+pub const MAX_STRIPE_UNIT: usize = 16 * 1024 * 1024;
+
+/// イレイジャーコーディングのパラメータ。バケットごとに設定し、オブジェクトごとに記録する。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErasureProfile {
+    pub data_shards: usize,
+    pub parity_shards: usize,
+    // 1ストライプあたりの各シャードのチャンク長
+    pub stripe_unit: usize,
+}
+
+impl Default for ErasureProfile {
+    fn default() -> Self {
+        Self {
+            data_shards: DATA_SHARDS,
+            parity_shards: PARITY_SHARDS,
+            stripe_unit: STRIPE_UNIT,
+        }
+    }
+}
+
+impl ErasureProfile {
+    pub fn new(data_shards: usize, parity_shards: usize, stripe_unit: usize) -> Result<Self> {
+        if data_shards == 0 || parity_shards == 0 {
+            bail!("data_shards and parity_shards must be at least 1");
+        }
+        if data_shards + parity_shards > MAX_TOTAL_SHARDS {
+            bail!(
+                "data_shards + parity_shards must be at most {}",
+                MAX_TOTAL_SHARDS
+            );
+        }
+        if !(MIN_STRIPE_UNIT..=MAX_STRIPE_UNIT).contains(&stripe_unit) {
+            bail!(
+                "stripe_unit must be between {} and {}",
+                MIN_STRIPE_UNIT,
+                MAX_STRIPE_UNIT
+            );
+        }
+        Ok(Self {
+            data_shards,
+            parity_shards,
+            stripe_unit,
+        })
+    }
+
+    /// メタデータの列から作る。プロファイル導入前の行(NULL)はデフォルトの 6+3 で書かれている。
+    pub fn from_columns(
+        data_shards: Option<i64>,
+        parity_shards: Option<i64>,
+        stripe_unit: Option<i64>,
+    ) -> Self {
+        let default = Self::default();
+        Self {
+            data_shards: data_shards.map_or(default.data_shards, |n| n as usize),
+            parity_shards: parity_shards.map_or(default.parity_shards, |n| n as usize),
+            stripe_unit: stripe_unit.map_or(default.stripe_unit, |n| n as usize),
+        }
+    }
+
+    /// 小さなオブジェクト用の、データシャード1つとパリティシャードからなるプロファイル。
+    /// データシャードが1つの場合、パリティシャードはデータと同じ内容になるので、完全な複製を
+    /// `parity_shards + 1` 個置くことになる。元のプロファイルと同じ数のシャードが失われても読み出せる。
+    pub fn replicated(&self) -> Self {
+        Self {
+            data_shards: 1,
+            ..*self
+        }
+    }
+
+    pub fn is_replicated(&self) -> bool {
+        self.data_shards == 1
+    }
+
+    pub fn total_shards(&self) -> usize {
+        self.data_shards + self.parity_shards
+    }
+
+    pub fn stripe_len(&self) -> usize {
+        self.stripe_unit * self.data_shards
+    }
+
+    pub fn reed_solomon(&self) -> Result<ReedSolomon> {
+        Ok(ReedSolomon::new(self.data_shards, self.parity_shards)?)
+    }
+
+    /// オブジェクト全体を1つのストライプとしてエンコードしたときのプロファイル。
+    /// ストライプに分ける前に書かれたシャードはこの形になっている。1ストライプに収まるオブジェクトなら元のプロファイルと同じ。
+    pub fn unstriped(&self, original_len: u64) -> Self {
+        let chunk_len = chunk_len_for(original_len as usize, self.data_shards);
+        Self {
+            stripe_unit: self.stripe_unit.max(chunk_len),
+            ..*self
+        }
+    }
+
+    pub fn layout(&self, original_len: u64) -> StripeLayout {
+        StripeLayout::new(self.data_shards, self.stripe_unit, original_len)
+    }
+}
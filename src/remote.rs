@@ -0,0 +1,338 @@
+use crate::placement::{Placement, Target};
+use crate::profile::ErasureProfile;
+use crate::store::{ShardStat, ShardStore, ShardUpload, remove_if_exists};
+use anyhow::{Result, bail};
+use bytes::Bytes;
+use futures::future::{BoxFuture, join_all};
+use reqwest::{Body, Client, StatusCode, Url, header};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use tracing::{error, instrument};
+use uuid::Uuid;
+
+// 落ちているノードを待ち続けないよう、接続はこの時間で諦める
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+// 接続した後に応答が止まったノードも、この時間読めなければ諦める
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+// シャードの読み出し・確認・削除は、この時間で終わらなければ諦めてそのシャードを欠損として扱う。
+// 書き込みはシャードの大きさによって時間が変わるので、`READ_TIMEOUT` だけで打ち切る
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// `t3 node` で起動したストレージノードにシャードを置く。
+/// ノードがシャード数以上あれば1つのオブジェクトのシャードは全て別々のノードに置かれ(`Placement`)、
+/// 1つのノードが落ちても失うシャードは1つだけになる。
+#[derive(Debug, Clone)]
+pub struct RemoteShardStore {
+    nodes: Vec<Url>,
+    placement: Placement,
+    client: Client,
+    request_timeout: Duration,
+}
+
+impl RemoteShardStore {
+    /// `nodes` はノードのベースURL(`http://127.0.0.1:9001` など)。
+    /// `zone-a=http://127.0.0.1:9001` のようにゾーンを付けると、シャードをできるだけ別々のゾーンに散らす。
+    pub fn new<S: AsRef<str>>(nodes: &[S]) -> Result<Self> {
+        if nodes.is_empty() {
+            bail!("no storage nodes are configured");
+        }
+        let mut urls = Vec::with_capacity(nodes.len());
+        let mut targets = Vec::with_capacity(nodes.len());
+        for node in nodes {
+            let (zone, url) = split_zone(node.as_ref().trim());
+            let url = Url::parse(url)?;
+            if url.cannot_be_a_base() {
+                bail!("storage node URLs must be http(s) URLs: {}", url);
+            }
+            targets.push(Target {
+                name: url.to_string(),
+                zone: zone.map(str::to_string),
+            });
+            urls.push(url);
+        }
+        let client = Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .read_timeout(READ_TIMEOUT)
+            .build()?;
+        Ok(Self {
+            nodes: urls,
+            placement: Placement::new(targets)?,
+            client,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+        })
+    }
+
+    /// シャードの読み出し・確認・削除を諦めるまでの時間を変える。
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    pub fn nodes(&self) -> &[Url] {
+        &self.nodes
+    }
+
+    /// シャード `index` を置くノード
+    pub fn node_for(&self, object_id: &str, index: usize) -> Result<&Url> {
+        Ok(&self.nodes[self.placement.target_for(object_id, index)?])
+    }
+
+    fn shards_url(node: &Url, object_id: &str) -> Url {
+        let mut url = node.clone();
+        // キーに `/` などが含まれていてもパスの1要素としてエスケープする
+        url.path_segments_mut()
+            .expect("node URL is a base URL")
+            .pop_if_empty()
+            .extend(["shards", object_id]);
+        url
+    }
+
+    fn shard_url(&self, object_id: &str, index: usize) -> Result<Url> {
+        let mut url = Self::shards_url(self.node_for(object_id, index)?, object_id);
+        url.path_segments_mut()
+            .expect("node URL is a base URL")
+            .push(&index.to_string());
+        Ok(url)
+    }
+}
+
+#[derive(Deserialize)]
+struct ListResponse {
+    data: Vec<usize>,
+}
+
+impl ShardStore for RemoteShardStore {
+    fn create<'a>(
+        &'a self,
+        object_id: &'a str,
+        index: usize,
+    ) -> BoxFuture<'a, Result<Box<dyn ShardUpload>>> {
+        Box::pin(async move {
+            let url = self.shard_url(object_id, index)?;
+            // シャードの長さはエンコードし終わるまで分からないので、ローカルの一時ファイルに書いてから送る
+            let temp_path =
+                std::env::temp_dir().join(format!("t3-upload-{}.tmp", Uuid::new_v4().simple()));
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&temp_path)
+                .await?;
+            Ok(Box::new(RemoteUpload {
+                client: self.client.clone(),
+                url,
+                temp_path,
+                file,
+            }) as Box<dyn ShardUpload>)
+        })
+    }
+
+    fn get<'a>(
+        &'a self,
+        object_id: &'a str,
+        index: usize,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, Result<Bytes>> {
+        Box::pin(async move {
+            if range.is_empty() {
+                return Ok(Bytes::new());
+            }
+            let response = self
+                .client
+                .get(self.shard_url(object_id, index)?)
+                .header(
+                    header::RANGE,
+                    format!("bytes={}-{}", range.start, range.end - 1),
+                )
+                .timeout(self.request_timeout)
+                .send()
+                .await?
+                .error_for_status()?;
+            if response.status() != StatusCode::PARTIAL_CONTENT {
+                bail!("node did not return the requested range {:?}", range);
+            }
+            let data = response.bytes().await?;
+            if data.len() as u64 != range.end - range.start {
+                bail!(
+                    "range {:?} is out of bounds: got {} bytes",
+                    range,
+                    data.len()
+                );
+            }
+            Ok(data)
+        })
+    }
+
+    fn delete<'a>(&'a self, object_id: &'a str, index: usize) -> BoxFuture<'a, Result<bool>> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .delete(self.shard_url(object_id, index)?)
+                .timeout(self.request_timeout)
+                .send()
+                .await?;
+            match response.status() {
+                StatusCode::NOT_FOUND => Ok(false),
+                _ => {
+                    response.error_for_status()?;
+                    Ok(true)
+                }
+            }
+        })
+    }
+
+    fn stat<'a>(
+        &'a self,
+        object_id: &'a str,
+        index: usize,
+    ) -> BoxFuture<'a, Result<Option<ShardStat>>> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .head(self.shard_url(object_id, index)?)
+                .timeout(self.request_timeout)
+                .send()
+                .await?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            let response = response.error_for_status()?;
+            let Some(len) = response
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok())
+            else {
+                bail!("node returned no content-length");
+            };
+            Ok(Some(ShardStat { len }))
+        })
+    }
+
+    fn list<'a>(&'a self, object_id: &'a str) -> BoxFuture<'a, Result<Vec<usize>>> {
+        Box::pin(async move {
+            let responses = join_all(self.nodes.iter().map(|node| async move {
+                let response = self
+                    .client
+                    .get(Self::shards_url(node, object_id))
+                    .timeout(self.request_timeout)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                let list: ListResponse = serde_json::from_slice(&response.bytes().await?)?;
+                anyhow::Ok(list.data)
+            }))
+            .await;
+            // 応答しないノードのシャードは無いものとして扱う
+            let mut indices = BTreeSet::new();
+            for (node, response) in self.nodes.iter().zip(responses) {
+                match response {
+                    Ok(found) => indices.extend(found),
+                    Err(e) => error!("Failed to list shards on {}: {}", node, e),
+                }
+            }
+            Ok(indices.into_iter().collect())
+        })
+    }
+
+    fn flush<'a>(
+        &'a self,
+        _object_id: &'a str,
+        _indices: &'a [usize],
+    ) -> BoxFuture<'a, Result<()>> {
+        // ノードはPUTに応答する前にシャードを永続化している
+        Box::pin(async { Ok(()) })
+    }
+
+    fn max_shards(&self) -> Option<usize> {
+        Some(self.nodes.len())
+    }
+
+    /// データシャード数と過半数の大きい方。残りのシャードはスクラブで作り直す。
+    fn write_quorum(&self, profile: ErasureProfile) -> usize {
+        profile
+            .data_shards
+            .max(profile.total_shards() / 2 + 1)
+            .min(profile.total_shards())
+    }
+}
+
+struct RemoteUpload {
+    client: Client,
+    url: Url,
+    temp_path: PathBuf,
+    file: File,
+}
+
+impl RemoteUpload {
+    #[instrument(skip(self), fields(url = %self.url))]
+    async fn send(&mut self) -> Result<()> {
+        self.file.seek(SeekFrom::Start(0)).await?;
+        let file = self.file.try_clone().await?;
+        let len = file.metadata().await?.len();
+        self.client
+            .put(self.url.clone())
+            .header(header::CONTENT_LENGTH, len)
+            .body(Body::wrap_stream(ReaderStream::new(file)))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+impl ShardUpload for RemoteUpload {
+    fn write_at<'a>(&'a mut self, offset: u64, data: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.file.seek(SeekFrom::Start(offset)).await?;
+            self.file.write_all(data).await?;
+            Ok(())
+        })
+    }
+
+    fn sync(&mut self) -> BoxFuture<'_, Result<()>> {
+        // ノードに送るまでの一時ファイルなので永続化しない
+        Box::pin(async move { Ok(self.file.flush().await?) })
+    }
+
+    fn commit(mut self: Box<Self>) -> BoxFuture<'static, Result<()>> {
+        Box::pin(async move {
+            let result = self.send().await;
+            drop(self.file);
+            remove_if_exists(&self.temp_path).await;
+            result
+        })
+    }
+
+    fn abort(self: Box<Self>) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            drop(self.file);
+            remove_if_exists(&self.temp_path).await;
+        })
+    }
+}
+
+/// `zone=url` をゾーンとURLに分ける。
+fn split_zone(node: &str) -> (Option<&str>, &str) {
+    match node.split_once('=') {
+        Some((zone, url)) if !zone.contains("://") => (Some(zone.trim()), url.trim()),
+        _ => (None, node),
+    }
+}
+
+/// ノードのURLをカンマ区切りで並べた文字列(環境変数 `NODES`)を分割する。
+pub fn parse_nodes(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|node| !node.is_empty())
+        .map(str::to_string)
+        .collect()
+}
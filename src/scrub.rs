@@ -0,0 +1,450 @@
+use crate::db::{MetadataStore, ObjectMetadata, ObjectPart};
+use crate::decode::ShardReader;
+use crate::encode::{ShardWriter, remove_shards};
+use crate::pack::{self, PackKey};
+use crate::profile::ErasureProfile;
+use crate::store::SharedShardStore;
+use anyhow::{Context, Result, bail};
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashSet};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use tracing::{error, info, instrument, warn};
+
+// 1回のクエリで読み込むオブジェクトの数
+const BATCH_SIZE: i64 = 100;
+
+// 後に並べたものほど悪い状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Health {
+    /// 全てのシャードが揃っている
+    Healthy,
+    /// 壊れたシャードを作り直した
+    Repaired,
+    /// 読み出せるが、壊れたシャードを作り直せなかった
+    Degraded,
+    /// 復元できない
+    Lost,
+}
+
+impl Health {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Health::Healthy => "healthy",
+            Health::Repaired => "repaired",
+            Health::Degraded => "degraded",
+            Health::Lost => "lost",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScrubReport {
+    pub health: Health,
+    // 欠けていた、または壊れていたシャードのインデックス
+    pub damaged_shards: Vec<usize>,
+}
+
+#[derive(Debug, Default)]
+pub struct ScrubSummary {
+    pub scanned: u64,
+    pub repaired: u64,
+    pub degraded: u64,
+    pub lost: u64,
+}
+
+/// 1つのオブジェクトの全てのシャードを検査し、欠けたシャードや壊れたシャードがあれば作り直す。
+/// 作り直したシャードは `shards` の同じ位置に置き換える。
+#[instrument(skip(shards))]
+pub async fn scrub_object(
+    shards: &SharedShardStore,
+    shard_id: &str,
+    content_length: u64,
+    profile: ErasureProfile,
+    expected_sha256: Option<&str>,
+) -> ScrubReport {
+    let mut reader = match ShardReader::open(shards, shard_id, content_length, profile).await {
+        Ok(reader) => reader,
+        Err(e) => return lost(e),
+    };
+    let damaged_shards = find_damaged(&mut reader).await;
+    if damaged_shards.is_empty() {
+        return healthy();
+    }
+
+    warn!("Damaged shards found: {:?}", damaged_shards);
+    let result = repair(
+        shards,
+        &mut reader,
+        shard_id,
+        &damaged_shards,
+        expected_sha256,
+    )
+    .await;
+    report(result, &mut reader, damaged_shards, expected_sha256).await
+}
+
+/// パックファイルに置いたオブジェクトをスクラブする。
+/// 作り直したシャードはセグメントに追記し直し、シャードの位置を付け替える。
+#[instrument(skip(store, shards, object), fields(object_id = %object.object_id))]
+pub async fn scrub_packed_object(
+    store: &MetadataStore,
+    shards: &SharedShardStore,
+    object: &ObjectMetadata,
+    content_length: u64,
+) -> ScrubReport {
+    let expected_sha256 = object.sha256.as_deref();
+    let mut reader = match pack::open_reader(store, shards, object, content_length).await {
+        Ok(reader) => reader,
+        Err(e) => return lost(e),
+    };
+    let damaged_shards = find_damaged(&mut reader).await;
+    if damaged_shards.is_empty() {
+        return healthy();
+    }
+
+    warn!("Damaged shards found: {:?}", damaged_shards);
+    let result = async {
+        let mut data = Vec::with_capacity(content_length as usize);
+        for stripe in 0..reader.stripe_count() {
+            data.extend_from_slice(&reader.read_stripe(stripe).await?);
+        }
+        if let Some(expected) = expected_sha256
+            && hex::encode(Sha256::digest(&data)) != expected
+        {
+            bail!("reconstructed data does not match the stored digest");
+        }
+        let generation = object
+            .generation
+            .as_deref()
+            .context("packed object has no generation")?;
+        let root = shards
+            .segment_root()
+            .context("shard store has no local directory for segments")?;
+        let key = PackKey {
+            bucket_name: &object.bucket_name,
+            object_id: &object.object_id,
+            generation,
+            shard_id: &object.shard_id(),
+        };
+        let entries =
+            pack::write_shards(root, key, reader.profile(), &data, &damaged_shards).await?;
+        store.put_pack_entries(&entries).await?;
+        anyhow::Ok(())
+    }
+    .await;
+    report(result, &mut reader, damaged_shards, expected_sha256).await
+}
+
+fn healthy() -> ScrubReport {
+    ScrubReport {
+        health: Health::Healthy,
+        damaged_shards: Vec::new(),
+    }
+}
+
+fn lost(e: anyhow::Error) -> ScrubReport {
+    error!("Object is lost: {}", e);
+    ScrubReport {
+        health: Health::Lost,
+        damaged_shards: Vec::new(),
+    }
+}
+
+/// 全てのストライプを検査し、開けなかったシャードと壊れたチャンクを含むシャードのインデックスを返す。
+async fn find_damaged(reader: &mut ShardReader) -> Vec<usize> {
+    let mut damaged: BTreeSet<usize> = reader.unavailable_shards().into_iter().collect();
+    for stripe in 0..reader.stripe_count() {
+        damaged.extend(reader.verify_stripe(stripe).await);
+    }
+    damaged.into_iter().collect()
+}
+
+async fn report(
+    repaired: Result<()>,
+    reader: &mut ShardReader,
+    damaged_shards: Vec<usize>,
+    expected_sha256: Option<&str>,
+) -> ScrubReport {
+    let health = match repaired {
+        Ok(()) => {
+            info!("Repaired shards {:?}", damaged_shards);
+            Health::Repaired
+        }
+        Err(e) => {
+            error!("Failed to repair shards {:?}: {}", damaged_shards, e);
+            // どのストライプも復元できるなら、読み出しはできる
+            if readable(reader, expected_sha256).await {
+                Health::Degraded
+            } else {
+                Health::Lost
+            }
+        }
+    };
+    ScrubReport {
+        health,
+        damaged_shards,
+    }
+}
+
+// read-repairを実行中のシャードID。同じオブジェクトへのGETが続いても修復は1つだけ走らせる
+static READ_REPAIRS: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(Default::default);
+
+/// read-repairで作り直すシャードを持つオブジェクト(マルチパートオブジェクトならパートの持ち主)。
+/// GETの後に削除・上書きされていれば、シャードを作り直しても使われないので残さない。
+#[derive(Clone)]
+pub struct RepairOwner {
+    store: MetadataStore,
+    bucket_name: String,
+    object_id: String,
+    generation: Option<String>,
+    blob_id: String,
+}
+
+impl RepairOwner {
+    pub fn new(store: &MetadataStore, object: &ObjectMetadata) -> Self {
+        Self {
+            store: store.clone(),
+            bucket_name: object.bucket_name.clone(),
+            object_id: object.object_id.clone(),
+            generation: object.generation.clone(),
+            blob_id: object.shard_id(),
+        }
+    }
+
+    async fn in_use(&self) -> Result<bool> {
+        self.store
+            .object_in_use(
+                &self.bucket_name,
+                &self.object_id,
+                self.generation.as_deref(),
+                &self.blob_id,
+            )
+            .await
+    }
+}
+
+/// GETで読めなかったシャードを作り直す(read-repair)。
+/// 修復したシャードごとに `read_repair` ターゲットのトレースイベントを出す。
+#[instrument(skip(shards, expected_sha256, owner))]
+pub async fn read_repair(
+    shards: SharedShardStore,
+    shard_id: String,
+    content_length: u64,
+    profile: ErasureProfile,
+    expected_sha256: Option<String>,
+    damaged_shards: Vec<usize>,
+    owner: RepairOwner,
+) {
+    if !READ_REPAIRS.lock().unwrap().insert(shard_id.clone()) {
+        info!("Read-repair is already running.");
+        return;
+    }
+    let result = async {
+        if !owner.in_use().await? {
+            info!("Object was deleted before read-repair.");
+            return anyhow::Ok(Vec::new());
+        }
+        let mut reader = ShardReader::open(&shards, &shard_id, content_length, profile).await?;
+        let mut damaged: BTreeSet<usize> = damaged_shards.into_iter().collect();
+        damaged.extend(reader.unavailable_shards());
+        let damaged: Vec<usize> = damaged.into_iter().collect();
+        repair(
+            &shards,
+            &mut reader,
+            &shard_id,
+            &damaged,
+            expected_sha256.as_deref(),
+        )
+        .await?;
+        // 修復している間に削除・上書きされた場合、削除する側が先にシャードを消していると
+        // 作り直したシャードだけが残ってしまうので消す
+        if !owner.in_use().await? {
+            info!(
+                "Object was deleted during read-repair. Removing {}",
+                shard_id
+            );
+            remove_shards(&shards, &shard_id, profile).await;
+            return Ok(Vec::new());
+        }
+        Ok(damaged)
+    }
+    .await;
+    READ_REPAIRS.lock().unwrap().remove(&shard_id);
+
+    match result {
+        Ok(repaired) => {
+            for shard in repaired {
+                info!(target: "read_repair", shard_id = %shard_id, shard, "Repaired shard.");
+            }
+        }
+        Err(e) => error!("Read-repair failed: {}", e),
+    }
+}
+
+/// 元データを復元し、`damaged_shards` のシャードだけをエンコードし直して書き込む。
+async fn repair(
+    shards: &SharedShardStore,
+    reader: &mut ShardReader,
+    shard_id: &str,
+    damaged_shards: &[usize],
+    expected_sha256: Option<&str>,
+) -> Result<()> {
+    let mut writer =
+        ShardWriter::create_for_shards(shards, shard_id, reader.profile(), damaged_shards).await?;
+    let written = async {
+        for stripe in 0..reader.stripe_count() {
+            let data = reader.read_stripe(stripe).await?;
+            writer.write(&data).await?;
+        }
+        // 復元したデータが壊れていないことを確かめてからシャードを置き換える
+        if let Some(expected) = expected_sha256
+            && writer.digests().sha256 != expected
+        {
+            bail!("reconstructed data does not match the stored digest");
+        }
+        anyhow::Ok(())
+    }
+    .await;
+    if let Err(e) = written {
+        writer.abort().await;
+        return Err(e);
+    }
+    writer.finish().await?;
+    Ok(())
+}
+
+async fn readable(reader: &mut ShardReader, expected_sha256: Option<&str>) -> bool {
+    let mut hasher = Sha256::new();
+    for stripe in 0..reader.stripe_count() {
+        match reader.read_stripe(stripe).await {
+            Ok(data) => hasher.update(&data),
+            Err(_) => return false,
+        }
+    }
+    let actual = hex::encode(hasher.finalize());
+    expected_sha256.is_none_or(|expected| expected == actual)
+}
+
+/// 全てのオブジェクトをスクラブし、結果をメタデータに記録する。
+#[instrument(skip(store, shards))]
+pub async fn scrub_all(store: &MetadataStore, shards: &SharedShardStore) -> Result<ScrubSummary> {
+    let mut summary = ScrubSummary::default();
+    let mut after_id = 0;
+    loop {
+        let objects = store.list_objects_after_id(after_id, BATCH_SIZE).await?;
+        let Some(last) = objects.last() else {
+            break;
+        };
+        after_id = last.id;
+        for object in objects {
+            let Some(content_length) = object.content_length else {
+                error!(
+                    "content_length is missing in metadata of {}",
+                    object.object_id
+                );
+                continue;
+            };
+            let health = scrub_and_record(store, shards, &object, content_length as u64).await?;
+            summary.scanned += 1;
+            match health {
+                Health::Healthy => {}
+                Health::Repaired => summary.repaired += 1,
+                Health::Degraded => summary.degraded += 1,
+                Health::Lost => summary.lost += 1,
+            }
+        }
+    }
+    Ok(summary)
+}
+
+async fn scrub_and_record(
+    store: &MetadataStore,
+    shards: &SharedShardStore,
+    object: &ObjectMetadata,
+    content_length: u64,
+) -> Result<Health> {
+    let shard_id = object.shard_id();
+    let parts = match object.part_count {
+        Some(_) => store.get_object_parts(&shard_id).await?,
+        None => Vec::new(),
+    };
+    let report = if object.packed {
+        scrub_packed_object(store, shards, object, content_length).await
+    } else if object.part_count.is_some() {
+        scrub_parts(shards, &parts, object.profile()).await
+    } else {
+        scrub_object(
+            shards,
+            &shard_id,
+            content_length,
+            object.profile(),
+            object.sha256.as_deref(),
+        )
+        .await
+    };
+    let recorded = store
+        .update_health(
+            &object.bucket_name,
+            &object.object_id,
+            object.generation.as_deref(),
+            report.health.as_str(),
+            &Utc::now().to_rfc3339(),
+        )
+        .await?;
+    if !recorded
+        && report.health == Health::Repaired
+        && !object.packed
+        && store.count_blob_refs(&shard_id).await? == 0
+    {
+        // スクラブ中に上書き・削除され、リネーム・コピーした先からも参照されていないので、
+        // 作り直したシャードはもう使われない(パックファイルに追記したシャードはコンパクションで回収する)
+        info!("Object was replaced during scrub. Removing {}", shard_id);
+        remove_shards(shards, &shard_id, object.profile()).await;
+        for part in &parts {
+            remove_shards(shards, &part.part_blob_id, object.profile()).await;
+        }
+    }
+    Ok(report.health)
+}
+
+/// マルチパートオブジェクトの全てのパートをスクラブする。状態は最も悪いパートのものにする。
+async fn scrub_parts(
+    shards: &SharedShardStore,
+    parts: &[ObjectPart],
+    profile: ErasureProfile,
+) -> ScrubReport {
+    let mut report = healthy();
+    let mut damaged = BTreeSet::new();
+    for part in parts {
+        let part_report = scrub_object(
+            shards,
+            &part.part_blob_id,
+            part.size as u64,
+            profile,
+            Some(&part.sha256),
+        )
+        .await;
+        report.health = report.health.max(part_report.health);
+        damaged.extend(part_report.damaged_shards);
+    }
+    report.damaged_shards = damaged.into_iter().collect();
+    report
+}
+
+/// `interval` ごとに全てのオブジェクトをスクラブし続ける。
+#[instrument(skip(store, shards))]
+pub async fn run(store: MetadataStore, shards: SharedShardStore, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        info!("Starting scrub.");
+        match scrub_all(&store, &shards).await {
+            Ok(summary) => info!(
+                "Scrub finished: scanned {}, repaired {}, degraded {}, lost {}",
+                summary.scanned, summary.repaired, summary.degraded, summary.lost
+            ),
+            Err(e) => error!("Scrub failed: {}", e),
+        }
+    }
+}
@@ -1,17 +1,32 @@
-use super::handler::{bucket, delete::delete_object, get::get_object, post::post_object};
+use super::handler::{
+    bucket, head::head_object, job::get_job, list::list_objects, multipart as multipart_handler,
+};
 use crate::db::MetadataStore;
+use crate::job::JobRegistry;
+use crate::remote::{self, RemoteShardStore};
+use crate::store::{LocalShardStore, SharedShardStore};
+use crate::{multipart, pack, scrub};
 use anyhow::Result;
 use axum::{
     Router,
-    extract::DefaultBodyLimit,
+    extract::{DefaultBodyLimit, FromRef},
     routing::{get, post, put},
 };
 use std::env;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tower_http::limit::RequestBodyLimitLayer;
 use tracing::{info, instrument};
 
+const DEFAULT_SCRUB_INTERVAL_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_COMPACTION_INTERVAL_SECS: u64 = 60 * 60;
+const DEFAULT_SMALL_OBJECT_THRESHOLD: usize = 64 * 1024;
+const DEFAULT_MULTIPART_UPLOAD_EXPIRY_SECS: u64 = 7 * 24 * 60 * 60;
+// 期限切れのマルチパートアップロードを探す間隔の上限
+const MAX_REAP_INTERVAL_SECS: u64 = 60 * 60;
+
 #[instrument]
 pub async fn run_server() -> Result<()> {
     info!("Starting the object storage server.");
@@ -19,8 +34,76 @@ pub async fn run_server() -> Result<()> {
     dotenvy::dotenv()?;
     let db_path = env::var("DATABASE_URL")?;
     let metadata_store = MetadataStore::new(&db_path).await?;
+    // NODESにストレージノードのURLを指定した場合は、シャードをそれらのノードに分散して置く
+    let nodes = env::var("NODES")
+        .map(|nodes| remote::parse_nodes(&nodes))
+        .unwrap_or_default();
+    let shard_store: SharedShardStore = if nodes.is_empty() {
+        Arc::new(LocalShardStore::new())
+    } else {
+        info!("Placing shards on storage nodes: {:?}", nodes);
+        Arc::new(RemoteShardStore::new(&nodes)?)
+    };
+
+    // SCRUB_INTERVAL_SECS秒ごとにシャードを検査する。0なら検査しない
+    let scrub_interval = match env::var("SCRUB_INTERVAL_SECS") {
+        Ok(secs) => secs.parse()?,
+        Err(_) => DEFAULT_SCRUB_INTERVAL_SECS,
+    };
+    if scrub_interval > 0 {
+        info!("Scrubbing shards every {} seconds.", scrub_interval);
+        tokio::spawn(scrub::run(
+            metadata_store.clone(),
+            shard_store.clone(),
+            Duration::from_secs(scrub_interval),
+        ));
+    }
+
+    // COMPACTION_INTERVAL_SECS秒ごとにパックファイルをコンパクションする。0ならしない
+    let compaction_interval = match env::var("COMPACTION_INTERVAL_SECS") {
+        Ok(secs) => secs.parse()?,
+        Err(_) => DEFAULT_COMPACTION_INTERVAL_SECS,
+    };
+    // セグメントはローカルのファイルなので、ストレージノードを使う場合はパックもコンパクションもしない
+    if compaction_interval > 0
+        && let Some(root) = shard_store.segment_root()
+    {
+        info!("Compacting segments every {} seconds.", compaction_interval);
+        tokio::spawn(pack::run(
+            metadata_store.clone(),
+            root.to_path_buf(),
+            Duration::from_secs(compaction_interval),
+        ));
+    }
+
+    // MULTIPART_UPLOAD_EXPIRY_SECS秒を過ぎても完了・中止されていないマルチパートアップロードを中止する。0ならしない
+    let upload_expiry = match env::var("MULTIPART_UPLOAD_EXPIRY_SECS") {
+        Ok(secs) => secs.parse()?,
+        Err(_) => DEFAULT_MULTIPART_UPLOAD_EXPIRY_SECS,
+    };
+    if upload_expiry > 0 {
+        info!(
+            "Aborting multipart uploads after {} seconds.",
+            upload_expiry
+        );
+        tokio::spawn(multipart::run_reaper(
+            metadata_store.clone(),
+            shard_store.clone(),
+            Duration::from_secs(upload_expiry),
+            Duration::from_secs(upload_expiry.min(MAX_REAP_INTERVAL_SECS)),
+        ));
+    }
 
-    let app = app(metadata_store);
+    let config = ServerConfig {
+        read_repair: env::var("READ_REPAIR").is_ok_and(|v| v == "1" || v == "true"),
+        small_object_threshold: match env::var("SMALL_OBJECT_THRESHOLD") {
+            Ok(bytes) => bytes.parse()?,
+            Err(_) => DEFAULT_SMALL_OBJECT_THRESHOLD,
+        },
+        pack_small_objects: shard_store.segment_root().is_some(),
+    };
+    info!("{:?}", config);
+    let app = app(AppState::new(metadata_store, shard_store, config));
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 8080));
     info!("listening on {}", addr);
@@ -29,8 +112,63 @@ pub async fn run_server() -> Result<()> {
     Ok(())
 }
 
+/// 環境変数で切り替えるサーバーの設定
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+    // GETで欠けたシャードを見つけたら、レスポンスを返した後に作り直す(READ_REPAIR=true)
+    pub read_repair: bool,
+    // これより小さいオブジェクトはシャードに分割せず複製で保存する(SMALL_OBJECT_THRESHOLD、0なら常に分割する)
+    pub small_object_threshold: usize,
+    // 小さなオブジェクトの複製をローカルのパックファイルに追記する。ストレージノードを使う場合はシャードとしてノードに置く
+    pub pack_small_objects: bool,
+}
+
+/// ハンドラで共有する状態。各ハンドラは `State<MetadataStore>` のように必要なものだけを取り出す。
+#[derive(Clone)]
+pub struct AppState {
+    pub store: MetadataStore,
+    pub shards: SharedShardStore,
+    pub jobs: JobRegistry,
+    pub config: ServerConfig,
+}
+
+impl AppState {
+    pub fn new(store: MetadataStore, shards: SharedShardStore, config: ServerConfig) -> Self {
+        Self {
+            store,
+            shards,
+            jobs: JobRegistry::new(),
+            config,
+        }
+    }
+}
+
+impl FromRef<AppState> for ServerConfig {
+    fn from_ref(state: &AppState) -> Self {
+        state.config.clone()
+    }
+}
+
+impl FromRef<AppState> for MetadataStore {
+    fn from_ref(state: &AppState) -> Self {
+        state.store.clone()
+    }
+}
+
+impl FromRef<AppState> for SharedShardStore {
+    fn from_ref(state: &AppState) -> Self {
+        state.shards.clone()
+    }
+}
+
+impl FromRef<AppState> for JobRegistry {
+    fn from_ref(state: &AppState) -> Self {
+        state.jobs.clone()
+    }
+}
+
 #[instrument(skip(state))]
-pub fn app(state: MetadataStore) -> Router {
+pub fn app(state: AppState) -> Router {
     Router::new()
         .merge(object_routes())
         .merge(bucket_routes())
@@ -38,22 +176,30 @@ pub fn app(state: MetadataStore) -> Router {
 }
 
 #[instrument]
-fn object_routes() -> Router<MetadataStore> {
+fn object_routes() -> Router<AppState> {
     Router::new()
+        // キーには `/` を含められる。`?uploads` などが付いたリクエストはマルチパートアップロードとして扱う
         .route(
-            "/bucket/{:bucket_name}/{:object_id}",
-            post(post_object).get(get_object).delete(delete_object),
+            "/bucket/{:bucket_name}/{*object_id}",
+            post(multipart_handler::dispatch_post)
+                .put(multipart_handler::dispatch_put)
+                .get(multipart_handler::dispatch_get)
+                .head(head_object)
+                .delete(multipart_handler::dispatch_delete),
         )
         .layer(DefaultBodyLimit::disable())
         .layer(RequestBodyLimitLayer::new(3 * 1024 * 1024 * 1024))
 }
 
 #[instrument]
-fn bucket_routes() -> Router<MetadataStore> {
+fn bucket_routes() -> Router<AppState> {
     Router::new()
         .route(
             "/bucket/{:bucket_name}",
-            put(bucket::create_bucket).delete(bucket::delete_bucket),
+            put(bucket::create_bucket)
+                .get(list_objects)
+                .delete(bucket::delete_bucket),
         )
         .route("/bucket", get(bucket::list_buckets))
+        .route("/jobs/{:job_id}", get(get_job))
 }
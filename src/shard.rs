@@ -0,0 +1,432 @@
+use crate::profile::ErasureProfile;
+use anyhow::{Result, bail, ensure};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+// シャードファイルのフォーマット(リトルエンディアン)
+//
+// | magic "T3SH" | version u16 | shard_index u16 | data_shards u16 | parity_shards u16 |
+// | stripe_unit u32 | original_len u64 | object_id_len u16 | object_id | crc32c(ここまで) u32 |
+// | chunk_0 | crc32c(chunk_0) u32 | chunk_1 | crc32c(chunk_1) u32 | ... |
+//
+// オブジェクトは `stripe_unit * data_shards` バイトずつのストライプに分割され、
+// 各シャードファイルにはストライプごとのチャンクがチェックサム付きで順番に並ぶ。
+// バージョン2はヘッダのチェックサムがないだけで、後は同じ。
+// バージョン1はストライプに分けず、ペイロード全体のチェックサムをヘッダに持つ。
+//
+// | magic "T3SH" | version u16 | shard_index u16 | data_shards u16 | parity_shards u16 |
+// | original_len u64 | shard_len u64 | crc32c(payload) u32 | object_id_len u16 | object_id |
+// | payload (shard_len bytes) |
+//
+// ヘッダのないファイルはベースラインで書かれたシャードで、ストライプに分けていないペイロードだけを持つ。
+pub const MAGIC: &[u8; 4] = b"T3SH";
+pub const FORMAT_VERSION: u16 = 3;
+pub const CHECKSUM_LEN: usize = 4;
+
+const V1_HEADER_LEN: usize = 4 + 2 + 2 + 2 + 2 + 8 + 8 + 4 + 2;
+const V2_HEADER_LEN: usize = 4 + 2 + 2 + 2 + 2 + 4 + 8 + 2;
+const FIXED_HEADER_LEN: usize = V2_HEADER_LEN + CHECKSUM_LEN;
+
+/// 読み込んだシャードファイルの形式。書き込むときは常に最新の形式になる。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShardFormat {
+    /// ヘッダもチェックサムもないベースラインのシャード。ストライプは1つだけ
+    Legacy,
+    /// ペイロード全体のチェックサムをヘッダに持つ。ストライプは1つだけ
+    V1 {
+        checksum: u32,
+    },
+    /// チャンクごとのチェックサムはあるが、ヘッダにはチェックサムがない
+    V2,
+    V3,
+}
+
+impl ShardFormat {
+    /// チャンクの後ろに付くチェックサムの長さ
+    pub fn trailer_len(&self) -> usize {
+        match self {
+            ShardFormat::Legacy | ShardFormat::V1 { .. } => 0,
+            ShardFormat::V2 | ShardFormat::V3 => CHECKSUM_LEN,
+        }
+    }
+
+    /// `framed` を検証してチャンク本体を返す。チェックサムのない形式はそのまま返す。
+    pub fn read_chunk<'a>(&self, framed: &'a [u8]) -> Result<&'a [u8]> {
+        match self {
+            ShardFormat::Legacy => Ok(framed),
+            ShardFormat::V1 { checksum } => {
+                let actual = crc32c::crc32c(framed);
+                ensure!(
+                    actual == *checksum,
+                    "checksum mismatch: expected {:08x}, got {:08x}",
+                    checksum,
+                    actual
+                );
+                Ok(framed)
+            }
+            ShardFormat::V2 | ShardFormat::V3 => read_chunk(framed),
+        }
+    }
+
+    /// ストライプに分けて書いた形式かどうか。そうでなければオブジェクト全体が1つのストライプになっている
+    pub fn is_striped(&self) -> bool {
+        matches!(self, ShardFormat::V2 | ShardFormat::V3)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardHeader {
+    pub object_id: String,
+    pub shard_index: u16,
+    pub data_shards: u16,
+    pub parity_shards: u16,
+    pub stripe_unit: u32,
+    pub original_len: u64,
+    pub format: ShardFormat,
+}
+
+impl ShardHeader {
+    pub fn new(
+        object_id: &str,
+        shard_index: usize,
+        data_shards: usize,
+        parity_shards: usize,
+        stripe_unit: usize,
+        original_len: u64,
+    ) -> Self {
+        Self {
+            object_id: object_id.to_string(),
+            shard_index: shard_index as u16,
+            data_shards: data_shards as u16,
+            parity_shards: parity_shards as u16,
+            stripe_unit: stripe_unit as u32,
+            original_len,
+            format: ShardFormat::V3,
+        }
+    }
+
+    /// ヘッダのないベースラインのシャードを表すヘッダ。
+    /// オブジェクト全体を1つのストライプとしてエンコードしていたので、`profile` をそれに合わせる。
+    pub fn legacy(
+        object_id: &str,
+        shard_index: usize,
+        profile: ErasureProfile,
+        original_len: u64,
+    ) -> Self {
+        let profile = profile.unstriped(original_len);
+        Self {
+            format: ShardFormat::Legacy,
+            ..Self::new(
+                object_id,
+                shard_index,
+                profile.data_shards,
+                profile.parity_shards,
+                profile.stripe_unit,
+                original_len,
+            )
+        }
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        match self.format {
+            ShardFormat::Legacy => 0,
+            ShardFormat::V1 { .. } => V1_HEADER_LEN + self.object_id.len(),
+            ShardFormat::V2 => V2_HEADER_LEN + self.object_id.len(),
+            ShardFormat::V3 => header_len(&self.object_id),
+        }
+    }
+
+    pub fn layout(&self) -> StripeLayout {
+        StripeLayout::new(
+            self.data_shards as usize,
+            self.stripe_unit as usize,
+            self.original_len,
+        )
+    }
+
+    /// 最新の形式でヘッダを書き込む。
+    pub fn write_to(&self, buf: &mut BytesMut) -> Result<()> {
+        let Ok(id_len) = u16::try_from(self.object_id.len()) else {
+            bail!(
+                "object id is too long for a shard header: {} bytes",
+                self.object_id.len()
+            );
+        };
+        let start = buf.len();
+        buf.put_slice(MAGIC);
+        buf.put_u16_le(FORMAT_VERSION);
+        buf.put_u16_le(self.shard_index);
+        buf.put_u16_le(self.data_shards);
+        buf.put_u16_le(self.parity_shards);
+        buf.put_u32_le(self.stripe_unit);
+        buf.put_u64_le(self.original_len);
+        buf.put_u16_le(id_len);
+        buf.put_slice(self.object_id.as_bytes());
+        let checksum = crc32c::crc32c(&buf[start..]);
+        buf.put_u32_le(checksum);
+        Ok(())
+    }
+
+    pub fn read_from(buf: &mut Bytes) -> Result<Self> {
+        let header = buf.clone();
+        ensure!(
+            buf.remaining() >= V2_HEADER_LEN,
+            "shard header is truncated"
+        );
+        if &buf[..MAGIC.len()] != MAGIC {
+            bail!("invalid shard magic");
+        }
+        buf.advance(MAGIC.len());
+        let version = buf.get_u16_le();
+        if version == 1 {
+            return Self::read_v1(buf);
+        }
+        let format = match version {
+            2 => ShardFormat::V2,
+            3 => ShardFormat::V3,
+            version => bail!("unsupported shard format version: {}", version),
+        };
+        let shard_index = buf.get_u16_le();
+        let data_shards = buf.get_u16_le();
+        let parity_shards = buf.get_u16_le();
+        let stripe_unit = buf.get_u32_le();
+        let original_len = buf.get_u64_le();
+        let id_len = buf.get_u16_le() as usize;
+        ensure!(buf.remaining() >= id_len, "shard header is truncated");
+        let id = buf.split_to(id_len);
+        if format == ShardFormat::V3 {
+            ensure!(buf.remaining() >= CHECKSUM_LEN, "shard header is truncated");
+            let expected = buf.get_u32_le();
+            let actual = crc32c::crc32c(&header[..V2_HEADER_LEN + id_len]);
+            ensure!(
+                actual == expected,
+                "shard header checksum mismatch: expected {:08x}, got {:08x}",
+                expected,
+                actual
+            );
+        }
+        let object_id = String::from_utf8(id.to_vec())?;
+        ensure!(stripe_unit > 0, "stripe unit must not be zero");
+
+        Ok(Self {
+            object_id,
+            shard_index,
+            data_shards,
+            parity_shards,
+            stripe_unit,
+            original_len,
+            format,
+        })
+    }
+
+    /// バージョン番号より後のバージョン1のヘッダを読む。
+    /// ストライプ単位の代わりにペイロードの長さを持つので、それを1ストライプのチャンク長として扱う。
+    fn read_v1(buf: &mut Bytes) -> Result<Self> {
+        ensure!(
+            buf.remaining() >= V1_HEADER_LEN - MAGIC.len() - 2,
+            "shard header is truncated"
+        );
+        let shard_index = buf.get_u16_le();
+        let data_shards = buf.get_u16_le();
+        let parity_shards = buf.get_u16_le();
+        let original_len = buf.get_u64_le();
+        let shard_len = buf.get_u64_le();
+        let checksum = buf.get_u32_le();
+        let id_len = buf.get_u16_le() as usize;
+        ensure!(buf.remaining() >= id_len, "shard header is truncated");
+        let object_id = String::from_utf8(buf.split_to(id_len).to_vec())?;
+        ensure!(data_shards > 0, "data shards must not be zero");
+        let expected = chunk_len_for(original_len as usize, data_shards as usize) as u64;
+        ensure!(
+            shard_len == expected,
+            "shard length mismatch: expected {}, got {}",
+            expected,
+            shard_len
+        );
+
+        Ok(Self {
+            object_id,
+            shard_index,
+            data_shards,
+            parity_shards,
+            stripe_unit: u32::try_from(shard_len)?,
+            original_len,
+            format: ShardFormat::V1 { checksum },
+        })
+    }
+
+    /// ヘッダが期待するシャードのものかどうかを確認する。
+    pub fn validate(
+        &self,
+        object_id: &str,
+        shard_index: usize,
+        data_shards: usize,
+        parity_shards: usize,
+    ) -> Result<()> {
+        ensure!(
+            self.object_id == object_id,
+            "object id mismatch: {}",
+            self.object_id
+        );
+        ensure!(
+            self.shard_index as usize == shard_index,
+            "shard index mismatch: {}",
+            self.shard_index
+        );
+        ensure!(
+            self.data_shards as usize == data_shards
+                && self.parity_shards as usize == parity_shards,
+            "shard count mismatch: {}+{}",
+            self.data_shards,
+            self.parity_shards
+        );
+        Ok(())
+    }
+}
+
+/// `object_id` のシャードのヘッダ長(最新の形式)
+pub fn header_len(object_id: &str) -> usize {
+    FIXED_HEADER_LEN + object_id.len()
+}
+
+/// `prefix` で始まるシャードの、`object_id` のヘッダ長。
+/// バージョン1のヘッダは最新の形式より長いので、先頭を読んでから残りを読むときに使う。
+pub fn header_len_of(prefix: &[u8], object_id: &str) -> usize {
+    match prefix.get(MAGIC.len()..MAGIC.len() + 2) {
+        Some([1, 0]) => V1_HEADER_LEN + object_id.len(),
+        _ => header_len(object_id),
+    }
+}
+
+/// オブジェクトのストライプ分割を表す。
+/// 最後のストライプ以外のチャンク長は `stripe_unit` で、最後のストライプだけ残りのデータに合わせて短くなる。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StripeLayout {
+    pub data_shards: usize,
+    pub stripe_unit: usize,
+    pub original_len: u64,
+}
+
+impl StripeLayout {
+    pub fn new(data_shards: usize, stripe_unit: usize, original_len: u64) -> Self {
+        Self {
+            data_shards,
+            stripe_unit,
+            original_len,
+        }
+    }
+
+    /// 1ストライプに含まれる元データのバイト数(最後のストライプを除く)
+    pub fn stripe_len(&self) -> usize {
+        self.stripe_unit * self.data_shards
+    }
+
+    pub fn stripe_count(&self) -> usize {
+        // 0バイトのオブジェクトでも1ストライプは書き込む
+        (self.original_len.div_ceil(self.stripe_len() as u64) as usize).max(1)
+    }
+
+    /// ストライプ `stripe` に含まれる元データのバイト数
+    pub fn stripe_data_len(&self, stripe: usize) -> usize {
+        let start = stripe as u64 * self.stripe_len() as u64;
+        self.original_len
+            .saturating_sub(start)
+            .min(self.stripe_len() as u64) as usize
+    }
+
+    /// ストライプ `stripe` の各シャードのチャンク長
+    pub fn chunk_len(&self, stripe: usize) -> usize {
+        chunk_len_for(self.stripe_data_len(stripe), self.data_shards)
+    }
+
+    /// ペイロード先頭からストライプ `stripe` のチャンクまでのバイト数(チェックサムを含む)
+    pub fn chunk_offset(&self, stripe: usize) -> u64 {
+        stripe as u64 * (self.stripe_unit + CHECKSUM_LEN) as u64
+    }
+
+    /// チェックサムを除いたシャードの長さ
+    pub fn shard_len(&self) -> u64 {
+        let count = self.stripe_count();
+        (count - 1) as u64 * self.stripe_unit as u64 + self.chunk_len(count - 1) as u64
+    }
+}
+
+/// `data_len` バイトのストライプを `data_shards` 個に分けたときのチャンク長
+pub fn chunk_len_for(data_len: usize, data_shards: usize) -> usize {
+    // 空のシャードはエンコードできないので、最低1バイトは確保する
+    data_len.div_ceil(data_shards).max(1)
+}
+
+/// チャンクにチェックサムを付けて `buf` に書き込む。
+pub fn write_chunk(chunk: &[u8], buf: &mut BytesMut) {
+    buf.put_slice(chunk);
+    buf.put_u32_le(crc32c::crc32c(chunk));
+}
+
+/// チェックサム付きのチャンクを検証してチャンク本体を返す。
+pub fn read_chunk(framed: &[u8]) -> Result<&[u8]> {
+    ensure!(framed.len() >= CHECKSUM_LEN, "chunk is truncated");
+    let (chunk, mut checksum) = framed.split_at(framed.len() - CHECKSUM_LEN);
+    let expected = checksum.get_u32_le();
+    let actual = crc32c::crc32c(chunk);
+    ensure!(
+        actual == expected,
+        "checksum mismatch: expected {:08x}, got {:08x}",
+        expected,
+        actual
+    );
+    Ok(chunk)
+}
+
+/// ヘッダとチャンクを並べてシャードファイルの中身を作る。
+/// `payload` はストライプごとのチャンクを連結したもの。
+pub fn encode_shard(header: &ShardHeader, payload: &[u8]) -> Result<BytesMut> {
+    let layout = header.layout();
+    ensure!(
+        payload.len() as u64 == layout.shard_len(),
+        "shard length mismatch: expected {}, got {}",
+        layout.shard_len(),
+        payload.len()
+    );
+    let mut buf = BytesMut::with_capacity(
+        header.encoded_len() + payload.len() + layout.stripe_count() * CHECKSUM_LEN,
+    );
+    header.write_to(&mut buf)?;
+    let mut offset = 0;
+    for stripe in 0..layout.stripe_count() {
+        let len = layout.chunk_len(stripe);
+        write_chunk(&payload[offset..offset + len], &mut buf);
+        offset += len;
+    }
+    Ok(buf)
+}
+
+/// シャードファイルの中身を検証してヘッダとペイロードを返す。
+/// ヘッダが期待するシャードと一致しない場合やチェックサムが合わない場合はエラーになる。
+pub fn decode_shard(
+    content: Bytes,
+    object_id: &str,
+    shard_index: usize,
+    data_shards: usize,
+    parity_shards: usize,
+) -> Result<(ShardHeader, BytesMut)> {
+    let mut buf = content;
+    let header = ShardHeader::read_from(&mut buf)?;
+    header.validate(object_id, shard_index, data_shards, parity_shards)?;
+
+    let layout = header.layout();
+    let trailer_len = header.format.trailer_len();
+    let expected = layout.shard_len() + (layout.stripe_count() * trailer_len) as u64;
+    ensure!(
+        buf.len() as u64 == expected,
+        "shard length mismatch: expected {}, got {}",
+        expected,
+        buf.len()
+    );
+    let mut payload = BytesMut::with_capacity(layout.shard_len() as usize);
+    for stripe in 0..layout.stripe_count() {
+        let framed = buf.split_to(layout.chunk_len(stripe) + trailer_len);
+        payload.extend_from_slice(header.format.read_chunk(&framed)?);
+    }
+
+    Ok((header, payload))
+}
@@ -0,0 +1,502 @@
+use crate::env::{NUM_OUTPUT_DIRS, OUTPUT_DIR_PREFIX};
+use crate::profile::ErasureProfile;
+use crate::{legacy_output_dir_name, output_dir_name, shard_filename};
+use anyhow::{Result, bail};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::SeekFrom;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::error;
+use uuid::Uuid;
+
+/// ハンドラで共有するシャードの保存先
+pub type SharedShardStore = Arc<dyn ShardStore>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShardStat {
+    pub len: u64,
+}
+
+/// シャードの保存先。シャードはシャードIDとシャードインデックスで指定する。
+pub trait ShardStore: Send + Sync + Debug {
+    /// シャードを書き込む一時領域を作る。`ShardUpload::commit` するまで他のメソッドからは見えない。
+    fn create<'a>(
+        &'a self,
+        object_id: &'a str,
+        index: usize,
+    ) -> BoxFuture<'a, Result<Box<dyn ShardUpload>>>;
+
+    /// シャードの `range` の部分を読む。
+    fn get<'a>(
+        &'a self,
+        object_id: &'a str,
+        index: usize,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, Result<Bytes>>;
+
+    /// シャードを削除する。シャードがなかった場合は `false` を返す。
+    fn delete<'a>(&'a self, object_id: &'a str, index: usize) -> BoxFuture<'a, Result<bool>>;
+
+    fn stat<'a>(
+        &'a self,
+        object_id: &'a str,
+        index: usize,
+    ) -> BoxFuture<'a, Result<Option<ShardStat>>>;
+
+    /// 保存されているシャードのインデックスを昇順で返す。
+    fn list<'a>(&'a self, object_id: &'a str) -> BoxFuture<'a, Result<Vec<usize>>>;
+
+    /// commitしたシャードを永続化する。
+    fn flush<'a>(&'a self, object_id: &'a str, indices: &'a [usize]) -> BoxFuture<'a, Result<()>>;
+
+    /// パックファイル(セグメント)を置くディレクトリ。`outputs/outputN` はこの下に作る。
+    /// セグメントはこのトレイトを通さず直接ファイルとして追記・読み出しするので、
+    /// ローカルのファイルシステムにシャードを置くストアだけが返す。`None` なら小さなオブジェクトもシャードとして置く。
+    fn segment_root(&self) -> Option<&Path> {
+        None
+    }
+
+    /// 1つのオブジェクトのシャードを全て別々に置ける置き場所の数。`None` なら上限はない。
+    fn max_shards(&self) -> Option<usize> {
+        None
+    }
+
+    /// `profile` のオブジェクトを書き込むときに、commitに成功しなければならないシャードの数。
+    /// これ未満しか書けなかった場合は書き込み全体を失敗にする。
+    fn write_quorum(&self, profile: ErasureProfile) -> usize {
+        profile.total_shards()
+    }
+
+    /// シャードを丸ごと書き込む。
+    fn put<'a>(
+        &'a self,
+        object_id: &'a str,
+        index: usize,
+        data: Bytes,
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let mut upload = self.create(object_id, index).await?;
+            let written = async {
+                upload.write_at(0, &data).await?;
+                upload.sync().await
+            }
+            .await;
+            if let Err(e) = written {
+                upload.abort().await;
+                return Err(e);
+            }
+            upload.commit().await?;
+            self.flush(object_id, &[index]).await
+        })
+    }
+
+    /// シャード全体を読む。シャードがなければ `None` を返す。
+    fn get_all<'a>(
+        &'a self,
+        object_id: &'a str,
+        index: usize,
+    ) -> BoxFuture<'a, Result<Option<Bytes>>> {
+        Box::pin(async move {
+            let Some(stat) = self.stat(object_id, index).await? else {
+                return Ok(None);
+            };
+            self.get(object_id, index, 0..stat.len).await.map(Some)
+        })
+    }
+}
+
+/// 書き込み中のシャード
+pub trait ShardUpload: Send {
+    fn write_at<'a>(&'a mut self, offset: u64, data: &'a [u8]) -> BoxFuture<'a, Result<()>>;
+
+    /// 書き込んだ内容を永続化する。
+    fn sync(&mut self) -> BoxFuture<'_, Result<()>>;
+
+    /// シャードを読めるようにする。同じシャードが既にあれば置き換える。
+    fn commit(self: Box<Self>) -> BoxFuture<'static, Result<()>>;
+
+    /// 書きかけの内容を捨てる。
+    fn abort(self: Box<Self>) -> BoxFuture<'static, ()>;
+}
+
+/// ローカルファイルシステムの `outputs/outputN` にシャードを1つずつファイルとして置く。
+/// 書き込み中は `{シャードファイル名}.{uuid}.tmp` に書き、commitでリネームする。
+#[derive(Debug, Clone, Default)]
+pub struct LocalShardStore {
+    // `outputs` を置くディレクトリ。空ならカレントディレクトリ
+    root: PathBuf,
+}
+
+impl LocalShardStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `root` の下の `outputs/outputN` にシャードを置く。
+    pub fn with_root(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn filepath(&self, object_id: &str, index: usize) -> Result<PathBuf> {
+        Ok(self
+            .root
+            .join(output_dir_name(object_id, index)?)
+            .join(shard_filename(object_id, index)))
+    }
+
+    /// 配置を決める前の置き場所が今の置き場所と違えば、そのパス
+    fn legacy_filepath(&self, object_id: &str, index: usize) -> Result<Option<PathBuf>> {
+        let legacy = legacy_output_dir_name(object_id, index);
+        Ok((legacy != output_dir_name(object_id, index)?).then(|| {
+            self.root
+                .join(legacy)
+                .join(shard_filename(object_id, index))
+        }))
+    }
+
+    /// 読み出すシャードのパス。今の置き場所になく、古い置き場所にあればそちらを返す
+    async fn existing_filepath(&self, object_id: &str, index: usize) -> Result<PathBuf> {
+        let filepath = self.filepath(object_id, index)?;
+        if let Some(legacy) = self.legacy_filepath(object_id, index)?
+            && !fs::try_exists(&filepath).await?
+            && fs::try_exists(&legacy).await?
+        {
+            return Ok(legacy);
+        }
+        Ok(filepath)
+    }
+}
+
+async fn remove_file(path: &Path) -> Result<bool> {
+    match fs::remove_file(path).await {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl ShardStore for LocalShardStore {
+    fn segment_root(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+
+    fn max_shards(&self) -> Option<usize> {
+        Some(NUM_OUTPUT_DIRS)
+    }
+
+    fn create<'a>(
+        &'a self,
+        object_id: &'a str,
+        index: usize,
+    ) -> BoxFuture<'a, Result<Box<dyn ShardUpload>>> {
+        Box::pin(async move {
+            let filepath = self.filepath(object_id, index)?;
+            // ディレクトリは書き込むときだけ作る
+            if let Some(dir) = filepath.parent() {
+                fs::create_dir_all(dir).await?;
+            }
+            let temp_path = temp_path(&filepath, &Uuid::new_v4().simple().to_string());
+            let file = File::create(&temp_path).await?;
+            Ok(Box::new(LocalUpload {
+                filepath,
+                temp_path,
+                file,
+            }) as Box<dyn ShardUpload>)
+        })
+    }
+
+    fn get<'a>(
+        &'a self,
+        object_id: &'a str,
+        index: usize,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, Result<Bytes>> {
+        Box::pin(async move {
+            let filepath = self.existing_filepath(object_id, index).await?;
+            read_range(&filepath, range).await
+        })
+    }
+
+    fn delete<'a>(&'a self, object_id: &'a str, index: usize) -> BoxFuture<'a, Result<bool>> {
+        Box::pin(async move {
+            let mut removed = remove_file(&self.filepath(object_id, index)?).await?;
+            // 作り直す前のシャードが古い置き場所に残っていれば一緒に消す
+            if let Some(legacy) = self.legacy_filepath(object_id, index)? {
+                removed |= remove_file(&legacy).await?;
+            }
+            Ok(removed)
+        })
+    }
+
+    fn stat<'a>(
+        &'a self,
+        object_id: &'a str,
+        index: usize,
+    ) -> BoxFuture<'a, Result<Option<ShardStat>>> {
+        Box::pin(async move {
+            match fs::metadata(self.existing_filepath(object_id, index).await?).await {
+                Ok(metadata) => Ok(Some(ShardStat {
+                    len: metadata.len(),
+                })),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e.into()),
+            }
+        })
+    }
+
+    fn list<'a>(&'a self, object_id: &'a str) -> BoxFuture<'a, Result<Vec<usize>>> {
+        Box::pin(async move {
+            let prefix = format!("{}_", object_id);
+            let mut indices = Vec::new();
+            for i in 1..=NUM_OUTPUT_DIRS {
+                let dir = self.root.join(format!("{}{}", OUTPUT_DIR_PREFIX, i));
+                let mut entries = match fs::read_dir(&dir).await {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                };
+                while let Some(entry) = entries.next_entry().await? {
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    // `{object_id}_{NN}.bin` 以外(一時ファイルや他のオブジェクト)は数えない
+                    if let Some(index) = name
+                        .strip_prefix(&prefix)
+                        .and_then(|rest| rest.strip_suffix(".bin"))
+                        .and_then(|index| index.parse::<usize>().ok())
+                        && (output_dir_name(object_id, index)
+                            .is_ok_and(|current| self.root.join(current) == dir)
+                            || self.root.join(legacy_output_dir_name(object_id, index)) == dir)
+                    {
+                        indices.push(index);
+                    }
+                }
+            }
+            indices.sort_unstable();
+            indices.dedup();
+            Ok(indices)
+        })
+    }
+
+    fn flush<'a>(&'a self, object_id: &'a str, indices: &'a [usize]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            // リネーム自体を永続化するため、ディレクトリをfsyncする
+            let mut dirs = Vec::new();
+            for &i in indices {
+                dirs.push(self.root.join(output_dir_name(object_id, i)?));
+            }
+            dirs.sort();
+            dirs.dedup();
+            for dir in dirs {
+                File::open(dir).await?.sync_all().await?;
+            }
+            Ok(())
+        })
+    }
+}
+
+struct LocalUpload {
+    filepath: PathBuf,
+    temp_path: PathBuf,
+    file: File,
+}
+
+impl ShardUpload for LocalUpload {
+    fn write_at<'a>(&'a mut self, offset: u64, data: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            self.file.seek(SeekFrom::Start(offset)).await?;
+            self.file.write_all(data).await?;
+            Ok(())
+        })
+    }
+
+    fn sync(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move { Ok(self.file.sync_all().await?) })
+    }
+
+    fn commit(self: Box<Self>) -> BoxFuture<'static, Result<()>> {
+        Box::pin(async move {
+            drop(self.file);
+            if let Err(e) = fs::rename(&self.temp_path, &self.filepath).await {
+                remove_if_exists(&self.temp_path).await;
+                return Err(e.into());
+            }
+            Ok(())
+        })
+    }
+
+    fn abort(self: Box<Self>) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            drop(self.file);
+            remove_if_exists(&self.temp_path).await;
+        })
+    }
+}
+
+/// ファイルの `range` の部分を読む。
+pub async fn read_range(path: &Path, range: Range<u64>) -> Result<Bytes> {
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(range.start)).await?;
+    let mut buf = vec![0u8; (range.end - range.start) as usize];
+    file.read_exact(&mut buf).await?;
+    Ok(Bytes::from(buf))
+}
+
+fn temp_path(filepath: &Path, suffix: &str) -> PathBuf {
+    let mut name = filepath.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}.tmp", suffix));
+    filepath.with_file_name(name)
+}
+
+pub(crate) async fn remove_if_exists(path: &Path) {
+    match fs::remove_file(path).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => error!("Failed to remove {:?}: {}", path, e),
+    }
+}
+
+type MemoryShards = Arc<Mutex<HashMap<(String, usize), Bytes>>>;
+
+/// シャードをメモリ上に置く。テスト用。
+#[derive(Debug, Clone, Default)]
+pub struct MemoryShardStore {
+    shards: MemoryShards,
+}
+
+impl MemoryShardStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 保存されているシャードの数
+    pub fn len(&self) -> usize {
+        self.shards.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl ShardStore for MemoryShardStore {
+    fn create<'a>(
+        &'a self,
+        object_id: &'a str,
+        index: usize,
+    ) -> BoxFuture<'a, Result<Box<dyn ShardUpload>>> {
+        Box::pin(async move {
+            Ok(Box::new(MemoryUpload {
+                key: (object_id.to_string(), index),
+                data: Vec::new(),
+                shards: self.shards.clone(),
+            }) as Box<dyn ShardUpload>)
+        })
+    }
+
+    fn get<'a>(
+        &'a self,
+        object_id: &'a str,
+        index: usize,
+        range: Range<u64>,
+    ) -> BoxFuture<'a, Result<Bytes>> {
+        Box::pin(async move {
+            let shards = self.shards.lock().unwrap();
+            let Some(shard) = shards.get(&(object_id.to_string(), index)) else {
+                bail!("shard {} of {} not found", index, object_id);
+            };
+            if range.end > shard.len() as u64 {
+                bail!(
+                    "range {:?} is out of bounds for {} bytes",
+                    range,
+                    shard.len()
+                );
+            }
+            Ok(shard.slice(range.start as usize..range.end as usize))
+        })
+    }
+
+    fn delete<'a>(&'a self, object_id: &'a str, index: usize) -> BoxFuture<'a, Result<bool>> {
+        Box::pin(async move {
+            let mut shards = self.shards.lock().unwrap();
+            Ok(shards.remove(&(object_id.to_string(), index)).is_some())
+        })
+    }
+
+    fn stat<'a>(
+        &'a self,
+        object_id: &'a str,
+        index: usize,
+    ) -> BoxFuture<'a, Result<Option<ShardStat>>> {
+        Box::pin(async move {
+            let shards = self.shards.lock().unwrap();
+            Ok(shards
+                .get(&(object_id.to_string(), index))
+                .map(|shard| ShardStat {
+                    len: shard.len() as u64,
+                }))
+        })
+    }
+
+    fn list<'a>(&'a self, object_id: &'a str) -> BoxFuture<'a, Result<Vec<usize>>> {
+        Box::pin(async move {
+            let shards = self.shards.lock().unwrap();
+            let mut indices: Vec<usize> = shards
+                .keys()
+                .filter(|(id, _)| id == object_id)
+                .map(|(_, index)| *index)
+                .collect();
+            indices.sort_unstable();
+            Ok(indices)
+        })
+    }
+
+    fn flush<'a>(
+        &'a self,
+        _object_id: &'a str,
+        _indices: &'a [usize],
+    ) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+struct MemoryUpload {
+    key: (String, usize),
+    data: Vec<u8>,
+    shards: MemoryShards,
+}
+
+impl ShardUpload for MemoryUpload {
+    fn write_at<'a>(&'a mut self, offset: u64, data: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let offset = offset as usize;
+            if self.data.len() < offset + data.len() {
+                self.data.resize(offset + data.len(), 0);
+            }
+            self.data[offset..offset + data.len()].copy_from_slice(data);
+            Ok(())
+        })
+    }
+
+    fn sync(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn commit(self: Box<Self>) -> BoxFuture<'static, Result<()>> {
+        Box::pin(async move {
+            self.shards
+                .lock()
+                .unwrap()
+                .insert(self.key, Bytes::from(self.data));
+            Ok(())
+        })
+    }
+
+    fn abort(self: Box<Self>) -> BoxFuture<'static, ()> {
+        Box::pin(async {})
+    }
+}
@@ -0,0 +1,127 @@
+use anyhow::bail;
+use futures::TryStreamExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use t3::decode::ShardReader;
+use t3::encode::{ShardWriter, WriteStep, remove_shards};
+use t3::env::{DATA_SHARDS, NUM_OUTPUT_DIRS, OUTPUT_DIR_PREFIX, PARITY_SHARDS, STRIPE_UNIT};
+use t3::profile::ErasureProfile;
+use t3::store::{LocalShardStore, SharedShardStore};
+use uuid::Uuid;
+
+fn local(root: &Path) -> SharedShardStore {
+    Arc::new(LocalShardStore::with_root(root))
+}
+
+/// テストごとのデータディレクトリ。作業ディレクトリの `outputs` は使わない
+fn temp_root() -> PathBuf {
+    std::env::temp_dir().join(format!("t3-atomic-{}", Uuid::new_v4()))
+}
+
+fn sample(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 + 1).collect()
+}
+
+/// 一時ファイルも含めて、`object_id` のシャードとして残っているファイルを数える。
+fn leftover_files(root: &Path, object_id: &str) -> usize {
+    (1..=NUM_OUTPUT_DIRS)
+        .filter_map(|i| std::fs::read_dir(root.join(format!("{}{}", OUTPUT_DIR_PREFIX, i))).ok())
+        .flatten()
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(object_id))
+        .count()
+}
+
+async fn write(root: &Path, object_id: &str, data: &[u8]) -> ShardWriter {
+    let mut writer = ShardWriter::create(&local(root), object_id, ErasureProfile::default())
+        .await
+        .unwrap();
+    for chunk in data.chunks(100_003) {
+        writer.write(chunk).await.unwrap();
+    }
+    writer
+}
+
+#[tokio::test]
+async fn failure_at_each_step_leaves_no_shards() {
+    let root = temp_root();
+    let data = sample(STRIPE_UNIT * DATA_SHARDS + 11);
+    let steps = [
+        WriteStep::Written,
+        WriteStep::Synced,
+        WriteStep::Renamed(0),
+        WriteStep::Renamed(DATA_SHARDS),
+        WriteStep::Renamed(DATA_SHARDS + PARITY_SHARDS - 1),
+        WriteStep::DirSynced,
+    ];
+    for fail_at in steps {
+        let object_id = format!("atomic-{}", Uuid::new_v4());
+        let writer = write(&root, &object_id, &data).await;
+        assert!(leftover_files(&root, &object_id) > 0);
+        let result = writer
+            .finish_with(|step| {
+                if step == fail_at {
+                    bail!("injected failure at {:?}", step);
+                }
+                Ok(())
+            })
+            .await;
+        assert!(result.is_err(), "{:?}", fail_at);
+        assert_eq!(leftover_files(&root, &object_id), 0, "{:?}", fail_at);
+        assert!(
+            ShardReader::open(
+                &local(&root),
+                &object_id,
+                data.len() as u64,
+                ErasureProfile::default()
+            )
+            .await
+            .is_err()
+        );
+    }
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn aborted_upload_leaves_no_shards() {
+    let root = temp_root();
+    let object_id = format!("atomic-{}", Uuid::new_v4());
+    let writer = write(&root, &object_id, &sample(1000)).await;
+    writer.abort().await;
+    assert_eq!(leftover_files(&root, &object_id), 0);
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn committed_shards_are_readable_and_can_be_rolled_back() {
+    let root = temp_root();
+    let object_id = format!("atomic-{}", Uuid::new_v4());
+    let data = sample(1000);
+    let writer = write(&root, &object_id, &data).await;
+    let encoded = writer.finish().await.unwrap();
+    // 一時ファイルは残らず、最終的なシャードだけがある
+    assert_eq!(
+        leftover_files(&root, &object_id),
+        DATA_SHARDS + PARITY_SHARDS
+    );
+
+    let reader = ShardReader::open(
+        &local(&root),
+        &object_id,
+        encoded.content_length,
+        ErasureProfile::default(),
+    )
+    .await
+    .unwrap();
+    let parts: Vec<_> = reader
+        .into_stream(0..encoded.content_length)
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(parts.concat(), data);
+
+    // メタデータの登録に失敗した場合のロールバック
+    remove_shards(&local(&root), &object_id, ErasureProfile::default()).await;
+    assert_eq!(leftover_files(&root, &object_id), 0);
+    std::fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,245 @@
+use axum::Json;
+use axum::body::{Body, to_bytes};
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode, Uri};
+use axum::response::IntoResponse;
+use serde_json::Value;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use t3::db::{MetadataStore, MultipartUpload};
+use t3::encode::ShardWriter;
+use t3::handler::bucket::{create_bucket, delete_bucket};
+use t3::handler::put::put_object;
+use t3::job::{JobRegistry, JobState, JobStatus};
+use t3::profile::ErasureProfile;
+use t3::server::ServerConfig;
+use t3::store::{LocalShardStore, MemoryShardStore, SharedShardStore};
+use uuid::Uuid;
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("t3-{}-{}", name, Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn db_url(dir: &std::path::Path) -> String {
+    format!("sqlite://{}?mode=rwc", dir.join("t3.db").display())
+}
+
+fn temp_url(name: &str) -> String {
+    db_url(&temp_dir(name))
+}
+
+/// 一時ディレクトリのデータベースとシャードの置き場所を開く。
+async fn temp_stores(name: &str) -> (MetadataStore, SharedShardStore) {
+    let dir = temp_dir(name);
+    let store = MetadataStore::new(&db_url(&dir)).await.unwrap();
+    let shards: SharedShardStore = Arc::new(LocalShardStore::with_root(dir.join("outputs")));
+    (store, shards)
+}
+
+#[tokio::test]
+async fn rejects_profiles_with_more_shards_than_targets() {
+    let (store, shards) = temp_stores("bucket-profile").await;
+    // ローカルの置き場所は9つなので、12個のシャードは重ならずに置けない
+    let request = serde_json::from_value(serde_json::json!({
+        "data_shards": 8,
+        "parity_shards": 4,
+    }))
+    .unwrap();
+    let response = create_bucket(
+        Path("wide".to_string()),
+        State(store.clone()),
+        State(shards.clone()),
+        Some(Json(request)),
+    )
+    .await
+    .into_response();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert!(store.get_bucket("wide").await.unwrap().is_none());
+
+    let profile = ErasureProfile::new(8, 4, 16 * 1024).unwrap();
+    assert!(ShardWriter::create(&shards, "wide", profile).await.is_err());
+
+    // 置き場所の数ちょうどなら作れる
+    let request = serde_json::from_value(serde_json::json!({
+        "data_shards": 6,
+        "parity_shards": 3,
+    }))
+    .unwrap();
+    let response = create_bucket(
+        Path("narrow".to_string()),
+        State(store.clone()),
+        State(shards),
+        Some(Json(request)),
+    )
+    .await
+    .into_response();
+    assert_eq!(response.status(), StatusCode::CREATED);
+}
+
+/// バケット `bucket` を作り、`keys` のオブジェクトを置く。シャードはメモリに置く。
+async fn bucket_with_objects(
+    url: &str,
+    keys: &[&str],
+) -> (MetadataStore, MemoryShardStore, SharedShardStore) {
+    let store = MetadataStore::new(url).await.unwrap();
+    store
+        .create_bucket(
+            &Uuid::new_v4().to_string(),
+            "bucket",
+            "2026-01-01T00:00:00+00:00",
+            &ErasureProfile::default(),
+        )
+        .await
+        .unwrap();
+    let memory = MemoryShardStore::new();
+    let shards: SharedShardStore = Arc::new(memory.clone());
+    for key in keys {
+        let response = put_object(
+            Path(("bucket".to_string(), key.to_string())),
+            State(store.clone()),
+            State(shards.clone()),
+            State(ServerConfig::default()),
+            HeaderMap::new(),
+            Body::from(format!("data of {}", key)),
+        )
+        .await
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    (store, memory, shards)
+}
+
+async fn delete(
+    store: &MetadataStore,
+    shards: &SharedShardStore,
+    jobs: &JobRegistry,
+    query: &str,
+) -> (StatusCode, Value) {
+    let uri: Uri = format!("/bucket/bucket?{}", query).parse().unwrap();
+    let response = delete_bucket(
+        Path("bucket".to_string()),
+        Query::try_from_uri(&uri).unwrap(),
+        State(store.clone()),
+        State(shards.clone()),
+        State(jobs.clone()),
+    )
+    .await
+    .into_response();
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+/// ジョブが終わるまで待つ。
+async fn wait_for_job(jobs: &JobRegistry, id: &str) -> JobStatus {
+    for _ in 0..500 {
+        let job = jobs.get(id).unwrap();
+        if job.state != JobState::Running {
+            return job;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("job {} did not finish", id);
+}
+
+fn upload(upload_id: &str) -> MultipartUpload {
+    let profile = ErasureProfile::default();
+    MultipartUpload {
+        upload_id: upload_id.to_string(),
+        bucket_name: "bucket".to_string(),
+        object_id: "uploading".to_string(),
+        created_at: "2026-01-01T00:00:00+00:00".to_string(),
+        file_name: None,
+        content_type: None,
+        user_metadata: None,
+        data_shards: profile.data_shards as i64,
+        parity_shards: profile.parity_shards as i64,
+        stripe_unit: profile.stripe_unit as i64,
+    }
+}
+
+#[tokio::test]
+async fn non_empty_buckets_are_not_deleted_without_force() {
+    let (store, memory, shards) = bucket_with_objects(&temp_url("bucket-delete"), &["a"]).await;
+    let jobs = JobRegistry::new();
+    let (status, body) = delete(&store, &shards, &jobs, "").await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(
+        body["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("BucketNotEmpty:")
+    );
+    assert!(store.get_bucket("bucket").await.unwrap().is_some());
+    assert!(!memory.is_empty());
+}
+
+#[tokio::test]
+async fn buckets_referenced_by_uploads_are_not_deleted() {
+    // オブジェクトはなくても、マルチパートアップロードが参照しているバケットは外部キーで削除できない
+    let (store, _, shards) = bucket_with_objects(&temp_url("bucket-delete"), &[]).await;
+    store.create_multipart_upload(&upload("u1")).await.unwrap();
+    let jobs = JobRegistry::new();
+    let (status, body) = delete(&store, &shards, &jobs, "").await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(
+        body["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("BucketNotEmpty:")
+    );
+    assert!(store.get_bucket("bucket").await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn force_delete_removes_objects_in_a_job() {
+    let keys = ["a", "b", "photos/c"];
+    let (store, memory, shards) = bucket_with_objects(&temp_url("bucket-delete"), &keys).await;
+    let jobs = JobRegistry::new();
+    let (status, body) = delete(&store, &shards, &jobs, "force=true").await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_eq!(body["data"]["total_objects"], keys.len());
+
+    let job = wait_for_job(&jobs, body["data"]["id"].as_str().unwrap()).await;
+    assert_eq!(job.state, JobState::Completed, "{:?}", job.error);
+    assert_eq!(job.deleted_objects, keys.len() as u64);
+    assert!(store.get_bucket("bucket").await.unwrap().is_none());
+    assert_eq!(store.count_objects("bucket").await.unwrap(), 0);
+    assert!(memory.is_empty());
+}
+
+#[tokio::test]
+async fn force_delete_retries_when_objects_are_added() {
+    let url = temp_url("bucket-delete");
+    let (store, memory, shards) = bucket_with_objects(&url, &[]).await;
+    store.create_multipart_upload(&upload("u1")).await.unwrap();
+    // アップロードを中止した直後、バケットを削除する前にオブジェクトが追加されたことにする
+    let pool = sqlx::SqlitePool::connect(&url).await.unwrap();
+    sqlx::raw_sql(
+        "
+        CREATE TRIGGER add_object_after_abort AFTER DELETE ON multipart_uploads
+        BEGIN
+            INSERT INTO object_metadata (bucket_name, object_id, content_length, created_at)
+            VALUES (OLD.bucket_name, 'late', 0, '2026-01-01T00:00:00+00:00');
+        END
+        ",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    pool.close().await;
+
+    let jobs = JobRegistry::new();
+    let (status, body) = delete(&store, &shards, &jobs, "force=true").await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    // 外部キー違反で削除をやり直し、追加されたオブジェクトも消してからバケットを削除する
+    let job = wait_for_job(&jobs, body["data"]["id"].as_str().unwrap()).await;
+    assert_eq!(job.state, JobState::Completed, "{:?}", job.error);
+    assert_eq!(job.deleted_objects, 1);
+    assert!(store.get_bucket("bucket").await.unwrap().is_none());
+    assert_eq!(store.count_objects("bucket").await.unwrap(), 0);
+    assert!(memory.is_empty());
+}
@@ -0,0 +1,37 @@
+use t3::handler::bucket::validate_bucket_name;
+
+#[test]
+fn accepts_s3_bucket_names() {
+    for name in [
+        "abc",
+        "my-bucket",
+        "logs.2024.example",
+        "0bucket9",
+        &"a".repeat(63),
+    ] {
+        assert!(validate_bucket_name(name).is_ok(), "{}", name);
+    }
+}
+
+#[test]
+fn rejects_invalid_bucket_names() {
+    for name in [
+        "",
+        "ab",
+        &"a".repeat(64),
+        "MyBucket",
+        "my_bucket",
+        "my bucket",
+        "../outputs",
+        "バケット",
+        "-bucket",
+        "bucket-",
+        ".bucket",
+        "my..bucket",
+        "192.168.5.4",
+        "xn--bucket",
+        "bucket-s3alias",
+    ] {
+        assert!(validate_bucket_name(name).is_err(), "{}", name);
+    }
+}
@@ -0,0 +1,108 @@
+use axum::http::{HeaderMap, HeaderValue};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use md5::{Digest, Md5};
+use sha2::Sha256;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use t3::encode::ShardWriter;
+use t3::env::{DATA_SHARDS, PARITY_SHARDS};
+use t3::handler::checksum::ExpectedChecksums;
+use t3::profile::ErasureProfile;
+use t3::store::{LocalShardStore, SharedShardStore};
+use uuid::Uuid;
+
+fn local(root: &Path) -> SharedShardStore {
+    Arc::new(LocalShardStore::with_root(root))
+}
+
+/// テストごとのデータディレクトリ。作業ディレクトリの `outputs` は使わない
+fn temp_root() -> PathBuf {
+    std::env::temp_dir().join(format!("t3-checksum-{}", Uuid::new_v4()))
+}
+
+fn shard_path(root: &Path, object_id: &str, i: usize) -> PathBuf {
+    root.join(t3::output_dir_name(object_id, i).unwrap())
+        .join(t3::shard_filename(object_id, i))
+}
+
+const DATA: &[u8] = b"hello, t3";
+
+fn headers(pairs: &[(&'static str, String)]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in pairs {
+        headers.insert(*name, HeaderValue::from_str(value).unwrap());
+    }
+    headers
+}
+
+async fn write(root: &Path, object_id: &str, data: &[u8]) -> ShardWriter {
+    let mut writer = ShardWriter::create(&local(root), object_id, ErasureProfile::default())
+        .await
+        .unwrap();
+    writer.write(data).await.unwrap();
+    writer
+}
+
+#[tokio::test]
+async fn matching_checksums_are_accepted() {
+    let root = temp_root();
+    let expected = ExpectedChecksums::from_headers(&headers(&[
+        ("content-md5", STANDARD.encode(Md5::digest(DATA))),
+        (
+            "x-amz-checksum-crc32c",
+            STANDARD.encode(crc32c::crc32c(DATA).to_be_bytes()),
+        ),
+        (
+            "x-amz-checksum-sha256",
+            STANDARD.encode(Sha256::digest(DATA)),
+        ),
+    ]))
+    .unwrap();
+    assert!(expected.md5.is_some() && expected.crc32c.is_some() && expected.sha256.is_some());
+
+    let object_id = format!("checksum-{}", Uuid::new_v4());
+    let writer = write(&root, &object_id, DATA).await;
+    assert!(expected.verify(&writer.digests()).is_ok());
+    writer.abort().await;
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn mismatched_checksum_is_bad_digest_and_leaves_no_shards() {
+    let root = temp_root();
+    let mut expected = ExpectedChecksums::default();
+    // フォームフィールドの名前は大文字小文字を区別しない
+    assert!(
+        expected
+            .set("Content-MD5", &STANDARD.encode(Md5::digest(b"other")))
+            .unwrap()
+    );
+    assert!(!expected.set("key", "value").unwrap());
+
+    let object_id = format!("checksum-{}", Uuid::new_v4());
+    let writer = write(&root, &object_id, DATA).await;
+    let err = expected.verify(&writer.digests()).unwrap_err();
+    assert!(err.to_string().starts_with("BadDigest"));
+    writer.abort().await;
+    for i in 0..(DATA_SHARDS + PARITY_SHARDS) {
+        assert!(!shard_path(&root, &object_id, i).exists());
+    }
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn malformed_checksums_are_rejected() {
+    for (name, value) in [
+        ("content-md5", "not base64!".to_string()),
+        ("content-md5", STANDARD.encode([0u8; 4])),
+        ("x-amz-checksum-crc32c", STANDARD.encode([0u8; 8])),
+        ("x-amz-checksum-sha256", STANDARD.encode([0u8; 16])),
+    ] {
+        assert!(
+            ExpectedChecksums::from_headers(&headers(&[(name, value.clone())])).is_err(),
+            "{}: {}",
+            name,
+            value
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use axum::http::{HeaderMap, HeaderValue};
+use futures::TryStreamExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use t3::db::{LinkObject, MetadataStore, NewObjectMetadata, ObjectMetadata};
+use t3::encode::ShardWriter;
+use t3::handler::api::ApiResult;
+use t3::handler::copy::{COPY_SOURCE, RENAME_SOURCE, copy_object, parse_copy_source};
+use t3::multipart;
+use t3::profile::ErasureProfile;
+use t3::server::ServerConfig;
+use t3::store::{MemoryShardStore, SharedShardStore};
+use uuid::Uuid;
+
+#[test]
+fn parses_copy_source() {
+    let cases = [
+        ("/photos/cat.jpg", Some(("photos", "cat.jpg"))),
+        // 先頭の `/` は省略できる
+        ("photos/2024/cat.jpg", Some(("photos", "2024/cat.jpg"))),
+        // キーはURLエンコードされている
+        (
+            "/photos/2024%2F%E7%8C%AB%20.jpg",
+            Some(("photos", "2024/猫 .jpg")),
+        ),
+        ("/photos/a%3Fb", Some(("photos", "a?b"))),
+        ("/photos/cat.jpg?versionId=1", None),
+        ("/photos", None),
+        ("/photos/", None),
+        ("//cat.jpg", None),
+        ("/photos/%ZZ", None),
+    ];
+    for (value, expected) in cases {
+        assert_eq!(
+            parse_copy_source(value),
+            expected.map(|(bucket, key)| (bucket.to_string(), key.to_string())),
+            "{}",
+            value
+        );
+    }
+}
+
+/// 一時ディレクトリのデータベースを開き、バケット `bucket` と `other` を作る。
+async fn temp_store(dir: &Path) -> MetadataStore {
+    std::fs::create_dir_all(dir).unwrap();
+    let url = format!("sqlite://{}?mode=rwc", dir.join("t3.db").display());
+    let store = MetadataStore::new(&url).await.unwrap();
+    for bucket_name in ["bucket", "other"] {
+        store
+            .create_bucket(
+                &Uuid::new_v4().to_string(),
+                bucket_name,
+                "2026-01-01T00:00:00+00:00",
+                &ErasureProfile::default(),
+            )
+            .await
+            .unwrap();
+    }
+    store
+}
+
+fn temp_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("t3-copy-{}", Uuid::new_v4()))
+}
+
+fn config() -> ServerConfig {
+    ServerConfig {
+        read_repair: false,
+        small_object_threshold: 0,
+        pack_small_objects: false,
+    }
+}
+
+fn sample(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 + 1).collect()
+}
+
+/// シャードを書いてオブジェクトを登録する。
+async fn put_object(
+    store: &MetadataStore,
+    shards: &SharedShardStore,
+    (bucket_name, object_id): (&str, &str),
+    data: &[u8],
+) -> ObjectMetadata {
+    let profile = ErasureProfile::default();
+    let blob_id = t3::new_blob_id();
+    let mut writer = ShardWriter::create(shards, &blob_id, profile)
+        .await
+        .unwrap();
+    writer.write(data).await.unwrap();
+    let encoded = writer.finish().await.unwrap();
+    let generation = Uuid::new_v4().simple().to_string();
+    let metadata = NewObjectMetadata {
+        content_length: data.len() as i64,
+        md5: Some(&encoded.md5),
+        sha256: Some(&encoded.sha256),
+        generation: Some(&generation),
+        blob_id: Some(&blob_id),
+        profile,
+        ..Default::default()
+    };
+    store
+        .upsert_metadata(bucket_name, object_id, &metadata)
+        .await
+        .unwrap();
+    get(store, bucket_name, object_id).await.unwrap()
+}
+
+async fn get(store: &MetadataStore, bucket_name: &str, object_id: &str) -> Option<ObjectMetadata> {
+    store.get_metadata(bucket_name, object_id).await.unwrap()
+}
+
+async fn read(
+    store: &MetadataStore,
+    shards: &SharedShardStore,
+    object: &ObjectMetadata,
+) -> Vec<u8> {
+    let len = object.content_length.unwrap() as u64;
+    let reader = multipart::open_object(store, shards, object, len, false)
+        .await
+        .unwrap();
+    let parts: Vec<_> = reader.into_stream(0..len).try_collect().await.unwrap();
+    parts.concat()
+}
+
+/// DELETEと同じく、読んだ世代のメタデータを消してから参照されなくなったシャードを消す。
+async fn delete(store: &MetadataStore, shards: &SharedShardStore, object: &ObjectMetadata) {
+    assert!(
+        store
+            .delete_metadata_generation(
+                &object.bucket_name,
+                &object.object_id,
+                object.generation.as_deref()
+            )
+            .await
+            .unwrap()
+    );
+    multipart::remove_object_data(store, shards, object).await;
+}
+
+fn source_header(name: &'static str, value: &str) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(name, HeaderValue::from_str(value).unwrap());
+    headers
+}
+
+/// コピーが成功したら、シャードを共有したかどうかを返す。
+fn linked<T: serde::Serialize>(result: ApiResult<T>) -> bool {
+    match result {
+        ApiResult::Success(_, response) => serde_json::to_value(response).unwrap()["linked"]
+            .as_bool()
+            .unwrap(),
+        ApiResult::Error(status, message) => panic!("{}: {}", status, message),
+    }
+}
+
+#[tokio::test]
+async fn linked_copies_keep_shards_until_the_last_reference_is_deleted() {
+    let dir = temp_dir();
+    let store = temp_store(&dir).await;
+    let memory = MemoryShardStore::new();
+    let shards: SharedShardStore = Arc::new(memory.clone());
+    let data = sample(5000);
+    let source = put_object(&store, &shards, ("bucket", "src"), &data).await;
+    let stored = memory.len();
+
+    let headers = source_header(COPY_SOURCE, "/bucket/src");
+    let target = ("other".to_string(), "dst".to_string());
+    assert!(linked(
+        copy_object(&store, &shards, &config(), target, &headers, false).await
+    ));
+    // シャードを書き直さずに同じ内部IDを参照する
+    let copy = get(&store, "other", "dst").await.unwrap();
+    assert_eq!(copy.blob_id, source.blob_id);
+    assert_eq!(copy.md5, source.md5);
+    assert_eq!(memory.len(), stored);
+    assert_eq!(store.count_blob_refs(&source.shard_id()).await.unwrap(), 2);
+
+    // 片方を消しても、もう片方が参照しているシャードは残る
+    delete(&store, &shards, &source).await;
+    assert_eq!(store.count_blob_refs(&source.shard_id()).await.unwrap(), 1);
+    assert_eq!(memory.len(), stored);
+    assert_eq!(read(&store, &shards, &copy).await, data);
+
+    delete(&store, &shards, &copy).await;
+    assert!(memory.is_empty());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn rename_moves_the_object_and_removes_the_source() {
+    let dir = temp_dir();
+    let store = temp_store(&dir).await;
+    let memory = MemoryShardStore::new();
+    let shards: SharedShardStore = Arc::new(memory.clone());
+    let data = sample(5000);
+    let source = put_object(&store, &shards, ("bucket", "src"), &data).await;
+
+    let headers = source_header(RENAME_SOURCE, "/bucket/src");
+    let target = ("other".to_string(), "moved".to_string());
+    assert!(linked(
+        copy_object(&store, &shards, &config(), target, &headers, true).await
+    ));
+    assert!(get(&store, "bucket", "src").await.is_none());
+    let moved = get(&store, "other", "moved").await.unwrap();
+    assert_eq!(moved.blob_id, source.blob_id);
+    assert_eq!(store.count_blob_refs(&source.shard_id()).await.unwrap(), 1);
+    assert_eq!(read(&store, &shards, &moved).await, data);
+
+    delete(&store, &shards, &moved).await;
+    assert!(memory.is_empty());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn link_fails_if_the_source_changed() {
+    let dir = temp_dir();
+    let store = temp_store(&dir).await;
+    let shards: SharedShardStore = Arc::new(MemoryShardStore::new());
+    let stale = put_object(&store, &shards, ("bucket", "src"), &sample(10)).await;
+    // 読んだ後に上書きされた
+    let current = put_object(&store, &shards, ("bucket", "src"), &sample(20)).await;
+
+    let blob_id = stale.shard_id();
+    let generation = Uuid::new_v4().simple().to_string();
+    let metadata = NewObjectMetadata {
+        content_length: 10,
+        generation: Some(&generation),
+        blob_id: Some(&blob_id),
+        ..Default::default()
+    };
+    for move_source in [false, true] {
+        let linked = store
+            .link_object(&stale, ("other", "dst"), &metadata, false, move_source)
+            .await
+            .unwrap();
+        assert!(matches!(linked, LinkObject::SourceChanged));
+    }
+    assert!(get(&store, "other", "dst").await.is_none());
+    // 上書きした新しいオブジェクトはそのまま残る
+    let source = get(&store, "bucket", "src").await.unwrap();
+    assert_eq!(source.generation, current.generation);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
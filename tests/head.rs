@@ -0,0 +1,143 @@
+use axum::body::{Body, to_bytes};
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use axum::response::IntoResponse;
+use std::sync::Arc;
+use t3::db::MetadataStore;
+use t3::handler::get::get_object;
+use t3::handler::head::{content_disposition, head_object};
+use t3::handler::put::{file_name_from_disposition, put_object};
+use t3::profile::ErasureProfile;
+use t3::server::ServerConfig;
+use t3::store::{MemoryShardStore, SharedShardStore};
+use uuid::Uuid;
+
+#[test]
+fn content_disposition_escapes_file_name() {
+    for name in [
+        "cat.jpg",
+        "写真 2024.jpg",
+        "a\"b.txt",
+        "x;y\\z%41.txt",
+        "line\r\nX-Injected: 1",
+    ] {
+        let value = content_disposition(name);
+        // ヘッダの値として使え、ファイル名が引用符の外にはみ出さない
+        assert!(
+            value.bytes().all(|b| (b' '..=b'~').contains(&b)),
+            "{}",
+            value
+        );
+        let (_, quoted) = value.split_once("filename=\"").unwrap();
+        assert!(
+            quoted
+                .find('"')
+                .is_some_and(|end| !quoted[..end].contains(';'))
+        );
+        assert_eq!(file_name_from_disposition(&value).as_deref(), Some(name));
+    }
+    assert_eq!(
+        content_disposition("写真.jpg"),
+        "attachment; filename=\"__.jpg\"; filename*=UTF-8''%E5%86%99%E7%9C%9F.jpg"
+    );
+}
+
+/// 一時ディレクトリのデータベースを開き、バケット `bucket` を作る。シャードはメモリに置く。
+async fn temp_stores() -> (MetadataStore, SharedShardStore) {
+    let dir = std::env::temp_dir().join(format!("t3-head-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let url = format!("sqlite://{}?mode=rwc", dir.join("t3.db").display());
+    let store = MetadataStore::new(&url).await.unwrap();
+    store
+        .create_bucket(
+            &Uuid::new_v4().to_string(),
+            "bucket",
+            "2026-01-01T00:00:00+00:00",
+            &ErasureProfile::default(),
+        )
+        .await
+        .unwrap();
+    (store, Arc::new(MemoryShardStore::new()))
+}
+
+async fn head(store: &MetadataStore, key: &str) -> (StatusCode, HeaderMap) {
+    let response = head_object(
+        Path(("bucket".to_string(), key.to_string())),
+        State(store.clone()),
+    )
+    .await
+    .into_response();
+    (response.status(), response.headers().clone())
+}
+
+#[tokio::test]
+async fn head_returns_headers_from_metadata() {
+    let (store, shards) = temp_stores().await;
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+    headers.insert(
+        header::CONTENT_DISPOSITION,
+        HeaderValue::from_static("attachment; filename=\"cat.png\""),
+    );
+    let data = "not really a png";
+    let response = put_object(
+        Path(("bucket".to_string(), "cat".to_string())),
+        State(store.clone()),
+        State(shards.clone()),
+        State(ServerConfig::default()),
+        headers,
+        Body::from(data),
+    )
+    .await
+    .into_response();
+    assert_eq!(response.status(), StatusCode::OK);
+    let metadata = store.get_metadata("bucket", "cat").await.unwrap().unwrap();
+
+    let (status, headers) = head(&store, "cat").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        headers[header::CONTENT_LENGTH],
+        metadata.content_length.unwrap().to_string()
+    );
+    assert_eq!(headers[header::CONTENT_LENGTH], data.len().to_string());
+    assert_eq!(
+        headers[header::ETAG],
+        format!("\"{}\"", metadata.md5.as_deref().unwrap())
+    );
+    assert_eq!(headers[header::CONTENT_TYPE], "image/png");
+    assert_eq!(
+        headers[header::CONTENT_DISPOSITION],
+        content_disposition("cat.png").as_str()
+    );
+
+    // GETも同じヘッダを返す
+    let response = get_object(
+        Path(("bucket".to_string(), "cat".to_string())),
+        State(store.clone()),
+        State(shards),
+        State(ServerConfig::default()),
+        HeaderMap::new(),
+    )
+    .await
+    .into_response();
+    assert_eq!(response.status(), StatusCode::OK);
+    for name in [
+        header::CONTENT_LENGTH,
+        header::ETAG,
+        header::CONTENT_TYPE,
+        header::CONTENT_DISPOSITION,
+        header::LAST_MODIFIED,
+    ] {
+        assert_eq!(response.headers()[&name], headers[&name], "{}", name);
+    }
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(&body[..], data.as_bytes());
+}
+
+#[tokio::test]
+async fn head_of_a_missing_object_is_not_found() {
+    let (store, _) = temp_stores().await;
+    let (status, headers) = head(&store, "missing").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(headers.get(header::ETAG).is_none());
+}
@@ -0,0 +1,52 @@
+use std::time::Duration;
+use t3::job::{JobRegistry, JobState};
+
+#[test]
+fn job_progress_is_tracked_until_finished() {
+    let jobs = JobRegistry::new();
+    let (job, started) = jobs.start("b1", 2);
+    assert!(started);
+    assert_eq!(job.state, JobState::Running);
+
+    // 実行中のバケットには新しいジョブを作らない
+    let (running, started) = jobs.start("b1", 2);
+    assert!(!started);
+    assert_eq!(running.id, job.id);
+
+    jobs.add_deleted(&job.id, 1);
+    jobs.add_deleted(&job.id, 2);
+    let progress = jobs.get(&job.id).unwrap();
+    assert_eq!(progress.deleted_objects, 3);
+    assert_eq!(progress.total_objects, 3);
+
+    jobs.finish(&job.id, None);
+    let finished = jobs.get(&job.id).unwrap();
+    assert_eq!(finished.state, JobState::Completed);
+    assert!(finished.finished_at.is_some());
+    assert!(jobs.start("b1", 0).1);
+}
+
+#[test]
+fn failed_job_keeps_error() {
+    let jobs = JobRegistry::new();
+    let (job, _) = jobs.start("b1", 1);
+    jobs.finish(&job.id, Some("disk error".to_string()));
+    let failed = jobs.get(&job.id).unwrap();
+    assert_eq!(failed.state, JobState::Failed);
+    assert_eq!(failed.error.as_deref(), Some("disk error"));
+    assert!(jobs.get("unknown").is_none());
+}
+
+#[test]
+fn finished_jobs_expire_after_ttl() {
+    let jobs = JobRegistry::with_ttl(Duration::from_millis(50));
+    let (running, _) = jobs.start("b1", 1);
+    let (done, _) = jobs.start("b2", 1);
+    jobs.finish(&done.id, None);
+    assert!(jobs.get(&done.id).is_some());
+
+    std::thread::sleep(Duration::from_millis(100));
+    // 終わったジョブだけが消え、実行中のジョブは残る
+    assert!(jobs.get(&done.id).is_none());
+    assert_eq!(jobs.get(&running.id).unwrap().state, JobState::Running);
+}
@@ -0,0 +1,173 @@
+use axum::body::to_bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::{StatusCode, Uri};
+use axum::response::IntoResponse;
+use serde_json::Value;
+use t3::db::{MetadataStore, NewObjectMetadata};
+use t3::handler::list::list_objects;
+use t3::profile::ErasureProfile;
+use uuid::Uuid;
+
+const KEYS: &[&str] = &[
+    "a.txt",
+    "photos/2024/a.jpg",
+    "photos/2024/b.jpg",
+    "photos/2025/c.jpg",
+    "photos/cat.jpg",
+    "photos0",
+    "photosx",
+    "photot",
+    "videos/d.mp4",
+];
+
+/// 一時ディレクトリのデータベースを開き、バケット `bucket` に `KEYS` のオブジェクトを登録する。
+async fn temp_store() -> MetadataStore {
+    let dir = std::env::temp_dir().join(format!("t3-list-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let url = format!("sqlite://{}?mode=rwc", dir.join("t3.db").display());
+    let store = MetadataStore::new(&url).await.unwrap();
+    store
+        .create_bucket(
+            &Uuid::new_v4().to_string(),
+            "bucket",
+            "2026-01-01T00:00:00+00:00",
+            &ErasureProfile::default(),
+        )
+        .await
+        .unwrap();
+    for key in KEYS {
+        store
+            .insert_metadata(
+                "bucket",
+                key,
+                &NewObjectMetadata {
+                    file_name: None,
+                    content_type: None,
+                    content_length: 1,
+                    user_metadata: None,
+                    md5: None,
+                    sha256: None,
+                    generation: None,
+                    blob_id: None,
+                    profile: ErasureProfile::default(),
+                    packed: false,
+                    part_count: None,
+                },
+            )
+            .await
+            .unwrap();
+    }
+    store
+}
+
+/// `query` を付けて一覧を取得し、ステータスとレスポンスのJSONを返す。
+async fn list(store: &MetadataStore, query: &str) -> (StatusCode, Value) {
+    let uri: Uri = format!("/bucket/bucket?{}", query).parse().unwrap();
+    let response = list_objects(
+        Path("bucket".to_string()),
+        Query::try_from_uri(&uri).unwrap(),
+        State(store.clone()),
+    )
+    .await
+    .into_response();
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap())
+}
+
+fn keys(page: &Value) -> Vec<String> {
+    page["data"]["contents"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["key"].as_str().unwrap().to_string())
+        .collect()
+}
+
+fn common_prefixes(page: &Value) -> Vec<String> {
+    page["data"]["common_prefixes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|prefix| prefix.as_str().unwrap().to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn lists_keys_with_prefix() {
+    let store = temp_store().await;
+    let (status, page) = list(&store, "prefix=photos/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        keys(&page),
+        [
+            "photos/2024/a.jpg",
+            "photos/2024/b.jpg",
+            "photos/2025/c.jpg",
+            "photos/cat.jpg"
+        ]
+    );
+    assert_eq!(page["data"]["is_truncated"], false);
+
+    // プレフィックスの直後に並ぶキーは含まない
+    let (_, page) = list(&store, "prefix=photos").await;
+    assert_eq!(keys(&page), &KEYS[1..7]);
+    let (_, page) = list(&store, "prefix=zzz").await;
+    assert!(keys(&page).is_empty());
+}
+
+#[tokio::test]
+async fn folds_keys_into_common_prefixes() {
+    let store = temp_store().await;
+    let (_, page) = list(&store, "prefix=photos/&delimiter=/").await;
+    assert_eq!(keys(&page), ["photos/cat.jpg"]);
+    assert_eq!(common_prefixes(&page), ["photos/2024/", "photos/2025/"]);
+
+    let (_, page) = list(&store, "delimiter=/").await;
+    assert_eq!(keys(&page), ["a.txt", "photos0", "photosx", "photot"]);
+    assert_eq!(common_prefixes(&page), ["photos/", "videos/"]);
+}
+
+#[tokio::test]
+async fn pages_follow_continuation_tokens() {
+    let store = temp_store().await;
+    for query in ["", "delimiter=/", "prefix=photos/&delimiter=/"] {
+        let (_, all) = list(&store, query).await;
+        let (mut listed, mut prefixes) = (Vec::new(), Vec::new());
+        let mut token: Option<String> = None;
+        loop {
+            let paged = match &token {
+                Some(token) => format!("{}&max-keys=2&continuation-token={}", query, token),
+                None => format!("{}&max-keys=2", query),
+            };
+            let (status, page) = list(&store, &paged).await;
+            assert_eq!(status, StatusCode::OK);
+            assert!(keys(&page).len() + common_prefixes(&page).len() <= 2);
+            listed.extend(keys(&page));
+            prefixes.extend(common_prefixes(&page));
+            if page["data"]["is_truncated"] == false {
+                assert!(page["data"]["next_continuation_token"].is_null());
+                break;
+            }
+            token = Some(
+                page["data"]["next_continuation_token"]
+                    .as_str()
+                    .unwrap()
+                    .to_string(),
+            );
+        }
+        // ページに分けても、一度に取得したときと同じエントリを重複なく返す
+        assert_eq!(listed, keys(&all), "{}", query);
+        assert_eq!(prefixes, common_prefixes(&all), "{}", query);
+    }
+}
+
+#[tokio::test]
+async fn rejects_invalid_continuation_tokens() {
+    let store = temp_store().await;
+    for token in ["abc", "zz", "ff", "%E3%81%82%E3%81%82"] {
+        let (status, page) = list(&store, &format!("continuation-token={}", token)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", token);
+        assert_eq!(page["status"], "error");
+    }
+}
@@ -0,0 +1,89 @@
+use std::path::PathBuf;
+use t3::db::{MetadataStore, NewObjectMetadata};
+use t3::profile::ErasureProfile;
+use uuid::Uuid;
+
+/// 一時ディレクトリのデータベースを開き、バケット `bucket` を作る。
+async fn temp_store() -> (MetadataStore, PathBuf) {
+    let dir = std::env::temp_dir().join(format!("t3-metadata-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let url = format!("sqlite://{}?mode=rwc", dir.join("t3.db").display());
+    let store = MetadataStore::new(&url).await.unwrap();
+    store
+        .create_bucket(
+            &Uuid::new_v4().to_string(),
+            "bucket",
+            "2026-01-01T00:00:00+00:00",
+            &ErasureProfile::default(),
+        )
+        .await
+        .unwrap();
+    (store, dir)
+}
+
+fn object<'a>(generation: &'a str, blob_id: &'a str) -> NewObjectMetadata<'a> {
+    NewObjectMetadata {
+        content_length: 3,
+        generation: Some(generation),
+        blob_id: Some(blob_id),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn overwriting_resets_scrub_results() {
+    let (store, dir) = temp_store().await;
+    store
+        .upsert_metadata("bucket", "key", &object("g1", "b1"))
+        .await
+        .unwrap();
+    let scrubbed_at = "2026-01-02T00:00:00+00:00";
+    assert!(
+        store
+            .update_health("bucket", "key", Some("g1"), "lost", scrubbed_at)
+            .await
+            .unwrap()
+    );
+
+    // 新しい世代のシャードはまだスクラブしていない
+    let previous = store
+        .upsert_metadata("bucket", "key", &object("g2", "b2"))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(previous.health.as_deref(), Some("lost"));
+    let current = store.get_metadata("bucket", "key").await.unwrap().unwrap();
+    assert_eq!(current.generation.as_deref(), Some("g2"));
+    assert_eq!(current.health, None);
+    assert_eq!(current.last_scrubbed_at, None);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn migrations_rebuild_baseline_tables() {
+    let dir = std::env::temp_dir().join(format!("t3-metadata-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let url = format!("sqlite://{}?mode=rwc", dir.join("t3.db").display());
+    // 空のデータベースで初期のマイグレーションを流したときに残る古い形のテーブル
+    let pool = sqlx::SqlitePool::connect(&url).await.unwrap();
+    for schema in [
+        include_str!("../migrations/20250505150450_object_metadata_table.sql"),
+        include_str!("../migrations/20250506180917_bucket_metadata_table.sql"),
+        "INSERT INTO bucket_metadata (id, bucket_name, created_at) VALUES ('b1', 'bucket', '2026-01-01T00:00:00+00:00')",
+    ] {
+        sqlx::raw_sql(schema).execute(&pool).await.unwrap();
+    }
+    pool.close().await;
+
+    let store = MetadataStore::new(&url).await.unwrap();
+    let bucket = store.get_bucket("bucket").await.unwrap().unwrap();
+    assert_eq!(bucket.id, "b1");
+    store
+        .upsert_metadata("bucket", "key", &object("g1", "b1"))
+        .await
+        .unwrap();
+    assert!(store.get_metadata("bucket", "key").await.unwrap().is_some());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,154 @@
+use futures::TryStreamExt;
+use std::sync::Arc;
+use t3::db::{ObjectPart, UploadedPart};
+use t3::encode::{ShardWriter, remove_shards};
+use t3::multipart::{ObjectReader, PartsReader, multipart_etag};
+use t3::profile::ErasureProfile;
+use t3::store::{MemoryShardStore, SharedShardStore};
+
+fn sample(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+fn uploaded(part_number: i64, md5: &str) -> UploadedPart {
+    UploadedPart {
+        upload_id: "upload".to_string(),
+        part_number,
+        blob_id: format!("part-{}", part_number),
+        size: 0,
+        md5: md5.to_string(),
+        sha256: String::new(),
+        created_at: String::new(),
+    }
+}
+
+#[test]
+fn etag_is_md5_of_part_md5s() {
+    // "a" と "b" のMD5を繋げたもののMD5
+    let a = uploaded(1, "0cc175b9c0f1b6a831c399e269772661");
+    let b = uploaded(2, "92eb5ffee6ae2fec3ad71c777531578f");
+    let etag = multipart_etag(&[&a, &b]).unwrap();
+    assert_eq!(etag, "96e024ba2074fe77e8e965ba43a704be-2");
+    // パートの順番が変わればETagも変わる
+    assert_ne!(multipart_etag(&[&b, &a]).unwrap(), etag);
+    assert!(multipart_etag(&[&uploaded(1, "not hex")]).is_err());
+}
+
+#[tokio::test]
+async fn parts_are_read_in_order_across_boundaries() {
+    let memory = MemoryShardStore::new();
+    let store: SharedShardStore = Arc::new(memory.clone());
+    let profile = ErasureProfile::new(2, 1, 4096).unwrap();
+    let sizes = [10_000, 1, 8192, 333];
+
+    let mut parts = Vec::new();
+    let mut expected = Vec::new();
+    for (i, size) in sizes.iter().enumerate() {
+        let part_blob_id = t3::new_blob_id();
+        let data = sample(*size, i as u8 + 1);
+        let mut writer = ShardWriter::create(&store, &part_blob_id, profile)
+            .await
+            .unwrap();
+        writer.write(&data).await.unwrap();
+        let sha256 = writer.finish().await.unwrap().sha256;
+        parts.push(ObjectPart {
+            blob_id: "object".to_string(),
+            part_number: i as i64 + 1,
+            part_blob_id,
+            position: expected.len() as i64,
+            size: *size as i64,
+            sha256,
+        });
+        expected.extend_from_slice(&data);
+    }
+    let len = expected.len() as u64;
+
+    for range in [
+        0..len,
+        9_990..10_010,
+        10_000..10_001,
+        10_001..18_193,
+        len - 1..len,
+    ] {
+        let reader = ObjectReader::Parts(PartsReader::new(&store, profile, parts.clone(), None));
+        let data: Vec<_> = reader
+            .into_stream(range.clone())
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            data.concat(),
+            &expected[range.start as usize..range.end as usize],
+            "range {:?}",
+            range
+        );
+    }
+
+    let mut reader = PartsReader::new(&store, profile, parts.clone(), None);
+    let mut range = len - 1..len + 1;
+    assert!(reader.read_next(&mut range).await.is_err());
+
+    for part in &parts {
+        remove_shards(&store, &part.part_blob_id, profile).await;
+    }
+    assert!(memory.is_empty());
+}
+
+#[tokio::test]
+async fn verified_stream_rejects_corrupted_part() {
+    let memory = MemoryShardStore::new();
+    let store: SharedShardStore = Arc::new(memory.clone());
+    let profile = ErasureProfile::new(2, 1, 4096).unwrap();
+    let sizes = [10_000, 20_000, 333];
+
+    let mut parts = Vec::new();
+    let mut expected = Vec::new();
+    for (i, size) in sizes.iter().enumerate() {
+        let part_blob_id = t3::new_blob_id();
+        let data = sample(*size, i as u8 + 1);
+        let mut writer = ShardWriter::create(&store, &part_blob_id, profile)
+            .await
+            .unwrap();
+        writer.write(&data).await.unwrap();
+        let sha256 = writer.finish().await.unwrap().sha256;
+        parts.push(ObjectPart {
+            blob_id: "object".to_string(),
+            part_number: i as i64 + 1,
+            part_blob_id,
+            position: expected.len() as i64,
+            size: *size as i64,
+            sha256,
+        });
+        expected.extend_from_slice(&data);
+    }
+
+    let reader = PartsReader::new(&store, profile, parts.clone(), None);
+    let data: Vec<_> = reader.into_verified_stream().try_collect().await.unwrap();
+    assert_eq!(data.concat(), expected);
+
+    // シャードとしては正しいが、記録したダイジェストと違うデータに置き換える
+    let mut writer = ShardWriter::create(&store, &parts[1].part_blob_id, profile)
+        .await
+        .unwrap();
+    writer.write(&sample(sizes[1], 0xff)).await.unwrap();
+    writer.finish().await.unwrap();
+
+    let reader = PartsReader::new(&store, profile, parts.clone(), None);
+    let mut stream = Box::pin(reader.into_verified_stream());
+    let mut received = 0;
+    let result = loop {
+        match stream.try_next().await {
+            Ok(Some(data)) => received += data.len(),
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    assert!(result.is_err());
+    // 壊れたパートの最後のチャンクは送らない
+    assert!(received < sizes[0] + sizes[1]);
+
+    for part in &parts {
+        remove_shards(&store, &part.part_blob_id, profile).await;
+    }
+    assert!(memory.is_empty());
+}
@@ -0,0 +1,251 @@
+use axum::extract::Request;
+use axum::http::StatusCode;
+use axum::middleware::{self, Next};
+use axum::response::IntoResponse;
+use bytes::Bytes;
+use futures::TryStreamExt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use t3::decode::ShardReader;
+use t3::encode::{ShardWriter, remove_shards};
+use t3::node;
+use t3::profile::ErasureProfile;
+use t3::remote::RemoteShardStore;
+use t3::store::{LocalShardStore, MemoryShardStore, ShardStat, SharedShardStore};
+use tokio::net::TcpListener;
+use uuid::Uuid;
+
+fn sample(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 + 1).collect()
+}
+
+fn profile() -> ErasureProfile {
+    ErasureProfile::new(6, 3, 16 * 1024).unwrap()
+}
+
+struct TestNode {
+    url: String,
+    shards: MemoryShardStore,
+    // trueの間は全てのリクエストに503を返す
+    down: Arc<AtomicBool>,
+    // trueの間は全てのリクエストに応答しない
+    stalled: Arc<AtomicBool>,
+}
+
+/// シャードをメモリに置くノードを `count` 個起動する。
+async fn start_nodes(count: usize) -> Vec<TestNode> {
+    let mut nodes = Vec::new();
+    for _ in 0..count {
+        let shards = MemoryShardStore::new();
+        let down = Arc::new(AtomicBool::new(false));
+        let stalled = Arc::new(AtomicBool::new(false));
+        let (down_flag, stalled_flag) = (down.clone(), stalled.clone());
+        let app = node::app(Arc::new(shards.clone())).layer(middleware::from_fn(
+            move |request: Request, next: Next| {
+                let down = down_flag.load(Ordering::SeqCst);
+                let stalled = stalled_flag.load(Ordering::SeqCst);
+                async move {
+                    if stalled {
+                        std::future::pending::<()>().await;
+                    }
+                    if down {
+                        StatusCode::SERVICE_UNAVAILABLE.into_response()
+                    } else {
+                        next.run(request).await
+                    }
+                }
+            },
+        ));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(axum::serve(listener, app).into_future());
+        nodes.push(TestNode {
+            url,
+            shards,
+            down,
+            stalled,
+        });
+    }
+    nodes
+}
+
+fn connect(nodes: &[TestNode]) -> SharedShardStore {
+    let urls: Vec<&str> = nodes.iter().map(|node| node.url.as_str()).collect();
+    Arc::new(RemoteShardStore::new(&urls).unwrap())
+}
+
+/// 先頭から `down` 個のノードを落とし、残りを戻す。
+fn set_down(nodes: &[TestNode], down: usize) {
+    for (i, node) in nodes.iter().enumerate() {
+        node.down.store(i < down, Ordering::SeqCst);
+    }
+}
+
+async fn write_object(
+    store: &SharedShardStore,
+    object_id: &str,
+    data: &[u8],
+) -> anyhow::Result<()> {
+    let mut writer = ShardWriter::create(store, object_id, profile()).await?;
+    writer.write(data).await?;
+    writer.finish().await?;
+    Ok(())
+}
+
+async fn read_object(
+    store: &SharedShardStore,
+    object_id: &str,
+    len: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let reader = ShardReader::open(store, object_id, len as u64, profile()).await?;
+    let parts: Vec<_> = reader.into_stream(0..len as u64).try_collect().await?;
+    Ok(parts.concat())
+}
+
+#[tokio::test]
+async fn node_serves_shard_api() {
+    let nodes = start_nodes(1).await;
+    let store = connect(&nodes);
+    let object_id = format!("node/{}", Uuid::new_v4());
+
+    assert_eq!(store.stat(&object_id, 0).await.unwrap(), None);
+    store
+        .put(&object_id, 0, Bytes::from_static(b"hello shard"))
+        .await
+        .unwrap();
+    assert_eq!(
+        store.stat(&object_id, 0).await.unwrap(),
+        Some(ShardStat { len: 11 })
+    );
+    assert_eq!(
+        &store.get(&object_id, 0, 6..11).await.unwrap()[..],
+        b"shard"
+    );
+    assert!(store.get(&object_id, 0, 6..20).await.is_err());
+    assert_eq!(store.list(&object_id).await.unwrap(), vec![0]);
+    assert!(store.delete(&object_id, 0).await.unwrap());
+    assert!(!store.delete(&object_id, 0).await.unwrap());
+    // ノードが1つなら、2つ目のシャードは置けない
+    assert!(store.stat(&object_id, 1).await.is_err());
+    assert!(nodes[0].shards.is_empty());
+}
+
+#[tokio::test]
+async fn shard_ids_cannot_escape_the_data_directory() {
+    let base = std::env::temp_dir().join(format!("t3-node-{}", Uuid::new_v4()));
+    let root = base.join("data");
+    let app = node::app(Arc::new(LocalShardStore::with_root(&root)));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(axum::serve(listener, app).into_future());
+
+    let client = reqwest::Client::new();
+    let shard_url = format!("{}/shards/..%2F..%2F..%2Fescaped/0", url);
+    let response = client.put(&shard_url).body("shard").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = client.get(&shard_url).send().await.unwrap();
+    assert_eq!(response.bytes().await.unwrap(), "shard");
+
+    // シャードはデータディレクトリの中に1つのファイルとして置かれる
+    assert_eq!(std::fs::read_dir(&base).unwrap().count(), 1);
+    let files: Vec<_> = std::fs::read_dir(root.join("outputs"))
+        .unwrap()
+        .flat_map(|dir| std::fs::read_dir(dir.unwrap().path()).unwrap())
+        .map(|file| file.unwrap().path())
+        .collect();
+    assert_eq!(files.len(), 1);
+    assert!(files[0].is_file());
+
+    let response = client.delete(&shard_url).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    std::fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn local_shard_ids_have_no_separators() {
+    assert_eq!(node::local_shard_id("abc.def"), "abc.def");
+    assert_eq!(node::local_shard_id("../a\\b%"), "..%2Fa%5Cb%25");
+    assert_eq!(node::local_shard_id("a\0b"), "a%00b");
+}
+
+#[tokio::test]
+async fn reads_tolerate_parity_shards_unreachable_nodes() {
+    let profile = profile();
+    let nodes = start_nodes(profile.total_shards()).await;
+    let store = connect(&nodes);
+    let object_id = format!("node-{}", Uuid::new_v4());
+    let len = profile.stripe_len() * 2 + 13;
+    let data = sample(len);
+    write_object(&store, &object_id, &data).await.unwrap();
+
+    // ノードがシャード数以上あれば、各ノードにシャードが1つずつ置かれる
+    assert!(nodes.iter().all(|node| node.shards.len() == 1));
+    assert_eq!(read_object(&store, &object_id, len).await.unwrap(), data);
+
+    set_down(&nodes, profile.parity_shards);
+    assert_eq!(read_object(&store, &object_id, len).await.unwrap(), data);
+    set_down(&nodes, profile.parity_shards + 1);
+    assert!(read_object(&store, &object_id, len).await.is_err());
+
+    set_down(&nodes, 0);
+    remove_shards(&store, &object_id, profile).await;
+    assert!(nodes.iter().all(|node| node.shards.is_empty()));
+}
+
+#[tokio::test]
+async fn writes_succeed_with_quorum() {
+    let profile = profile();
+    let nodes = start_nodes(profile.total_shards()).await;
+    let store = connect(&nodes);
+    let data = sample(profile.stripe_len() + 1);
+
+    // データシャード数のノードに書ければ成功し、読み出せる
+    set_down(&nodes, profile.parity_shards);
+    let object_id = format!("node-{}", Uuid::new_v4());
+    write_object(&store, &object_id, &data).await.unwrap();
+    let total: usize = nodes.iter().map(|node| node.shards.len()).sum();
+    assert_eq!(total, profile.data_shards);
+    assert_eq!(
+        read_object(&store, &object_id, data.len()).await.unwrap(),
+        data
+    );
+    remove_shards(&store, &object_id, profile).await;
+
+    // クォーラムに届かなければ失敗し、書けたシャードも残さない
+    set_down(&nodes, profile.parity_shards + 1);
+    let object_id = format!("node-{}", Uuid::new_v4());
+    assert!(write_object(&store, &object_id, &data).await.is_err());
+    assert!(nodes.iter().all(|node| node.shards.is_empty()));
+}
+
+#[tokio::test]
+async fn stalled_nodes_are_treated_as_unavailable() {
+    let profile = profile();
+    let nodes = start_nodes(profile.total_shards()).await;
+    let timeout = Duration::from_millis(300);
+    let urls: Vec<&str> = nodes.iter().map(|node| node.url.as_str()).collect();
+    let store: SharedShardStore = Arc::new(
+        RemoteShardStore::new(&urls)
+            .unwrap()
+            .with_request_timeout(timeout),
+    );
+    let object_id = format!("node-{}", Uuid::new_v4());
+    let len = profile.stripe_len() * 2 + 13;
+    let data = sample(len);
+    write_object(&store, &object_id, &data).await.unwrap();
+
+    // 応答しないノードのシャードは時間切れで欠損として扱い、残りのシャードから復元する
+    for node in &nodes[..profile.parity_shards] {
+        node.stalled.store(true, Ordering::SeqCst);
+    }
+    let started = Instant::now();
+    assert_eq!(read_object(&store, &object_id, len).await.unwrap(), data);
+    // シャードは同時に開くので、応答しないノードが複数あっても待つのはその1つ分だけ
+    assert!(started.elapsed() < timeout * 4, "{:?}", started.elapsed());
+
+    for node in &nodes {
+        node.stalled.store(false, Ordering::SeqCst);
+    }
+    remove_shards(&store, &object_id, profile).await;
+}
@@ -0,0 +1,305 @@
+use futures::TryStreamExt;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use t3::db::{MetadataStore, NewObjectMetadata, PackEntry};
+use t3::decode::{ShardLocation, ShardReader};
+use t3::env::NUM_OUTPUT_DIRS;
+use t3::pack::{PackKey, compact, write_shards};
+use t3::profile::ErasureProfile;
+use t3::store::{LocalShardStore, SharedShardStore};
+use uuid::Uuid;
+
+fn local(root: &Path) -> SharedShardStore {
+    Arc::new(LocalShardStore::with_root(root))
+}
+
+/// `root` の下のデータベースを開き、バケット `bucket` を作る。
+async fn temp_store(root: &Path) -> MetadataStore {
+    std::fs::create_dir_all(root).unwrap();
+    let url = format!("sqlite://{}?mode=rwc", root.join("t3.db").display());
+    let store = MetadataStore::new(&url).await.unwrap();
+    store
+        .create_bucket(
+            &Uuid::new_v4().to_string(),
+            "bucket",
+            "2026-01-01T00:00:00+00:00",
+            &ErasureProfile::default(),
+        )
+        .await
+        .unwrap();
+    store
+}
+
+fn sample(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+fn locations(entries: &[PackEntry], total: usize) -> Vec<Option<ShardLocation>> {
+    let mut locations = vec![None; total];
+    for entry in entries {
+        locations[entry.shard_index as usize] = Some(ShardLocation {
+            path: PathBuf::from(&entry.segment),
+            offset: entry.position as u64,
+        });
+    }
+    locations
+}
+
+async fn read(
+    root: &Path,
+    shard_id: &str,
+    len: usize,
+    profile: ErasureProfile,
+    locations: Vec<Option<ShardLocation>>,
+) -> Vec<u8> {
+    let reader = ShardReader::open_at(&local(root), shard_id, len as u64, profile, locations)
+        .await
+        .unwrap();
+    let parts: Vec<_> = reader
+        .into_stream(0..len as u64)
+        .try_collect()
+        .await
+        .unwrap();
+    parts.concat()
+}
+
+#[tokio::test]
+async fn packed_objects_share_segments() {
+    let root = std::env::temp_dir().join(format!("t3-pack-{}", Uuid::new_v4()));
+    let profile = ErasureProfile::default().replicated();
+    let total = profile.total_shards();
+    let mut segments = BTreeSet::new();
+    let mut objects = Vec::new();
+    for (seed, len) in [(1u8, 0usize), (2, 10), (3, 5000)] {
+        let object_id = format!("pack-{}", Uuid::new_v4());
+        let generation = Uuid::new_v4().simple().to_string();
+        let data = sample(len, seed);
+        let indices: Vec<usize> = (0..total).collect();
+        let shard_id = t3::shard_id(&object_id, Some(&generation));
+        let key = PackKey {
+            bucket_name: "bucket",
+            object_id: &object_id,
+            generation: &generation,
+            shard_id: &shard_id,
+        };
+        let entries = write_shards(&root, key, profile, &data, &indices)
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), total);
+        segments.extend(entries.iter().map(|entry| entry.segment.clone()));
+        objects.push((object_id, generation, data, entries));
+    }
+    // シャードごとにファイルを作らず、ディレクトリごとのセグメントに追記する
+    assert!(segments.len() <= t3::env::NUM_OUTPUT_DIRS);
+    assert!(
+        objects
+            .iter()
+            .any(|(_, _, _, entries)| entries.iter().any(|e| e.position > 0))
+    );
+
+    for (object_id, generation, data, entries) in &objects {
+        let shard_id = t3::shard_id(object_id, Some(generation));
+        let read_back = read(
+            &root,
+            &shard_id,
+            data.len(),
+            profile,
+            locations(entries, total),
+        )
+        .await;
+        assert_eq!(&read_back, data);
+
+        // 位置が分かっている複製が1つあれば読める
+        let mut only_last = vec![None; total];
+        only_last[total - 1] = locations(entries, total).pop().unwrap();
+        assert_eq!(
+            &read(&root, &shard_id, data.len(), profile, only_last).await,
+            data
+        );
+
+        // 他のオブジェクトの位置を指していたら読まない
+        let (other_id, other_generation, _, _) = objects
+            .iter()
+            .find(|(id, _, _, _)| id != object_id)
+            .unwrap();
+        let other = t3::shard_id(other_id, Some(other_generation));
+        assert!(
+            ShardReader::open_at(
+                &local(&root),
+                &other,
+                data.len() as u64,
+                profile,
+                locations(entries, total)
+            )
+            .await
+            .is_err()
+        );
+    }
+
+    assert!(
+        segments
+            .iter()
+            .all(|segment| segment.starts_with(&*root.to_string_lossy()))
+    );
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+/// パックファイルに置いたオブジェクト
+struct Packed {
+    object_id: String,
+    generation: String,
+    data: Vec<u8>,
+}
+
+impl Packed {
+    fn shard_id(&self) -> String {
+        t3::shard_id(&self.object_id, Some(&self.generation))
+    }
+
+    fn key<'a>(&'a self, shard_id: &'a str) -> PackKey<'a> {
+        PackKey {
+            bucket_name: "bucket",
+            object_id: &self.object_id,
+            generation: &self.generation,
+            shard_id,
+        }
+    }
+
+    async fn entries(&self, store: &MetadataStore) -> Vec<PackEntry> {
+        store
+            .get_pack_entries("bucket", &self.object_id, &self.generation)
+            .await
+            .unwrap()
+    }
+}
+
+/// シャードを全てパックファイルに書き、メタデータと位置を登録する。
+async fn write_packed(
+    root: &Path,
+    store: &MetadataStore,
+    profile: ErasureProfile,
+    data: Vec<u8>,
+) -> Packed {
+    let object = Packed {
+        object_id: format!("pack-{}", Uuid::new_v4()),
+        generation: Uuid::new_v4().simple().to_string(),
+        data,
+    };
+    let shard_id = object.shard_id();
+    let indices: Vec<usize> = (0..profile.total_shards()).collect();
+    let entries = write_shards(root, object.key(&shard_id), profile, &object.data, &indices)
+        .await
+        .unwrap();
+    let metadata = NewObjectMetadata {
+        content_length: object.data.len() as i64,
+        generation: Some(&object.generation),
+        profile,
+        packed: true,
+        ..Default::default()
+    };
+    store
+        .upsert_metadata("bucket", &object.object_id, &metadata)
+        .await
+        .unwrap();
+    store.put_pack_entries(&entries).await.unwrap();
+    object
+}
+
+#[tokio::test]
+async fn compaction_moves_live_shards_and_removes_old_segments() {
+    let root = std::env::temp_dir().join(format!("t3-compact-{}", Uuid::new_v4()));
+    let store = temp_store(&root).await;
+    // シャード数をディレクトリ数に合わせると、どのオブジェクトも各ディレクトリのセグメントに1つずつシャードを置く
+    let profile = ErasureProfile::new(1, NUM_OUTPUT_DIRS - 1, 64 * 1024).unwrap();
+    let kept = write_packed(&root, &store, profile, sample(100, 1)).await;
+    let scrubbed = write_packed(&root, &store, profile, sample(100, 2)).await;
+    let deleted = write_packed(&root, &store, profile, sample(20_000, 3)).await;
+    store
+        .delete_metadata("bucket", &deleted.object_id)
+        .await
+        .unwrap();
+    let old_segments: BTreeSet<String> = kept
+        .entries(&store)
+        .await
+        .into_iter()
+        .map(|entry| entry.segment)
+        .collect();
+    assert_eq!(old_segments.len(), NUM_OUTPUT_DIRS);
+
+    // スクラブがシャードを書き直して位置を変えたら、コンパクションは古い位置から移さない
+    let stale = scrubbed.entries(&store).await.remove(0);
+    let shard_id = scrubbed.shard_id();
+    let rewritten = write_shards(
+        &root,
+        scrubbed.key(&shard_id),
+        profile,
+        &scrubbed.data,
+        &[0],
+    )
+    .await
+    .unwrap();
+    store.put_pack_entries(&rewritten).await.unwrap();
+    assert!(!store.move_pack_entry(&stale, "elsewhere", 0).await.unwrap());
+    assert_eq!(scrubbed.entries(&store).await[0], rewritten[0]);
+
+    // 最近追記したセグメントは書き直さない
+    let summary = compact(&store, &root, Duration::from_secs(3600))
+        .await
+        .unwrap();
+    assert_eq!(summary.segments, 0);
+
+    let grace = Duration::from_millis(300);
+    tokio::time::sleep(grace * 2).await;
+    let compaction = tokio::spawn({
+        let (store, root) = (store.clone(), root.clone());
+        async move { compact(&store, &root, grace).await.unwrap() }
+    });
+    // 生きているシャードを移した後も、古い位置を読んでいるGETのために猶予期間の間は古いセグメントを残す
+    loop {
+        let moved = kept.entries(&store).await;
+        if moved
+            .iter()
+            .all(|entry| !old_segments.contains(&entry.segment))
+        {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(
+        old_segments
+            .iter()
+            .all(|segment| Path::new(segment).exists())
+    );
+
+    let summary = compaction.await.unwrap();
+    assert_eq!(summary.segments, NUM_OUTPUT_DIRS as u64);
+    assert_eq!(summary.moved_shards, 2 * NUM_OUTPUT_DIRS as u64);
+    assert!(summary.reclaimed_bytes >= (deleted.data.len() * NUM_OUTPUT_DIRS) as u64);
+    assert!(
+        old_segments
+            .iter()
+            .all(|segment| !Path::new(segment).exists())
+    );
+    assert!(deleted.entries(&store).await.is_empty());
+
+    for object in [&kept, &scrubbed] {
+        let entries = object.entries(&store).await;
+        assert!(
+            entries
+                .iter()
+                .all(|entry| !old_segments.contains(&entry.segment))
+        );
+        let read_back = read(
+            &root,
+            &object.shard_id(),
+            object.data.len(),
+            profile,
+            locations(&entries, profile.total_shards()),
+        )
+        .await;
+        assert_eq!(read_back, object.data);
+    }
+    std::fs::remove_dir_all(&root).unwrap();
+}
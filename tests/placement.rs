@@ -0,0 +1,225 @@
+use axum::body::to_bytes;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use bytes::{Bytes, BytesMut};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use t3::db::{MetadataStore, NewObjectMetadata};
+use t3::encode::{encode_file, remove_shards};
+use t3::handler::get::get_object;
+use t3::placement::{Placement, Target};
+use t3::profile::ErasureProfile;
+use t3::server::ServerConfig;
+use t3::store::{LocalShardStore, ShardStore, SharedShardStore};
+use t3::{legacy_output_dir_name, output_dir_name, shard_filename};
+use uuid::Uuid;
+
+fn targets(count: usize) -> Vec<Target> {
+    (1..=count)
+        .map(|i| Target::new(format!("target{}", i)))
+        .collect()
+}
+
+#[test]
+fn shards_of_an_object_go_to_distinct_targets() {
+    let placement = Placement::new(targets(9)).unwrap();
+    for n in 0..200 {
+        let key = format!("object-{}", n);
+        let placed = placement.place(&key, 9).unwrap();
+        assert_eq!(placed.iter().collect::<BTreeSet<_>>().len(), 9, "{}", key);
+        // シャードごとに求めても同じ置き場所になる
+        for (index, &target) in placed.iter().enumerate() {
+            assert_eq!(placement.target_for(&key, index).unwrap(), target);
+        }
+    }
+    // 置き場所より多いシャードは同じ置き場所に重なるので置けない
+    assert!(placement.place("object", 12).is_err());
+    assert!(placement.target_for("object", 9).is_err());
+    assert!(output_dir_name("object", 9).is_err());
+
+    assert!(Placement::new(Vec::new()).is_err());
+    assert!(Placement::new(vec![Target::new("a"), Target::new("a")]).is_err());
+}
+
+#[test]
+fn placement_is_stable() {
+    let dirs = (1..=9)
+        .map(|i| Target::new(format!("outputs/output{}", i)))
+        .collect();
+    let placement = Placement::new(dirs).unwrap();
+    // Rustのバージョンに依存しないハッシュなので、配置は常に同じになる
+    assert_eq!(
+        placement.place("photos/cat.jpg", 9).unwrap(),
+        vec![4, 5, 8, 1, 2, 6, 0, 3, 7]
+    );
+    assert_eq!(
+        output_dir_name("photos/cat.jpg", 0).unwrap(),
+        std::path::PathBuf::from("outputs/output5")
+    );
+}
+
+#[test]
+fn adding_a_target_moves_few_shards() {
+    let before = Placement::new(targets(9)).unwrap();
+    let after = Placement::new(targets(10)).unwrap();
+    let mut moved = 0;
+    for n in 0..1000 {
+        let key = format!("object-{}", n);
+        let (old, new) = (
+            before.target_for(&key, 0).unwrap(),
+            after.target_for(&key, 0).unwrap(),
+        );
+        // 動くのは新しい置き場所に移るものだけ
+        if old != new {
+            assert_eq!(new, 9);
+            moved += 1;
+        }
+    }
+    assert!(moved > 0 && moved < 200, "{}", moved);
+}
+
+#[test]
+fn shards_are_spread_across_zones() {
+    let mut targets = Vec::new();
+    for zone in ["a", "b", "c"] {
+        for i in 0..3 {
+            targets.push(Target::in_zone(format!("{}{}", zone, i), zone));
+        }
+    }
+    let placement = Placement::new(targets.clone()).unwrap();
+    for n in 0..100 {
+        let key = format!("object-{}", n);
+        let zones = |count: usize| {
+            let mut zones: HashMap<&str, usize> = HashMap::new();
+            for target in placement.place(&key, count).unwrap() {
+                *zones
+                    .entry(targets[target].zone.as_deref().unwrap())
+                    .or_default() += 1;
+            }
+            zones
+        };
+        // ゾーンの数までは全て別々のゾーンに、それを超えたら均等に置く
+        assert!(zones(3).values().all(|&count| count == 1));
+        assert!(zones(6).values().all(|&count| count == 2));
+        assert!(zones(9).values().all(|&count| count == 3));
+    }
+}
+
+#[tokio::test]
+async fn local_store_reads_shards_from_legacy_directories() {
+    let root = std::env::temp_dir().join(format!("t3-placement-{}", Uuid::new_v4()));
+    let store = LocalShardStore::with_root(&root);
+    // 古い配置と今の配置が違うシャードを探して、古い置き場所に置く
+    let (object_id, index) = (0..)
+        .map(|n| (format!("legacy-{}", n), n % 9))
+        .find(|(id, i)| legacy_output_dir_name(id, *i) != output_dir_name(id, *i).unwrap())
+        .unwrap();
+    let legacy_dir = root.join(legacy_output_dir_name(&object_id, index));
+    std::fs::create_dir_all(&legacy_dir).unwrap();
+    std::fs::write(
+        legacy_dir.join(shard_filename(&object_id, index)),
+        b"legacy",
+    )
+    .unwrap();
+
+    assert_eq!(
+        store.get_all(&object_id, index).await.unwrap(),
+        Some(Bytes::from_static(b"legacy"))
+    );
+    assert_eq!(store.list(&object_id).await.unwrap(), vec![index]);
+
+    // 作り直したシャードは今の置き場所に置き、そちらを読む
+    store
+        .put(&object_id, index, Bytes::from_static(b"current"))
+        .await
+        .unwrap();
+    assert_eq!(
+        store.get_all(&object_id, index).await.unwrap(),
+        Some(Bytes::from_static(b"current"))
+    );
+    assert_eq!(store.list(&object_id).await.unwrap(), vec![index]);
+    assert!(store.delete(&object_id, index).await.unwrap());
+    assert_eq!(store.stat(&object_id, index).await.unwrap(), None);
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn baseline_objects_are_served_from_legacy_directories() {
+    let root = std::env::temp_dir().join(format!("t3-placement-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&root).unwrap();
+    let url = format!("sqlite://{}?mode=rwc", root.join("t3.db").display());
+    let store = MetadataStore::new(&url).await.unwrap();
+    let shards: SharedShardStore = Arc::new(LocalShardStore::with_root(root.join("data")));
+    let profile = ErasureProfile::default();
+    store
+        .create_bucket(
+            &Uuid::new_v4().to_string(),
+            "photos",
+            "2026-01-01T00:00:00+00:00",
+            &profile,
+        )
+        .await
+        .unwrap();
+
+    // ベースラインと同じく、ヘッダのないシャードを古い置き場所に書き、世代のないメタデータを登録する
+    let key = "cat.jpg";
+    let data: Vec<u8> = (0..3 * profile.stripe_len() + 10)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    let payloads = encode_file(
+        BytesMut::from(&data[..]),
+        profile.unstriped(data.len() as u64),
+    )
+    .unwrap();
+    for (i, payload) in payloads.iter().enumerate() {
+        let dir = root.join("data").join(legacy_output_dir_name(key, i));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(shard_filename(key, i)), payload).unwrap();
+    }
+    assert!(
+        (0..profile.total_shards())
+            .any(|i| legacy_output_dir_name(key, i) != output_dir_name(key, i).unwrap())
+    );
+    store
+        .insert_metadata(
+            "photos",
+            key,
+            &NewObjectMetadata {
+                file_name: Some("cat.jpg"),
+                content_type: Some("image/jpeg"),
+                content_length: data.len() as i64,
+                user_metadata: None,
+                md5: None,
+                sha256: None,
+                generation: None,
+                blob_id: None,
+                profile,
+                packed: false,
+                part_count: None,
+            },
+        )
+        .await
+        .unwrap();
+
+    let response = get_object(
+        Path(("photos".to_string(), key.to_string())),
+        State(store),
+        State(shards.clone()),
+        State(ServerConfig::default()),
+        HeaderMap::new(),
+    )
+    .await
+    .into_response();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(&body[..], &data[..]);
+
+    // 削除すると古い置き場所のシャードも消える
+    let mut indices = shards.list(key).await.unwrap();
+    indices.sort();
+    assert_eq!(indices, (0..profile.total_shards()).collect::<Vec<_>>());
+    remove_shards(&shards, key, profile).await;
+    assert!(shards.list(key).await.unwrap().is_empty());
+    std::fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,178 @@
+use futures::TryStreamExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use t3::decode::ShardReader;
+use t3::encode::{ShardWriter, remove_shards};
+use t3::profile::ErasureProfile;
+use t3::scrub::{Health, scrub_object};
+use t3::shard::decode_shard;
+use t3::store::{LocalShardStore, SharedShardStore};
+use uuid::Uuid;
+
+fn local(root: &Path) -> SharedShardStore {
+    Arc::new(LocalShardStore::with_root(root))
+}
+
+/// テストごとのデータディレクトリ。作業ディレクトリの `outputs` は使わない
+fn temp_root() -> PathBuf {
+    std::env::temp_dir().join(format!("t3-profile-{}", Uuid::new_v4()))
+}
+
+fn shard_path(root: &Path, object_id: &str, i: usize) -> PathBuf {
+    root.join(t3::output_dir_name(object_id, i).unwrap())
+        .join(t3::shard_filename(object_id, i))
+}
+
+fn sample(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 + 1).collect()
+}
+
+async fn write_object(
+    root: &Path,
+    object_id: &str,
+    data: &[u8],
+    profile: ErasureProfile,
+) -> String {
+    let mut writer = ShardWriter::create(&local(root), object_id, profile)
+        .await
+        .unwrap();
+    writer.write(data).await.unwrap();
+    writer.finish().await.unwrap().sha256
+}
+
+async fn read_object(root: &Path, object_id: &str, len: usize, profile: ErasureProfile) -> Vec<u8> {
+    let reader = ShardReader::open(&local(root), object_id, len as u64, profile)
+        .await
+        .unwrap();
+    let parts: Vec<_> = reader
+        .into_stream(0..len as u64)
+        .try_collect()
+        .await
+        .unwrap();
+    parts.concat()
+}
+
+#[test]
+fn profile_validation() {
+    assert!(ErasureProfile::new(4, 2, 64 * 1024).is_ok());
+    assert!(ErasureProfile::new(0, 2, 64 * 1024).is_err());
+    assert!(ErasureProfile::new(4, 0, 64 * 1024).is_err());
+    assert!(ErasureProfile::new(60, 5, 64 * 1024).is_err());
+    assert!(ErasureProfile::new(4, 2, 1024).is_err());
+    assert!(ErasureProfile::new(4, 2, 32 * 1024 * 1024).is_err());
+
+    // プロファイル導入前の行はデフォルトで書かれている
+    assert_eq!(
+        ErasureProfile::from_columns(None, None, None),
+        ErasureProfile::default()
+    );
+}
+
+#[tokio::test]
+async fn objects_with_different_profiles_coexist() {
+    let root = temp_root();
+    let small = ErasureProfile::new(4, 2, 64 * 1024).unwrap();
+    let default = ErasureProfile::default();
+    let small_id = format!("profile-{}", Uuid::new_v4());
+    let default_id = format!("profile-{}", Uuid::new_v4());
+    let len = small.stripe_len() * 3 + 17;
+    let data = sample(len);
+    write_object(&root, &small_id, &data, small).await;
+    write_object(&root, &default_id, &data, default).await;
+
+    assert!(!shard_path(&root, &small_id, small.total_shards()).exists());
+    assert_eq!(read_object(&root, &small_id, len, small).await, data);
+    assert_eq!(read_object(&root, &default_id, len, default).await, data);
+
+    // 書き込んだときと違うプロファイルでは読まない
+    assert!(
+        ShardReader::open(&local(&root), &small_id, len as u64, default)
+            .await
+            .is_err()
+    );
+
+    remove_shards(&local(&root), &small_id, small).await;
+    remove_shards(&local(&root), &default_id, default).await;
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn scrub_repairs_with_object_profile() {
+    let root = temp_root();
+    let profile = ErasureProfile::new(4, 2, 16 * 1024).unwrap();
+    let object_id = format!("profile-{}", Uuid::new_v4());
+    let len = profile.stripe_len() * 2 + 5;
+    let data = sample(len);
+    let sha256 = write_object(&root, &object_id, &data, profile).await;
+    for i in [0, 5] {
+        tokio::fs::remove_file(shard_path(&root, &object_id, i))
+            .await
+            .unwrap();
+    }
+
+    let report = scrub_object(
+        &local(&root),
+        &object_id,
+        len as u64,
+        profile,
+        Some(&sha256),
+    )
+    .await;
+    assert_eq!(report.health, Health::Repaired);
+    assert_eq!(report.damaged_shards, vec![0, 5]);
+    let report = scrub_object(
+        &local(&root),
+        &object_id,
+        len as u64,
+        profile,
+        Some(&sha256),
+    )
+    .await;
+    assert_eq!(report.health, Health::Healthy);
+    assert_eq!(read_object(&root, &object_id, len, profile).await, data);
+    remove_shards(&local(&root), &object_id, profile).await;
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn replicated_profile_stores_full_copies() {
+    let root = temp_root();
+    let profile = ErasureProfile::default().replicated();
+    assert!(profile.is_replicated());
+    assert_eq!(
+        profile.total_shards(),
+        ErasureProfile::default().parity_shards + 1
+    );
+    let object_id = format!("profile-{}", Uuid::new_v4());
+    let data = sample(3000);
+    write_object(&root, &object_id, &data, profile).await;
+
+    // どのシャードも元データをそのまま持つ
+    for i in 0..profile.total_shards() {
+        let content = tokio::fs::read(shard_path(&root, &object_id, i))
+            .await
+            .unwrap();
+        let (_, payload) = decode_shard(
+            content.into(),
+            &object_id,
+            i,
+            profile.data_shards,
+            profile.parity_shards,
+        )
+        .unwrap();
+        assert_eq!(&payload[..], &data[..]);
+    }
+
+    // 1つでも残っていれば読み出せる
+    for i in 1..profile.total_shards() {
+        tokio::fs::remove_file(shard_path(&root, &object_id, i))
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        read_object(&root, &object_id, data.len(), profile).await,
+        data
+    );
+    remove_shards(&local(&root), &object_id, profile).await;
+    std::fs::remove_dir_all(&root).unwrap();
+}
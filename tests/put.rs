@@ -0,0 +1,162 @@
+use axum::body::Body;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use axum::response::IntoResponse;
+use bytes::Bytes;
+use futures::stream;
+use std::sync::Arc;
+use t3::db::MetadataStore;
+use t3::handler::put::{file_name_from_disposition, put_object};
+use t3::profile::ErasureProfile;
+use t3::server::ServerConfig;
+use t3::store::{MemoryShardStore, SharedShardStore};
+use tokio::sync::Barrier;
+use uuid::Uuid;
+
+#[test]
+fn reads_file_name_from_content_disposition() {
+    let cases = [
+        ("attachment; filename=\"cat.jpg\"", Some("cat.jpg")),
+        ("inline; filename=report.pdf", Some("report.pdf")),
+        ("attachment; FileName = \"a b.txt\"", Some("a b.txt")),
+        // filename* があればそちらを優先する
+        (
+            "attachment; filename=\"fallback.jpg\"; filename*=UTF-8''%E5%86%99%E7%9C%9F.jpg",
+            Some("写真.jpg"),
+        ),
+        (
+            "attachment; filename*=ISO-8859-1''x.txt; filename=\"x.txt\"",
+            Some("x.txt"),
+        ),
+        (
+            "attachment; filename*=UTF-8''%ZZ; filename=ok.txt",
+            Some("ok.txt"),
+        ),
+        ("attachment", None),
+        ("attachment; filename=\"\"", None),
+    ];
+    for (value, expected) in cases {
+        assert_eq!(
+            file_name_from_disposition(value).as_deref(),
+            expected,
+            "{}",
+            value
+        );
+    }
+}
+
+/// 一時ディレクトリのデータベースを開き、バケット `bucket` を作る。シャードはメモリに置く。
+async fn temp_stores() -> (MetadataStore, MemoryShardStore, SharedShardStore) {
+    let dir = std::env::temp_dir().join(format!("t3-put-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let url = format!("sqlite://{}?mode=rwc", dir.join("t3.db").display());
+    let store = MetadataStore::new(&url).await.unwrap();
+    store
+        .create_bucket(
+            &Uuid::new_v4().to_string(),
+            "bucket",
+            "2026-01-01T00:00:00+00:00",
+            &ErasureProfile::default(),
+        )
+        .await
+        .unwrap();
+    let memory = MemoryShardStore::new();
+    let shards: SharedShardStore = Arc::new(memory.clone());
+    (store, memory, shards)
+}
+
+async fn put(
+    store: &MetadataStore,
+    shards: &SharedShardStore,
+    key: &str,
+    body: Body,
+    if_none_match: bool,
+) -> StatusCode {
+    let mut headers = HeaderMap::new();
+    if if_none_match {
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("*"));
+    }
+    put_object(
+        Path(("bucket".to_string(), key.to_string())),
+        State(store.clone()),
+        State(shards.clone()),
+        State(ServerConfig::default()),
+        headers,
+        body,
+    )
+    .await
+    .into_response()
+    .status()
+}
+
+#[tokio::test]
+async fn if_none_match_does_not_overwrite_existing_objects() {
+    let (store, memory, shards) = temp_stores().await;
+    assert_eq!(
+        put(&store, &shards, "key", Body::from("first"), true).await,
+        StatusCode::OK
+    );
+    let first = store.get_metadata("bucket", "key").await.unwrap().unwrap();
+
+    assert_eq!(
+        put(&store, &shards, "key", Body::from("second"), true).await,
+        StatusCode::PRECONDITION_FAILED
+    );
+    let stored = store.get_metadata("bucket", "key").await.unwrap().unwrap();
+    assert_eq!(stored.generation, first.generation);
+    assert_eq!(stored.md5, first.md5);
+    // 拒否したアップロードのシャードは残らない
+    assert_eq!(memory.len(), first.profile().total_shards());
+}
+
+#[tokio::test]
+async fn only_one_conditional_writer_creates_the_key() {
+    let (store, memory, shards) = temp_stores().await;
+    // 両方のリクエストが存在の確認を通ってからボディを送り、一意インデックスで競合させる
+    let barrier = Arc::new(Barrier::new(2));
+    let body = |data: &'static str| {
+        let barrier = barrier.clone();
+        Body::from_stream(stream::once(async move {
+            barrier.wait().await;
+            Ok::<_, std::io::Error>(Bytes::from_static(data.as_bytes()))
+        }))
+    };
+    let (a, b) = tokio::join!(
+        put(&store, &shards, "key", body("from a"), true),
+        put(&store, &shards, "key", body("from b"), true),
+    );
+    let mut statuses = [a, b];
+    statuses.sort();
+    assert_eq!(statuses, [StatusCode::OK, StatusCode::PRECONDITION_FAILED]);
+
+    // 勝った方の世代だけが残り、負けた方のシャードは消える
+    let stored = store.get_metadata("bucket", "key").await.unwrap().unwrap();
+    assert_eq!(memory.len(), stored.profile().total_shards());
+    assert_eq!(
+        shards.list(&stored.shard_id()).await.unwrap().len(),
+        memory.len()
+    );
+}
+
+#[tokio::test]
+async fn overwriting_removes_the_previous_generation() {
+    let (store, memory, shards) = temp_stores().await;
+    assert_eq!(
+        put(&store, &shards, "key", Body::from("first"), false).await,
+        StatusCode::OK
+    );
+    let first = store.get_metadata("bucket", "key").await.unwrap().unwrap();
+    assert_eq!(
+        put(&store, &shards, "key", Body::from("second"), false).await,
+        StatusCode::OK
+    );
+    let second = store.get_metadata("bucket", "key").await.unwrap().unwrap();
+    assert_ne!(first.shard_id(), second.shard_id());
+
+    assert!(shards.list(&first.shard_id()).await.unwrap().is_empty());
+    assert_eq!(
+        shards.list(&second.shard_id()).await.unwrap().len(),
+        second.profile().total_shards()
+    );
+    assert_eq!(memory.len(), second.profile().total_shards());
+}
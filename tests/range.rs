@@ -0,0 +1,70 @@
+use t3::handler::range::{RangeRequest, parse_range};
+
+fn partial(ranges: &[(u64, u64)]) -> RangeRequest {
+    RangeRequest::Partial(ranges.iter().map(|&(start, end)| start..end).collect())
+}
+
+#[test]
+fn parses_single_ranges() {
+    let len = 1000;
+    assert_eq!(parse_range(Some("bytes=0-99"), len), partial(&[(0, 100)]));
+    assert_eq!(
+        parse_range(Some("bytes=900-"), len),
+        partial(&[(900, 1000)])
+    );
+    assert_eq!(
+        parse_range(Some("bytes=-100"), len),
+        partial(&[(900, 1000)])
+    );
+    // 末尾を超える指定はオブジェクトの終わりまでに切り詰める
+    assert_eq!(
+        parse_range(Some("bytes=990-2000"), len),
+        partial(&[(990, 1000)])
+    );
+    assert_eq!(parse_range(Some("bytes=-5000"), len), partial(&[(0, 1000)]));
+    // 単位名は大文字小文字を区別しない
+    assert_eq!(parse_range(Some("Bytes=0-99"), len), partial(&[(0, 100)]));
+    assert_eq!(
+        parse_range(Some("BYTES=-100"), len),
+        partial(&[(900, 1000)])
+    );
+}
+
+#[test]
+fn parses_multiple_ranges() {
+    assert_eq!(
+        parse_range(Some("bytes=0-0, 10-19,-1"), 100),
+        partial(&[(0, 1), (10, 20), (99, 100)])
+    );
+    // 満たせない範囲だけを捨てる
+    assert_eq!(
+        parse_range(Some("bytes=0-9,500-"), 100),
+        partial(&[(0, 10)])
+    );
+}
+
+#[test]
+fn unsatisfiable_ranges() {
+    assert_eq!(
+        parse_range(Some("bytes=100-"), 100),
+        RangeRequest::Unsatisfiable
+    );
+    assert_eq!(
+        parse_range(Some("bytes=-0"), 100),
+        RangeRequest::Unsatisfiable
+    );
+    assert_eq!(
+        parse_range(Some("bytes=0-"), 0),
+        RangeRequest::Unsatisfiable
+    );
+}
+
+#[test]
+fn invalid_ranges_are_ignored() {
+    assert_eq!(parse_range(None, 100), RangeRequest::Full);
+    assert_eq!(parse_range(Some("items=0-1"), 100), RangeRequest::Full);
+    assert_eq!(parse_range(Some("bytes=5-1"), 100), RangeRequest::Full);
+    assert_eq!(parse_range(Some("bytes=a-b"), 100), RangeRequest::Full);
+    assert_eq!(parse_range(Some("bytes=1"), 100), RangeRequest::Full);
+    assert_eq!(parse_range(Some("bytes="), 100), RangeRequest::Full);
+}
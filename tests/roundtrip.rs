@@ -0,0 +1,281 @@
+use futures::TryStreamExt;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use t3::decode::ShardReader;
+use t3::encode::{EncodedObject, ShardWriter};
+use t3::env::{DATA_SHARDS, PARITY_SHARDS, STRIPE_UNIT};
+use t3::profile::ErasureProfile;
+use t3::store::{LocalShardStore, MemoryShardStore, SharedShardStore};
+use uuid::Uuid;
+
+fn local(root: &Path) -> SharedShardStore {
+    Arc::new(LocalShardStore::with_root(root))
+}
+
+/// テストごとのデータディレクトリ。作業ディレクトリの `outputs` は使わない
+fn temp_root() -> PathBuf {
+    std::env::temp_dir().join(format!("t3-roundtrip-{}", Uuid::new_v4()))
+}
+
+fn shard_path(root: &Path, object_id: &str, i: usize) -> PathBuf {
+    root.join(t3::output_dir_name(object_id, i).unwrap())
+        .join(t3::shard_filename(object_id, i))
+}
+
+fn sample(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 + 1).collect()
+}
+
+/// メモリ上のストアに書き込んだオブジェクト
+async fn memory_object(data: &[u8]) -> (MemoryShardStore, SharedShardStore) {
+    let memory = MemoryShardStore::new();
+    let store: SharedShardStore = Arc::new(memory.clone());
+    let mut writer = ShardWriter::create(&store, "obj", ErasureProfile::default())
+        .await
+        .unwrap();
+    writer.write(data).await.unwrap();
+    writer.finish().await.unwrap();
+    (memory, store)
+}
+
+async fn roundtrip(len: usize, missing: &[usize]) -> Vec<u8> {
+    let (_, store) = memory_object(&sample(len)).await;
+    for &i in missing {
+        assert!(store.delete("obj", i).await.unwrap());
+    }
+    let reader = ShardReader::open(&store, "obj", len as u64, ErasureProfile::default())
+        .await
+        .unwrap();
+    let parts: Vec<_> = reader
+        .into_stream(0..len as u64)
+        .try_collect()
+        .await
+        .unwrap();
+    parts.concat()
+}
+
+#[tokio::test]
+async fn roundtrip_strips_padding() {
+    let sizes = [
+        0,
+        1,
+        2,
+        DATA_SHARDS - 1,
+        DATA_SHARDS,
+        DATA_SHARDS + 1,
+        2 * DATA_SHARDS - 1,
+        1000,
+        STRIPE_UNIT * DATA_SHARDS,
+        2 * STRIPE_UNIT * DATA_SHARDS + 3,
+    ];
+    for len in sizes {
+        let decoded = roundtrip(len, &[]).await;
+        assert_eq!(&decoded[..], &sample(len)[..], "size {}", len);
+    }
+}
+
+#[tokio::test]
+async fn roundtrip_with_missing_shards() {
+    let missing_sets: [&[usize]; 3] = [&[0], &[DATA_SHARDS - 1, DATA_SHARDS], &[0, 2, 4]];
+    for len in [0, 1, 7, 4097, STRIPE_UNIT * DATA_SHARDS + 1] {
+        for missing in missing_sets {
+            assert!(missing.len() <= PARITY_SHARDS);
+            let decoded = roundtrip(len, missing).await;
+            assert_eq!(
+                &decoded[..],
+                &sample(len)[..],
+                "size {} missing {:?}",
+                len,
+                missing
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn decode_fails_with_too_many_missing_shards() {
+    let data = sample(100);
+    let (_, store) = memory_object(&data).await;
+    for i in 0..=PARITY_SHARDS {
+        store.delete("obj", i).await.unwrap();
+    }
+    assert!(
+        ShardReader::open(&store, "obj", data.len() as u64, ErasureProfile::default())
+            .await
+            .is_err()
+    );
+}
+
+async fn write_object(root: &Path, object_id: &str, data: &[u8]) -> EncodedObject {
+    let mut writer = ShardWriter::create(&local(root), object_id, ErasureProfile::default())
+        .await
+        .unwrap();
+    // multipartのチャンクのように細切れで渡す
+    for chunk in data.chunks(100_003) {
+        writer.write(chunk).await.unwrap();
+    }
+    let encoded = writer.finish().await.unwrap();
+    assert_eq!(encoded.content_length, data.len() as u64);
+    encoded
+}
+
+async fn read_object(root: &Path, object_id: &str, len: usize) -> Vec<u8> {
+    let reader = ShardReader::open(
+        &local(root),
+        object_id,
+        len as u64,
+        ErasureProfile::default(),
+    )
+    .await
+    .unwrap();
+    let stripes: Vec<_> = reader
+        .into_stream(0..len as u64)
+        .try_collect()
+        .await
+        .unwrap();
+    stripes.concat()
+}
+
+#[tokio::test]
+async fn streaming_roundtrip_through_shard_files() {
+    let root = temp_root();
+    for len in [0, 1, 7, STRIPE_UNIT * DATA_SHARDS + 1] {
+        let object_id = format!("roundtrip-{}", Uuid::new_v4());
+        let data = sample(len);
+        write_object(&root, &object_id, &data).await;
+        assert_eq!(
+            read_object(&root, &object_id, len).await,
+            data,
+            "size {}",
+            len
+        );
+    }
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn streaming_read_recovers_missing_and_corrupted_shards() {
+    let root = temp_root();
+    let object_id = format!("roundtrip-{}", Uuid::new_v4());
+    let len = 2 * STRIPE_UNIT * DATA_SHARDS + 5;
+    let data = sample(len);
+    write_object(&root, &object_id, &data).await;
+
+    // シャード0を消し、シャード3の2番目のストライプだけを壊す
+    tokio::fs::remove_file(shard_path(&root, &object_id, 0))
+        .await
+        .unwrap();
+    let path = shard_path(&root, &object_id, 3);
+    let mut content = tokio::fs::read(&path).await.unwrap();
+    let offset = content.len() - 10;
+    content[offset] ^= 0xff;
+    tokio::fs::write(&path, content).await.unwrap();
+
+    assert_eq!(read_object(&root, &object_id, len).await, data);
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn streaming_range_reads_across_stripes() {
+    let root = temp_root();
+    let object_id = format!("roundtrip-{}", Uuid::new_v4());
+    let stripe = STRIPE_UNIT * DATA_SHARDS;
+    let len = 2 * stripe + 5;
+    let data = sample(len);
+    write_object(&root, &object_id, &data).await;
+
+    let ranges = [
+        0..1,
+        stripe - 3..stripe + 3,
+        10..2 * stripe + 5,
+        len - 1..len,
+    ];
+    for range in ranges {
+        let reader = ShardReader::open(
+            &local(&root),
+            &object_id,
+            len as u64,
+            ErasureProfile::default(),
+        )
+        .await
+        .unwrap();
+        let stream = reader.into_stream(range.start as u64..range.end as u64);
+        let parts: Vec<_> = stream.try_collect().await.unwrap();
+        assert_eq!(parts.concat(), &data[range.clone()], "range {:?}", range);
+    }
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn decode_verifies_sha256() {
+    let data = sample(1000);
+    let digest = hex::encode(Sha256::digest(&data));
+    let (_, store) = memory_object(&data).await;
+    let len = data.len() as u64;
+    let read = |expected: String| {
+        let store = store.clone();
+        async move {
+            let reader = ShardReader::open(&store, "obj", len, ErasureProfile::default())
+                .await
+                .unwrap();
+            reader
+                .into_verified_stream(expected)
+                .try_collect::<Vec<_>>()
+                .await
+                .map(|parts| parts.concat())
+        }
+    };
+    assert_eq!(read(digest).await.unwrap(), data);
+
+    let wrong = hex::encode(Sha256::digest(b"other"));
+    assert!(read(wrong).await.is_err());
+}
+
+#[tokio::test]
+async fn streaming_write_computes_digests_and_read_verifies_them() {
+    let root = temp_root();
+    let object_id = format!("roundtrip-{}", Uuid::new_v4());
+    let len = STRIPE_UNIT * DATA_SHARDS + 7;
+    let data = sample(len);
+    let encoded = write_object(&root, &object_id, &data).await;
+    assert_eq!(encoded.md5, hex::encode(md5::Md5::digest(&data)));
+    assert_eq!(encoded.sha256, hex::encode(Sha256::digest(&data)));
+
+    let reader = ShardReader::open(
+        &local(&root),
+        &object_id,
+        len as u64,
+        ErasureProfile::default(),
+    )
+    .await
+    .unwrap();
+    let parts: Vec<_> = reader
+        .into_verified_stream(encoded.sha256)
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(parts.concat(), data);
+
+    // ダイジェストが一致しない場合は、最後のチャンクを送る前にエラーになる
+    let reader = ShardReader::open(
+        &local(&root),
+        &object_id,
+        len as u64,
+        ErasureProfile::default(),
+    )
+    .await
+    .unwrap();
+    let mut stream = Box::pin(reader.into_verified_stream(hex::encode(Sha256::digest(b"other"))));
+    let mut received = 0;
+    let result = loop {
+        match stream.try_next().await {
+            Ok(Some(part)) => received += part.len(),
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    assert!(result.is_err());
+    assert!(received < len);
+    std::fs::remove_dir_all(&root).unwrap();
+}
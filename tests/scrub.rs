@@ -0,0 +1,289 @@
+use futures::TryStreamExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use t3::db::{MetadataStore, NewObjectMetadata, ObjectMetadata};
+use t3::decode::ShardReader;
+use t3::encode::{EncodedObject, ShardWriter, remove_shards};
+use t3::env::{DATA_SHARDS, PARITY_SHARDS, STRIPE_UNIT};
+use t3::profile::ErasureProfile;
+use t3::scrub::{Health, RepairOwner, read_repair, scrub_object};
+use t3::store::{LocalShardStore, SharedShardStore};
+use uuid::Uuid;
+
+fn local(root: &Path) -> SharedShardStore {
+    Arc::new(LocalShardStore::with_root(root))
+}
+
+/// テストごとのデータディレクトリ。作業ディレクトリの `outputs` は使わない
+fn temp_root() -> PathBuf {
+    std::env::temp_dir().join(format!("t3-scrub-{}", Uuid::new_v4()))
+}
+
+fn shard_path(root: &Path, object_id: &str, i: usize) -> PathBuf {
+    root.join(t3::output_dir_name(object_id, i).unwrap())
+        .join(t3::shard_filename(object_id, i))
+}
+
+/// `root` の下のデータベースを開き、シャードの内部IDが `blob_id` のオブジェクトを登録する。
+async fn register(
+    root: &Path,
+    blob_id: &str,
+    encoded: &EncodedObject,
+) -> (MetadataStore, ObjectMetadata) {
+    let url = format!("sqlite://{}?mode=rwc", root.join("t3.db").display());
+    let store = MetadataStore::new(&url).await.unwrap();
+    let profile = ErasureProfile::default();
+    store
+        .create_bucket(
+            &Uuid::new_v4().to_string(),
+            "bucket",
+            "2026-01-01T00:00:00+00:00",
+            &profile,
+        )
+        .await
+        .unwrap();
+    let metadata = NewObjectMetadata {
+        content_length: encoded.content_length as i64,
+        sha256: Some(&encoded.sha256),
+        generation: Some("g1"),
+        blob_id: Some(blob_id),
+        profile,
+        ..Default::default()
+    };
+    store
+        .upsert_metadata("bucket", "key", &metadata)
+        .await
+        .unwrap();
+    let object = store.get_metadata("bucket", "key").await.unwrap().unwrap();
+    (store, object)
+}
+
+fn sample(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 + 1).collect()
+}
+
+async fn write_object(root: &Path, object_id: &str, data: &[u8]) -> EncodedObject {
+    let mut writer = ShardWriter::create(&local(root), object_id, ErasureProfile::default())
+        .await
+        .unwrap();
+    writer.write(data).await.unwrap();
+    writer.finish().await.unwrap()
+}
+
+async fn corrupt_last_stripe(root: &Path, object_id: &str, shard_index: usize) {
+    let path = shard_path(root, object_id, shard_index);
+    let mut content = tokio::fs::read(&path).await.unwrap();
+    let offset = content.len() - 10;
+    content[offset] ^= 0xff;
+    tokio::fs::write(&path, content).await.unwrap();
+}
+
+#[tokio::test]
+async fn scrub_repairs_missing_and_corrupted_shards() {
+    let root = temp_root();
+    let object_id = format!("scrub-{}", Uuid::new_v4());
+    let len = STRIPE_UNIT * DATA_SHARDS + 9;
+    let data = sample(len);
+    let encoded = write_object(&root, &object_id, &data).await;
+    let sha256 = Some(encoded.sha256.as_str());
+
+    let report = scrub_object(
+        &local(&root),
+        &object_id,
+        len as u64,
+        ErasureProfile::default(),
+        sha256,
+    )
+    .await;
+    assert_eq!(report.health, Health::Healthy);
+
+    tokio::fs::remove_file(shard_path(&root, &object_id, 1))
+        .await
+        .unwrap();
+    corrupt_last_stripe(&root, &object_id, DATA_SHARDS + 1).await;
+    let report = scrub_object(
+        &local(&root),
+        &object_id,
+        len as u64,
+        ErasureProfile::default(),
+        sha256,
+    )
+    .await;
+    assert_eq!(report.health, Health::Repaired);
+    assert_eq!(report.damaged_shards, vec![1, DATA_SHARDS + 1]);
+
+    // 作り直したシャードは元のシャードと同じ内容になる
+    let report = scrub_object(
+        &local(&root),
+        &object_id,
+        len as u64,
+        ErasureProfile::default(),
+        sha256,
+    )
+    .await;
+    assert_eq!(report.health, Health::Healthy);
+    let reader = ShardReader::open(
+        &local(&root),
+        &object_id,
+        len as u64,
+        ErasureProfile::default(),
+    )
+    .await
+    .unwrap();
+    let parts: Vec<_> = reader
+        .into_stream(0..len as u64)
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(parts.concat(), data);
+    remove_shards(&local(&root), &object_id, ErasureProfile::default()).await;
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn scrub_does_not_repair_with_wrong_digest() {
+    let root = temp_root();
+    let object_id = format!("scrub-{}", Uuid::new_v4());
+    let data = sample(1000);
+    write_object(&root, &object_id, &data).await;
+    tokio::fs::remove_file(shard_path(&root, &object_id, 0))
+        .await
+        .unwrap();
+
+    let report = scrub_object(
+        &local(&root),
+        &object_id,
+        data.len() as u64,
+        ErasureProfile::default(),
+        Some("00"),
+    )
+    .await;
+    assert_eq!(report.health, Health::Lost);
+    assert!(!shard_path(&root, &object_id, 0).exists());
+    remove_shards(&local(&root), &object_id, ErasureProfile::default()).await;
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn scrub_reports_lost_objects() {
+    let root = temp_root();
+    let object_id = format!("scrub-{}", Uuid::new_v4());
+    let data = sample(1000);
+    write_object(&root, &object_id, &data).await;
+    for i in 0..=PARITY_SHARDS {
+        tokio::fs::remove_file(shard_path(&root, &object_id, i))
+            .await
+            .unwrap();
+    }
+    let report = scrub_object(
+        &local(&root),
+        &object_id,
+        data.len() as u64,
+        ErasureProfile::default(),
+        None,
+    )
+    .await;
+    assert_eq!(report.health, Health::Lost);
+    remove_shards(&local(&root), &object_id, ErasureProfile::default()).await;
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn read_repair_rewrites_missing_shards_after_read() {
+    let root = temp_root();
+    let object_id = format!("scrub-{}", Uuid::new_v4());
+    let data = sample(1000);
+    let encoded = write_object(&root, &object_id, &data).await;
+    let (store, object) = register(&root, &object_id, &encoded).await;
+    let missing = shard_path(&root, &object_id, 2);
+    tokio::fs::remove_file(&missing).await.unwrap();
+
+    let reader = ShardReader::open(
+        &local(&root),
+        &object_id,
+        data.len() as u64,
+        ErasureProfile::default(),
+    )
+    .await
+    .unwrap()
+    .with_read_repair(Some(encoded.sha256), RepairOwner::new(&store, &object));
+    let parts: Vec<_> = reader
+        .into_stream(0..data.len() as u64)
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(parts.concat(), data);
+
+    // 修復はバックグラウンドで行われる
+    for _ in 0..50 {
+        if missing.exists() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    let report = scrub_object(
+        &local(&root),
+        &object_id,
+        data.len() as u64,
+        ErasureProfile::default(),
+        None,
+    )
+    .await;
+    assert_eq!(report.health, Health::Healthy);
+    remove_shards(&local(&root), &object_id, ErasureProfile::default()).await;
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn read_repair_skips_deleted_objects() {
+    let root = temp_root();
+    let object_id = format!("scrub-{}", Uuid::new_v4());
+    let data = sample(1000);
+    let encoded = write_object(&root, &object_id, &data).await;
+    let (store, object) = register(&root, &object_id, &encoded).await;
+    let missing = shard_path(&root, &object_id, 2);
+    tokio::fs::remove_file(&missing).await.unwrap();
+
+    // GETの後にオブジェクトが削除された
+    assert!(
+        store
+            .delete_metadata_generation("bucket", "key", Some("g1"))
+            .await
+            .unwrap()
+    );
+    read_repair(
+        local(&root),
+        object_id.clone(),
+        data.len() as u64,
+        ErasureProfile::default(),
+        Some(encoded.sha256),
+        vec![2],
+        RepairOwner::new(&store, &object),
+    )
+    .await;
+    assert!(!missing.exists());
+
+    // 同じ内部IDを参照するオブジェクトが残っていれば作り直す
+    let relinked = NewObjectMetadata {
+        content_length: data.len() as i64,
+        generation: Some("g2"),
+        blob_id: Some(&object_id),
+        ..Default::default()
+    };
+    store
+        .upsert_metadata("bucket", "copy", &relinked)
+        .await
+        .unwrap();
+    read_repair(
+        local(&root),
+        object_id.clone(),
+        data.len() as u64,
+        ErasureProfile::default(),
+        None,
+        vec![2],
+        RepairOwner::new(&store, &object),
+    )
+    .await;
+    assert!(missing.exists());
+    std::fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,217 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::TryStreamExt;
+use std::sync::Arc;
+use t3::decode::ShardReader;
+use t3::encode::encode_file;
+use t3::env::{DATA_SHARDS, PARITY_SHARDS, STRIPE_UNIT};
+use t3::profile::{ErasureProfile, MIN_STRIPE_UNIT};
+use t3::scrub::{Health, scrub_object};
+use t3::shard::{MAGIC, ShardHeader, decode_shard, encode_shard, write_chunk};
+use t3::store::{MemoryShardStore, SharedShardStore};
+
+fn header(object_id: &str, index: usize, original_len: u64) -> ShardHeader {
+    ShardHeader::new(
+        object_id,
+        index,
+        DATA_SHARDS,
+        PARITY_SHARDS,
+        STRIPE_UNIT,
+        original_len,
+    )
+}
+
+#[test]
+fn shard_header_roundtrip() {
+    let payload = b"seven!!";
+    let encoded = encode_shard(&header("obj", 4, 42), payload).unwrap();
+    let (decoded_header, decoded) =
+        decode_shard(encoded.freeze(), "obj", 4, DATA_SHARDS, PARITY_SHARDS).unwrap();
+    assert_eq!(decoded_header, header("obj", 4, 42));
+    assert_eq!(&decoded[..], payload);
+}
+
+#[test]
+fn shard_header_rejects_wrong_identity() {
+    let encoded = encode_shard(&header("obj", 1, 3), b"a").unwrap().freeze();
+    assert!(decode_shard(encoded.clone(), "other", 1, DATA_SHARDS, PARITY_SHARDS).is_err());
+    assert!(decode_shard(encoded.clone(), "obj", 2, DATA_SHARDS, PARITY_SHARDS).is_err());
+    assert!(decode_shard(encoded, "obj", 1, DATA_SHARDS + 1, PARITY_SHARDS).is_err());
+}
+
+#[test]
+fn corrupted_chunk_is_rejected() {
+    // 3ストライプにまたがるシャードの2番目のチャンクを壊す
+    let len = (2 * STRIPE_UNIT * DATA_SHARDS + 10) as u64;
+    let h = header("obj", 0, len);
+    let payload = vec![7u8; h.layout().shard_len() as usize];
+    let mut encoded = encode_shard(&h, &payload).unwrap();
+    let offset = h.encoded_len() + STRIPE_UNIT + 4 + 100;
+    encoded[offset] ^= 0x80;
+    assert!(decode_shard(encoded.freeze(), "obj", 0, DATA_SHARDS, PARITY_SHARDS).is_err());
+}
+
+#[tokio::test]
+async fn corrupted_shards_are_recovered_as_missing() {
+    let data: Vec<u8> = (0..10_000).map(|i| (i % 256) as u8).collect();
+    let shards = encode_file(BytesMut::from(&data[..]), ErasureProfile::default()).unwrap();
+    let files: Vec<Bytes> = shards
+        .iter()
+        .enumerate()
+        .map(|(i, s)| {
+            let mut f = encode_shard(&header("obj", i, data.len() as u64), s).unwrap();
+            if i < PARITY_SHARDS {
+                // ペイロードの1ビットを反転させる
+                let last = f.len() - 5;
+                f[last] ^= 0x01;
+            }
+            f.freeze()
+        })
+        .collect();
+
+    let corrupted = files
+        .iter()
+        .enumerate()
+        .filter(|(i, f)| decode_shard((*f).clone(), "obj", *i, DATA_SHARDS, PARITY_SHARDS).is_err())
+        .count();
+    assert_eq!(corrupted, PARITY_SHARDS);
+
+    // ストアから読むと、壊れたシャードを欠損として扱って復元する
+    let store: SharedShardStore = Arc::new(MemoryShardStore::new());
+    for (i, file) in files.into_iter().enumerate() {
+        store.put("obj", i, file).await.unwrap();
+    }
+    let len = data.len() as u64;
+    let reader = ShardReader::open(&store, "obj", len, ErasureProfile::default())
+        .await
+        .unwrap();
+    let parts: Vec<Bytes> = reader.into_stream(0..len).try_collect().await.unwrap();
+    assert_eq!(parts.concat(), data);
+}
+
+#[test]
+fn corrupted_header_is_rejected() {
+    let mut encoded = encode_shard(&header("obj", 1, 3), b"a").unwrap();
+    // original_len の最上位バイトを壊しても、ヘッダのチェックサムで検出できる
+    encoded[4 + 2 + 2 + 2 + 2 + 4 + 7] ^= 0x01;
+    let err = ShardHeader::read_from(&mut encoded.freeze()).unwrap_err();
+    assert!(err.to_string().contains("checksum"), "{}", err);
+}
+
+#[test]
+fn too_long_object_id_is_rejected() {
+    let object_id = "a".repeat(u16::MAX as usize + 1);
+    assert!(encode_shard(&header(&object_id, 0, 3), b"a").is_err());
+}
+
+#[test]
+fn version_2_shards_are_still_read() {
+    // ヘッダにチェックサムのないバージョン2の形式
+    let mut encoded = BytesMut::new();
+    encoded.put_slice(MAGIC);
+    encoded.put_u16_le(2);
+    encoded.put_u16_le(1);
+    encoded.put_u16_le(DATA_SHARDS as u16);
+    encoded.put_u16_le(PARITY_SHARDS as u16);
+    encoded.put_u32_le(STRIPE_UNIT as u32);
+    encoded.put_u64_le(3);
+    encoded.put_u16_le(3);
+    encoded.put_slice(b"obj");
+    write_chunk(b"a", &mut encoded);
+    let (decoded_header, payload) =
+        decode_shard(encoded.freeze(), "obj", 1, DATA_SHARDS, PARITY_SHARDS).unwrap();
+    assert_eq!(decoded_header.original_len, 3);
+    assert_eq!(&payload[..], b"a");
+}
+
+#[tokio::test]
+async fn legacy_shards_without_a_header_are_read_and_repaired() {
+    // ベースラインはヘッダを付けず、オブジェクト全体を1ストライプとしてエンコードしていた。
+    // ストライプ単位より大きいオブジェクトでも、ストライプに分けずに読めることを確かめる
+    let profile = ErasureProfile::new(DATA_SHARDS, PARITY_SHARDS, MIN_STRIPE_UNIT).unwrap();
+    let data: Vec<u8> = (0..3 * profile.stripe_len() + 10)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    let len = data.len() as u64;
+    let payloads = encode_file(BytesMut::from(&data[..]), profile.unstriped(len)).unwrap();
+    let memory = MemoryShardStore::new();
+    let store: SharedShardStore = Arc::new(memory.clone());
+    for (i, payload) in payloads.into_iter().enumerate() {
+        store.put("obj", i, payload.freeze()).await.unwrap();
+    }
+
+    let read = |store: SharedShardStore| {
+        let data = data.clone();
+        async move {
+            let reader = ShardReader::open(&store, "obj", len, profile)
+                .await
+                .unwrap();
+            let parts: Vec<Bytes> = reader.into_stream(0..len).try_collect().await.unwrap();
+            assert_eq!(parts.concat(), data);
+        }
+    };
+    read(store.clone()).await;
+
+    // 作り直したシャードは今の形式で書かれ、ヘッダのないシャードと一緒に読める
+    store.delete("obj", 0).await.unwrap();
+    let report = scrub_object(&store, "obj", len, profile, None).await;
+    assert_eq!(report.health, Health::Repaired);
+    assert_eq!(report.damaged_shards, vec![0]);
+    let repaired = store.get_all("obj", 0).await.unwrap().unwrap();
+    assert!(repaired.starts_with(MAGIC));
+    for i in 1..=PARITY_SHARDS {
+        store.delete("obj", i).await.unwrap();
+    }
+    read(store).await;
+}
+
+/// バージョン1の形式で、ストライプに分けずにシャードを書く。
+fn encode_v1_shard(object_id: &str, index: usize, original_len: u64, payload: &[u8]) -> Bytes {
+    let mut encoded = BytesMut::new();
+    encoded.put_slice(MAGIC);
+    encoded.put_u16_le(1);
+    encoded.put_u16_le(index as u16);
+    encoded.put_u16_le(DATA_SHARDS as u16);
+    encoded.put_u16_le(PARITY_SHARDS as u16);
+    encoded.put_u64_le(original_len);
+    encoded.put_u64_le(payload.len() as u64);
+    encoded.put_u32_le(crc32c::crc32c(payload));
+    encoded.put_u16_le(object_id.len() as u16);
+    encoded.put_slice(object_id.as_bytes());
+    encoded.put_slice(payload);
+    encoded.freeze()
+}
+
+#[tokio::test]
+async fn version_1_shards_are_still_read() {
+    let profile = ErasureProfile::new(DATA_SHARDS, PARITY_SHARDS, MIN_STRIPE_UNIT).unwrap();
+    let data: Vec<u8> = (0..2 * profile.stripe_len() + 10)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    let len = data.len() as u64;
+    let payloads = encode_file(BytesMut::from(&data[..]), profile.unstriped(len)).unwrap();
+    let files: Vec<Bytes> = payloads
+        .iter()
+        .enumerate()
+        .map(|(i, payload)| encode_v1_shard("obj", i, len, payload))
+        .collect();
+
+    let (decoded_header, payload) =
+        decode_shard(files[1].clone(), "obj", 1, DATA_SHARDS, PARITY_SHARDS).unwrap();
+    assert_eq!(decoded_header.original_len, len);
+    assert_eq!(payload, payloads[1]);
+    let mut corrupted = BytesMut::from(&files[1][..]);
+    let last = corrupted.len() - 1;
+    corrupted[last] ^= 0x01;
+    assert!(decode_shard(corrupted.freeze(), "obj", 1, DATA_SHARDS, PARITY_SHARDS).is_err());
+
+    let store: SharedShardStore = Arc::new(MemoryShardStore::new());
+    for (i, file) in files.into_iter().enumerate() {
+        store.put("obj", i, file).await.unwrap();
+    }
+    let reader = ShardReader::open(&store, "obj", len, profile)
+        .await
+        .unwrap();
+    assert!(reader.unavailable_shards().is_empty());
+    let parts: Vec<Bytes> = reader.into_stream(0..len).try_collect().await.unwrap();
+    assert_eq!(parts.concat(), data);
+}
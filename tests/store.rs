@@ -0,0 +1,144 @@
+use bytes::Bytes;
+use futures::TryStreamExt;
+use std::sync::Arc;
+use t3::decode::ShardReader;
+use t3::encode::{ShardWriter, remove_shards};
+use t3::profile::ErasureProfile;
+use t3::scrub::{Health, scrub_object};
+use t3::store::{LocalShardStore, MemoryShardStore, ShardStat, SharedShardStore};
+use uuid::Uuid;
+
+fn sample(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 + 1).collect()
+}
+
+async fn read_object(
+    store: &SharedShardStore,
+    object_id: &str,
+    len: usize,
+    profile: ErasureProfile,
+) -> Vec<u8> {
+    let reader = ShardReader::open(store, object_id, len as u64, profile)
+        .await
+        .unwrap();
+    let parts: Vec<_> = reader
+        .into_stream(0..len as u64)
+        .try_collect()
+        .await
+        .unwrap();
+    parts.concat()
+}
+
+async fn exercise(store: SharedShardStore) {
+    let object_id = format!("store-{}", Uuid::new_v4());
+    assert_eq!(store.stat(&object_id, 0).await.unwrap(), None);
+    assert_eq!(store.get_all(&object_id, 0).await.unwrap(), None);
+
+    store
+        .put(&object_id, 2, Bytes::from_static(b"hello shard"))
+        .await
+        .unwrap();
+    assert_eq!(
+        store.stat(&object_id, 2).await.unwrap(),
+        Some(ShardStat { len: 11 })
+    );
+    assert_eq!(
+        &store.get(&object_id, 2, 6..11).await.unwrap()[..],
+        b"shard"
+    );
+    assert!(store.get(&object_id, 2, 6..20).await.is_err());
+    assert_eq!(store.list(&object_id).await.unwrap(), vec![2]);
+
+    // commitするまでは見えない
+    let mut upload = store.create(&object_id, 0).await.unwrap();
+    upload.write_at(0, b"pending").await.unwrap();
+    assert_eq!(store.list(&object_id).await.unwrap(), vec![2]);
+    upload.abort().await;
+    assert_eq!(store.list(&object_id).await.unwrap(), vec![2]);
+
+    assert!(store.delete(&object_id, 2).await.unwrap());
+    assert!(!store.delete(&object_id, 2).await.unwrap());
+    assert!(store.list(&object_id).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn local_store_basic_operations() {
+    let root = std::env::temp_dir().join(format!("t3-store-{}", Uuid::new_v4()));
+    exercise(Arc::new(LocalShardStore::with_root(&root))).await;
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[tokio::test]
+async fn memory_store_basic_operations() {
+    exercise(Arc::new(MemoryShardStore::new())).await;
+}
+
+#[tokio::test]
+async fn objects_roundtrip_through_memory_store() {
+    let memory = MemoryShardStore::new();
+    let store: SharedShardStore = Arc::new(memory.clone());
+    let profile = ErasureProfile::new(4, 2, 16 * 1024).unwrap();
+    let object_id = format!("store-{}", Uuid::new_v4());
+    let len = profile.stripe_len() * 2 + 7;
+    let data = sample(len);
+
+    let mut writer = ShardWriter::create(&store, &object_id, profile)
+        .await
+        .unwrap();
+    writer.write(&data).await.unwrap();
+    let sha256 = writer.finish().await.unwrap().sha256;
+    assert_eq!(memory.len(), profile.total_shards());
+    assert_eq!(read_object(&store, &object_id, len, profile).await, data);
+
+    // 欠けたシャードはパリティから復元し、スクラブで同じストアに作り直す
+    store.delete(&object_id, 1).await.unwrap();
+    assert_eq!(read_object(&store, &object_id, len, profile).await, data);
+    let report = scrub_object(&store, &object_id, len as u64, profile, Some(&sha256)).await;
+    assert_eq!(report.health, Health::Repaired);
+    assert_eq!(report.damaged_shards, vec![1]);
+    assert_eq!(
+        store.list(&object_id).await.unwrap(),
+        (0..profile.total_shards()).collect::<Vec<_>>()
+    );
+
+    remove_shards(&store, &object_id, profile).await;
+    assert!(memory.is_empty());
+}
+
+#[tokio::test]
+async fn shards_are_named_by_blob_id() {
+    let root = std::env::temp_dir().join(format!("t3-blob-{}", Uuid::new_v4()));
+    let store: SharedShardStore = Arc::new(LocalShardStore::with_root(&root));
+    let profile = ErasureProfile::new(2, 1, 4096).unwrap();
+    // シャードはキーではなく内部IDで名前を付けるので、どんなキーのオブジェクトも root の下に置かれる
+    let mut blob_ids = Vec::new();
+    for _ in 0..3 {
+        let blob_id = t3::new_blob_id();
+        let mut writer = ShardWriter::create(&store, &blob_id, profile)
+            .await
+            .unwrap();
+        writer.write(&sample(100)).await.unwrap();
+        writer.finish().await.unwrap();
+        assert_eq!(
+            read_object(&store, &blob_id, 100, profile).await,
+            sample(100)
+        );
+        blob_ids.push(blob_id);
+    }
+    // 同じキーでも書き込むたびに別のIDになるので、バケットをまたいで重ならない
+    assert_ne!(t3::new_blob_id(), t3::new_blob_id());
+
+    let mut names = Vec::new();
+    for dir in std::fs::read_dir(root.join("outputs")).unwrap() {
+        for file in std::fs::read_dir(dir.unwrap().path()).unwrap() {
+            names.push(file.unwrap().file_name().into_string().unwrap());
+        }
+    }
+    assert_eq!(names.len(), blob_ids.len() * profile.total_shards());
+    assert!(names.iter().all(|name| {
+        blob_ids
+            .iter()
+            .any(|id| name.starts_with(id.as_str()) && name.is_ascii())
+    }));
+    std::fs::remove_dir_all(&root).unwrap();
+}